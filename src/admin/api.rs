@@ -0,0 +1,415 @@
+use crate::admin::overrides::HostOverrides;
+use crate::config::validate::is_dns_name;
+use crate::dns::record::DnsRecord;
+use crate::dns::server::cache::Cache;
+use crate::dns::server::policy::Policy;
+use crate::k8s::cluster::K8sClusters;
+use crate::listeners::Listeners;
+use crate::proxy::server::connections::Connections;
+use crate::proxy::server::proxy::Proxy;
+use crate::util::{log_error_result, read_http_request, write_http_response, HttpRequest};
+use anyhow::{anyhow, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::net::TcpStream;
+
+const OVERRIDES_PATH: &str = "/overrides";
+
+/// Json api on localhost to inspect dns cache, policy rules, proxy routes, clusters
+/// and connections, and to set temporary host overrides
+pub struct AdminApi {
+    cache: Option<Cache>,
+    policy: Option<Arc<Policy>>,
+    proxy_routes: Option<HashMap<String, SocketAddr>>,
+    proxy_aliases: HashMap<String, String>,
+    connections: Option<Connections>,
+    clusters: K8sClusters,
+    overrides: HostOverrides,
+}
+
+#[derive(Serialize)]
+struct CacheEntry {
+    host: String,
+    source: &'static str,
+    records: Vec<DnsRecord>,
+    expires_in_seconds: i64,
+}
+
+#[derive(Serialize)]
+struct Route {
+    host: String,
+    source: String,
+    /// Address of local service, 'k8s' for ingress or target host of alias
+    target: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Override {
+    #[serde(default)]
+    host: String,
+    /// Ip with or without port, same as in local cache file
+    addr: String,
+}
+
+impl AdminApi {
+    pub fn new(
+        cache: Option<Cache>,
+        policy: Option<Arc<Policy>>,
+        proxy: Option<&Proxy>,
+        clusters: &K8sClusters,
+        overrides: &HostOverrides,
+    ) -> AdminApi {
+        AdminApi {
+            cache,
+            policy,
+            proxy_routes: proxy.map(|proxy| proxy.local_routes()),
+            proxy_aliases: proxy.map(|proxy| proxy.aliases()).unwrap_or_default(),
+            connections: proxy.map(|proxy| proxy.connections()),
+            clusters: clusters.clone(),
+            overrides: overrides.clone(),
+        }
+    }
+
+    pub async fn serve(self, sockets: Arc<Listeners>, host: String, port: u16) -> Result<()> {
+        let listener = sockets.tcp(&host, port)?;
+        info!("Admin api listen on {}:{}", host, port);
+
+        let api = Arc::new(self);
+        let allowed_hosts = Arc::new(allowed_hosts(&host, port));
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let (api, allowed_hosts) = (api.clone(), allowed_hosts.clone());
+            tokio::spawn(async move {
+                log_error_result(api.respond(stream, &allowed_hosts).await);
+            });
+        }
+    }
+
+    async fn respond(&self, mut stream: TcpStream, allowed_hosts: &[String]) -> Result<()> {
+        let Some(request) = read_http_request(&mut stream).await? else {
+            return Ok(());
+        };
+
+        let (status, body) = match check_origin(&request, allowed_hosts) {
+            Err(e) => ("403 Forbidden", json!({ "error": e.to_string() })),
+            Ok(()) => match self.handle(&request).await {
+                Ok(response) => response,
+                Err(e) => ("400 Bad Request", json!({ "error": e.to_string() })),
+            },
+        };
+        let body = serde_json::to_vec_pretty(&body)?;
+        write_http_response(&mut stream, status, "application/json", &body).await
+    }
+
+    async fn handle(&self, request: &HttpRequest) -> Result<(&'static str, serde_json::Value)> {
+        let path = request.path.split('?').next().unwrap_or_default();
+        let override_host = path
+            .strip_prefix(OVERRIDES_PATH)
+            .and_then(|host| host.strip_prefix('/'))
+            .filter(|host| !host.is_empty());
+
+        Ok(match (request.method.as_str(), path, override_host) {
+            ("GET", "/cache", _) => ("200 OK", serde_json::to_value(self.cache().await)?),
+            ("GET", "/policy", _) => {
+                let hits = self
+                    .policy
+                    .as_ref()
+                    .map(|policy| policy.hits())
+                    .unwrap_or_default();
+                ("200 OK", serde_json::to_value(hits)?)
+            }
+            ("GET", "/routes", _) => ("200 OK", serde_json::to_value(self.routes())?),
+            ("GET", "/clusters", _) => ("200 OK", serde_json::to_value(self.clusters.health())?),
+            ("GET", "/connections", _) => {
+                let connections = self
+                    .connections
+                    .as_ref()
+                    .map(|connections| connections.list())
+                    .unwrap_or_default();
+                ("200 OK", serde_json::to_value(connections)?)
+            }
+            ("GET", OVERRIDES_PATH, _) => ("200 OK", serde_json::to_value(self.overrides())?),
+            ("PUT", _, Some(host)) => {
+                let host = override_host_name(host)?;
+                let body: Override = serde_json::from_slice(&request.body)
+                    .map_err(|e| anyhow!("Expected body {{\"addr\": \"<ip>[:port]\"}}, {}", e))?;
+                let addr = parse_addr(&body.addr)?;
+                self.overrides.set(&host, addr);
+                info!("Host {} is overridden to {}", host, addr);
+                ("200 OK", json!({ "host": host, "addr": addr.to_string() }))
+            }
+            ("DELETE", _, Some(host)) => {
+                let host = override_host_name(host)?;
+                if self.overrides.remove(&host) {
+                    info!("Host override {} is removed", host);
+                    ("200 OK", json!({ "host": host }))
+                } else {
+                    (
+                        "404 Not Found",
+                        json!({ "error": format!("{} is not overridden", host) }),
+                    )
+                }
+            }
+            _ => (
+                "404 Not Found",
+                json!({ "error": format!("{} {} is not found", request.method, path) }),
+            ),
+        })
+    }
+
+    async fn cache(&self) -> Vec<CacheEntry> {
+        let Some(cache) = &self.cache else {
+            return vec![];
+        };
+        let now = OffsetDateTime::now_utc();
+        cache
+            .entries()
+            .await
+            .into_iter()
+            .map(|(host, source, record)| CacheEntry {
+                host,
+                source,
+                records: record.records,
+                expires_in_seconds: (record.expires - now).whole_seconds(),
+            })
+            .collect()
+    }
+
+    /// Routes in precedence order: overrides, local caches, ingresses of ready clusters,
+    /// aliases are routed as their targets
+    fn routes(&self) -> Vec<Route> {
+        let Some(local_routes) = &self.proxy_routes else {
+            return vec![];
+        };
+
+        let mut routes: Vec<Route> = self
+            .overrides
+            .list()
+            .into_iter()
+            .map(|(host, addr)| Route {
+                host,
+                source: "override".to_string(),
+                target: addr.to_string(),
+            })
+            .collect();
+
+        let mut local: Vec<Route> = local_routes
+            .iter()
+            .map(|(host, addr)| Route {
+                host: host.to_string(),
+                source: "local".to_string(),
+                target: addr.to_string(),
+            })
+            .collect();
+        local.sort_by(|a, b| a.host.cmp(&b.host));
+        routes.extend(local);
+
+        // degraded cluster keeps serving its hosts until they are stale
+        for cluster in self.clusters.clusters() {
+            let mut hosts = cluster.hosts();
+            hosts.sort();
+            routes.extend(hosts.into_iter().map(|host| Route {
+                host,
+                source: cluster.source().to_string(),
+                target: "k8s".to_string(),
+            }));
+        }
+
+        let mut aliases: Vec<Route> = self
+            .proxy_aliases
+            .iter()
+            .map(|(host, target)| Route {
+                host: host.to_string(),
+                source: "alias".to_string(),
+                target: target.to_string(),
+            })
+            .collect();
+        aliases.sort_by(|a, b| a.host.cmp(&b.host));
+        routes.extend(aliases);
+        routes
+    }
+
+    fn overrides(&self) -> Vec<Override> {
+        self.overrides
+            .list()
+            .into_iter()
+            .map(|(host, addr)| Override {
+                host,
+                addr: addr.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Host header values of api address, page of other site could reach loopback api
+/// by rebinding its own name, so requests with other names are rejected
+fn allowed_hosts(host: &str, port: u16) -> Vec<String> {
+    let host = match IpAddr::from_str(host) {
+        Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+        _ => host.to_string(),
+    };
+    vec![format!("{}:{}", host, port), format!("localhost:{}", port)]
+}
+
+/// Reject requests to other host names and changes sent by browser pages
+fn check_origin(request: &HttpRequest, allowed_hosts: &[String]) -> Result<()> {
+    let host = request.host.as_deref().unwrap_or_default();
+    if !allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Err(anyhow!("Host '{}' is not allowed", host));
+    }
+    if request.method != "GET" && request.origin.is_some() {
+        return Err(anyhow!("Cross-origin {} is not allowed", request.method));
+    }
+    Ok(())
+}
+
+/// Host of override path in form of cache lookups, lowercase without trailing dot
+fn override_host_name(host: &str) -> Result<String> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if !is_dns_name(&host) {
+        return Err(anyhow!("'{}' is not dns name", host));
+    }
+    Ok(host)
+}
+
+/// Parse ip with or without port, port 0 means that only dns is overridden
+fn parse_addr(addr: &str) -> Result<SocketAddr> {
+    SocketAddr::from_str(addr)
+        .or_else(|_| IpAddr::from_str(addr).map(|ip| SocketAddr::new(ip, 0)))
+        .map_err(|_| anyhow!("'{}' is not ip address with optional port", addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::properties::parse_properties;
+    use std::time::Duration;
+
+    fn request(method: &str, path: &str, host: &str, origin: Option<&str>) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            host: Some(host.to_string()),
+            origin: origin.map(str::to_string),
+            body: vec![],
+        }
+    }
+
+    async fn api(overrides: &HostOverrides) -> AdminApi {
+        let props = parse_properties("", false).unwrap();
+        let clusters = K8sClusters::connect(&props, None, Duration::ZERO).await;
+        AdminApi::new(None, None, None, &clusters, overrides)
+    }
+
+    #[test]
+    fn allowed_host_is_accepted() {
+        let allowed = allowed_hosts("127.0.0.1", 9180);
+        for host in ["127.0.0.1:9180", "localhost:9180", "LocalHost:9180"] {
+            assert!(check_origin(&request("GET", "/cache", host, None), &allowed).is_ok());
+        }
+    }
+
+    #[test]
+    fn rebinding_host_is_rejected() {
+        let allowed = allowed_hosts("127.0.0.1", 9180);
+        for host in ["evil.example.com:9180", "127.0.0.1", "localhost:80", ""] {
+            assert!(
+                check_origin(&request("GET", "/cache", host, None), &allowed).is_err(),
+                "{} is rejected",
+                host
+            );
+        }
+        let mut without_host = request("GET", "/cache", "", None);
+        without_host.host = None;
+        assert!(check_origin(&without_host, &allowed).is_err());
+    }
+
+    #[test]
+    fn ipv6_host_is_bracketed() {
+        let allowed = allowed_hosts("::1", 9180);
+        assert_eq!(allowed, vec!["[::1]:9180", "localhost:9180"]);
+        assert!(check_origin(&request("GET", "/cache", "[::1]:9180", None), &allowed).is_ok());
+        assert!(check_origin(&request("GET", "/cache", "::1:9180", None), &allowed).is_err());
+    }
+
+    #[test]
+    fn change_with_origin_is_rejected() {
+        let allowed = allowed_hosts("127.0.0.1", 9180);
+        let path = "/overrides/app.example.com";
+        let host = "127.0.0.1:9180";
+        assert!(check_origin(&request("PUT", path, host, None), &allowed).is_ok());
+        assert!(check_origin(
+            &request("PUT", path, host, Some("http://127.0.0.1:9180")),
+            &allowed
+        )
+        .is_err());
+        assert!(check_origin(
+            &request("DELETE", path, host, Some("https://evil.example.com")),
+            &allowed
+        )
+        .is_err());
+        // reading is allowed, browser doesn't expose response without cors headers
+        assert!(check_origin(
+            &request("GET", "/routes", host, Some("https://evil.example.com")),
+            &allowed
+        )
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn override_host_is_validated_and_lowercased() {
+        let overrides = HostOverrides::default();
+        let api = api(&overrides).await;
+        let put = |path: &str| {
+            let mut request = request("PUT", path, "127.0.0.1:9180", None);
+            request.body = br#"{"addr": "127.0.0.1:3000"}"#.to_vec();
+            request
+        };
+
+        let (status, body) = api
+            .handle(&put("/overrides/App.Example.com."))
+            .await
+            .unwrap();
+        assert_eq!(status, "200 OK");
+        assert_eq!(body["host"], "app.example.com");
+        assert_eq!(
+            overrides.list(),
+            vec![(
+                "app.example.com".to_string(),
+                "127.0.0.1:3000".parse().unwrap()
+            )]
+        );
+
+        for path in [
+            "/overrides/bad_host",
+            "/overrides/-app.example.com",
+            "/overrides/a b",
+        ] {
+            assert!(
+                api.handle(&put(path)).await.is_err(),
+                "{} is rejected",
+                path
+            );
+        }
+        assert_eq!(overrides.list().len(), 1);
+
+        let delete = request(
+            "DELETE",
+            "/overrides/APP.example.com",
+            "127.0.0.1:9180",
+            None,
+        );
+        let (status, _) = api.handle(&delete).await.unwrap();
+        assert_eq!(status, "200 OK");
+        assert!(overrides.list().is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+/// Temporary hosts set through admin api, they take precedence over local caches and
+/// ingress hosts and are kept between config reloads
+#[derive(Clone, Default)]
+pub struct HostOverrides {
+    hosts: Arc<RwLock<HashMap<String, SocketAddr>>>,
+}
+
+impl HostOverrides {
+    pub fn get(&self, host: &str) -> Option<SocketAddr> {
+        self.hosts
+            .read()
+            .unwrap()
+            .get(&host.to_ascii_lowercase())
+            .copied()
+    }
+
+    pub fn set(&self, host: &str, addr: SocketAddr) {
+        self.hosts
+            .write()
+            .unwrap()
+            .insert(host.to_ascii_lowercase(), addr);
+    }
+
+    /// Return false if host is not overridden
+    pub fn remove(&self, host: &str) -> bool {
+        self.hosts
+            .write()
+            .unwrap()
+            .remove(&host.to_ascii_lowercase())
+            .is_some()
+    }
+
+    pub fn list(&self) -> Vec<(String, SocketAddr)> {
+        let mut hosts: Vec<(String, SocketAddr)> = self
+            .hosts
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(host, addr)| (host.to_string(), *addr))
+            .collect();
+        hosts.sort();
+        hosts
+    }
+}
@@ -0,0 +1,294 @@
+use crate::admin::api::AdminApi;
+use crate::admin::overrides::HostOverrides;
+use crate::config::properties::{is_url, split_cache_format, Properties};
+use crate::dns::resolved::Resolved;
+use crate::dns::server::dns::DnsServer;
+use crate::k8s::cluster::K8sClusters;
+use crate::listeners::Listeners;
+use crate::metrics;
+use crate::proxy::server::connections::Connections;
+use crate::proxy::server::proxy::Proxy;
+use crate::util::wait_until;
+use anyhow::Result;
+use log::{error, info, warn};
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::join;
+use tokio::task::JoinHandle;
+
+/// How long startup waits for clusters, before serving without them
+const CLUSTERS_STARTUP_WAIT: Duration = Duration::from_secs(5);
+
+/// Dns and proxy servers built from one properties snapshot
+pub struct App {
+    props: Properties,
+    dns_hosts: BTreeSet<String>,
+    proxy_hosts: BTreeSet<String>,
+    dns: Option<DnsServer>,
+    proxy: Option<Proxy>,
+    admin: Option<AdminApi>,
+    resolved: Option<Resolved>,
+    clusters: K8sClusters,
+    listeners: Arc<Listeners>,
+    connections: Connections,
+    dns_in_flight: Option<Arc<AtomicUsize>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl App {
+    /// Build servers and bind their listeners without starting them, fail if any of them
+    /// can't be built. Listeners and clusters with unchanged props of current app are reused
+    pub async fn new(
+        props: Properties,
+        overrides: &HostOverrides,
+        connections: &Connections,
+        current: Option<&App>,
+    ) -> Result<App> {
+        let listeners = Listeners::bind(&props, current.map(|app| app.listeners.as_ref()))?;
+
+        // unreachable clusters are retried in background and joined once they are ready
+        let clusters = K8sClusters::connect(
+            &props,
+            current.map(|app| &app.clusters),
+            CLUSTERS_STARTUP_WAIT,
+        )
+        .await;
+
+        let dns = if !props.dns.server.hosts().is_empty() {
+            Some(DnsServer::new(&props, &clusters, overrides).await?)
+        } else {
+            None
+        };
+
+        let proxy = if props.proxy.is_some() {
+            Some(Proxy::new(&props, &clusters, overrides, connections).await?)
+        } else {
+            None
+        };
+
+        let dns_hosts = match &dns {
+            None => BTreeSet::new(),
+            Some(dns) => dns.cache.hosts().await.into_iter().collect(),
+        };
+        let proxy_hosts = match &proxy {
+            None => BTreeSet::new(),
+            Some(proxy) => proxy.hosts().into_iter().collect(),
+        };
+
+        let resolved = match (&props.dns.resolved, &dns) {
+            (Some(resolved_props), Some(dns)) => Some(Resolved::new(
+                resolved_props,
+                &dns.hosts,
+                dns.port,
+                dns.zones
+                    .iter()
+                    .map(|zone| zone.name().to_string())
+                    .collect(),
+                dns.cache.clone(),
+            )?),
+            _ => None,
+        };
+
+        let admin = props.admin.as_ref().map(|_| {
+            AdminApi::new(
+                dns.as_ref().map(|dns| dns.cache.clone()),
+                dns.as_ref().and_then(|dns| dns.policy.clone()),
+                proxy.as_ref(),
+                &clusters,
+                overrides,
+            )
+        });
+
+        Ok(App {
+            props,
+            dns_hosts,
+            proxy_hosts,
+            dns_in_flight: dns.as_ref().map(|dns| dns.in_flight.clone()),
+            dns,
+            proxy,
+            admin,
+            resolved,
+            clusters,
+            listeners: Arc::new(listeners),
+            connections: connections.clone(),
+            tasks: vec![],
+        })
+    }
+
+    pub fn start(&mut self) {
+        if let Some(dns) = self.dns.take() {
+            let listeners = self.listeners.clone();
+            self.tasks.push(tokio::spawn(async {
+                if let Err(e) = dns.serve(listeners).await {
+                    error!("Unable to serve dns server, error: {:?}", e)
+                }
+            }));
+        }
+
+        if let Some(resolved) = self.resolved.clone() {
+            self.tasks.push(tokio::spawn(async {
+                if let Err(e) = resolved.serve().await {
+                    error!("Unable to register in systemd-resolved, error: {:?}", e)
+                }
+            }));
+        }
+
+        if let Some(metrics) = &self.props.metrics {
+            let (host, port) = (metrics.host.to_string(), metrics.port);
+            let listeners = self.listeners.clone();
+            self.tasks.push(tokio::spawn(async move {
+                if let Err(e) = metrics::serve(listeners, host, port).await {
+                    error!("Unable to serve metrics, error: {:?}", e)
+                }
+            }));
+        }
+
+        if let (Some(admin), Some(admin_props)) = (self.admin.take(), &self.props.admin) {
+            let (host, port) = (admin_props.host.to_string(), admin_props.port);
+            let listeners = self.listeners.clone();
+            self.tasks.push(tokio::spawn(async move {
+                if let Err(e) = admin.serve(listeners, host, port).await {
+                    error!("Unable to serve admin api, error: {:?}", e)
+                }
+            }));
+        }
+
+        if let Some(proxy) = self.proxy.take() {
+            let listeners = self.listeners.clone();
+            self.tasks.push(tokio::spawn(async {
+                if let Err(e) = proxy.serve(listeners).await {
+                    error!("Unable to serve proxy server, error: {:?}", e)
+                }
+            }));
+        }
+    }
+
+    /// Stop listeners and wait until they are closed, accepted connections are not interrupted
+    pub async fn stop(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+            let _ = task.await;
+        }
+        // sockets of next app are already cloned from them
+        self.listeners = Arc::default();
+    }
+
+    /// Stop listeners, wait for in-flight dns queries and proxy connections
+    /// up to shutdown timeout and close the rest
+    pub async fn shutdown(&mut self) {
+        self.stop().await;
+        // system resolver must not point to stopped dns server
+        self.revert_resolved(None).await;
+
+        let timeout = Duration::from_secs(self.props.shutdown_timeout_seconds);
+        let active = self.connections.len();
+        if active > 0 {
+            info!("Wait up to {:?} for {} proxy connections", timeout, active);
+        }
+
+        let dns_in_flight = self.dns_in_flight.clone();
+        let dns = async {
+            match dns_in_flight {
+                None => true,
+                Some(in_flight) => {
+                    wait_until(|| in_flight.load(Ordering::Relaxed) == 0, timeout).await
+                }
+            }
+        };
+        let (dns_answered, closed) = join!(dns, self.connections.drain(timeout));
+
+        if !dns_answered {
+            warn!("Dns queries are not answered in {:?}, drop them", timeout);
+        }
+        if closed > 0 {
+            warn!("Closed {} proxy connections after {:?}", closed, timeout);
+        }
+    }
+
+    /// Revert systemd-resolved link, unless next app registers on the same link
+    pub async fn revert_resolved(&self, next: Option<&App>) {
+        let Some(resolved) = &self.resolved else {
+            return;
+        };
+        let next_link = next
+            .and_then(|next| next.resolved.as_ref())
+            .map(|next| next.link());
+        if next_link == Some(resolved.link()) {
+            return;
+        }
+        if let Err(e) = resolved.revert().await {
+            error!("{:?}", e)
+        }
+    }
+
+    /// Config file, local cache and policy list files, which change require reload
+    pub fn watched_files(&self, config_path: &str) -> Vec<PathBuf> {
+        let mut files = vec![PathBuf::from(config_path)];
+        files.extend(
+            self.props
+                .dns
+                .cache
+                .iter()
+                .filter(|cache| !cache.eq_ignore_ascii_case("k8s"))
+                .map(|cache| PathBuf::from(split_cache_format(cache).1)),
+        );
+        // url lists are refreshed by dns server instead
+        files.extend(
+            self.props
+                .dns
+                .policy
+                .iter()
+                .flat_map(|policy| policy.blocklists.iter().chain(&policy.allowlists))
+                .filter(|list| !is_url(list))
+                .map(PathBuf::from),
+        );
+        files
+    }
+
+    /// Log what is changed in new app comparing to current one
+    pub fn log_diff(&self, new: &App) {
+        let (old_props, new_props) = (&self.props, &new.props);
+        log_changed("dns.server", &old_props.dns.server, &new_props.dns.server);
+        log_changed("dns.cache", &old_props.dns.cache, &new_props.dns.cache);
+        log_changed("dns.policy", &old_props.dns.policy, &new_props.dns.policy);
+        log_changed("k8s", &old_props.k8s, &new_props.k8s);
+        log_changed("proxy", &old_props.proxy, &new_props.proxy);
+        log_changed("metrics", &old_props.metrics, &new_props.metrics);
+        log_changed("admin", &old_props.admin, &new_props.admin);
+
+        if old_props.log_level != new_props.log_level {
+            warn!(
+                "Changed log-level from {} to {}, it requires restart",
+                old_props.log_level, new_props.log_level
+            );
+        }
+        if old_props.log_format != new_props.log_format {
+            warn!(
+                "Changed log-format from {:?} to {:?}, it requires restart",
+                old_props.log_format, new_props.log_format
+            );
+        }
+
+        log_hosts_diff("dns", &self.dns_hosts, &new.dns_hosts);
+        log_hosts_diff("proxy", &self.proxy_hosts, &new.proxy_hosts);
+    }
+}
+
+fn log_changed<T: Debug + PartialEq>(name: &str, old: &T, new: &T) {
+    if old != new {
+        info!("Changed {}: {:?} -> {:?}", name, old, new);
+    }
+}
+
+fn log_hosts_diff(name: &str, old: &BTreeSet<String>, new: &BTreeSet<String>) {
+    for host in new.difference(old) {
+        info!("Added {} host: {}", name, host);
+    }
+    for host in old.difference(new) {
+        info!("Removed {} host: {}", name, host);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::cli::{CaExportArgs, CaInitArgs, CertFormat};
+use crate::config::properties::{parse_properties, Properties};
+use crate::dns::local_cache::{load_local_aliases, load_local_cache};
+use crate::k8s::client::K8sClient;
+use crate::proxy::server::cert::name_within;
+use crate::util::write_private_file;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use rcgen::{
+    BasicConstraints, CertificateParams, CidrSubnet, DnType, GeneralSubtree, IsCa, KeyUsagePurpose,
+    NameConstraints,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Add;
+use std::path::Path;
+use std::str::FromStr;
+use time::{Duration, OffsetDateTime};
+use tokio::io::AsyncWriteExt;
+
+/// Generate self-signed root CA and write key(owner only readable) and cert in pem format
+pub async fn init(args: &CaInitArgs, config_path: &str, config_required: bool) -> Result<()> {
+    for path in [&args.key, &args.cert] {
+        if !args.force && Path::new(path).exists() {
+            return Err(anyhow!(
+                "File {} already exists, use --force to overwrite it",
+                path
+            ));
+        }
+    }
+
+    let mut params = CertificateParams::default();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, &args.name);
+    params
+        .distinguished_name
+        .push(DnType::OrganizationName, "kidns");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    params.not_before = OffsetDateTime::now_utc();
+    params.not_after = OffsetDateTime::now_utc().add(Duration::days(args.validity_days as i64));
+
+    if args.no_name_constraints {
+        warn!(
+            "Root CA is generated without name constraints, it can sign certificate for any domain"
+        );
+    } else {
+        let (permitted_domains, permitted_ips) =
+            if args.permitted_domains.is_empty() && args.permitted_ips.is_empty() {
+                let props = parse_properties(config_path, config_required).map_err(|e| {
+                    anyhow!(
+                        "Unable to read config to find domains served by kidns, \
+                        set them with --permitted-domain or use --no-name-constraints, err: {:?}",
+                        e
+                    )
+                })?;
+                (
+                    default_permitted_domains(&props).await?,
+                    default_permitted_ips(&props),
+                )
+            } else {
+                (args.permitted_domains.clone(), args.permitted_ips.clone())
+            };
+
+        // empty dns subtrees would not restrict dns names at all
+        if permitted_domains.is_empty() {
+            return Err(anyhow!(
+                "No domains served by kidns found to constrain root CA, \
+                set them with --permitted-domain or use --no-name-constraints"
+            ));
+        }
+        info!(
+            "Root CA is constrained to {:?} and ip addresses {:?}",
+            permitted_domains, permitted_ips
+        );
+        let permitted_ips = permitted_ips
+            .iter()
+            .map(|ip| parse_subnet(ip))
+            .collect::<Result<Vec<_>>>()?;
+        params.name_constraints = Some(name_constraints(&permitted_domains, permitted_ips));
+    }
+
+    let key_pair = args.key_algorithm.to_key_pair()?;
+    let cert = params.self_signed(&key_pair)?;
+
+    write_private_file(&args.key, key_pair.serialize_pem().as_bytes()).await?;
+    tokio::fs::write(&args.cert, cert.pem()).await?;
+
+    info!("Root CA key written to {}", args.key);
+    info!("Root CA certificate written to {}", args.cert);
+    Ok(())
+}
+
+/// Permitted domains and ip subnets, ip addresses out of permitted subnets are excluded,
+/// so certificate of any ip address can't be signed by CA when no subnet is permitted
+fn name_constraints(domains: &[String], ips: Vec<CidrSubnet>) -> NameConstraints {
+    let mut permitted_subtrees: Vec<GeneralSubtree> = domains
+        .iter()
+        .map(|domain| GeneralSubtree::DnsName(domain.to_string()))
+        .collect();
+    let excluded_subtrees = if ips.is_empty() {
+        vec![
+            GeneralSubtree::IpAddress(CidrSubnet::from_addr_prefix(
+                Ipv4Addr::UNSPECIFIED.into(),
+                0,
+            )),
+            GeneralSubtree::IpAddress(CidrSubnet::from_addr_prefix(
+                Ipv6Addr::UNSPECIFIED.into(),
+                0,
+            )),
+        ]
+    } else {
+        permitted_subtrees.extend(ips.into_iter().map(GeneralSubtree::IpAddress));
+        vec![]
+    };
+    NameConstraints {
+        permitted_subtrees,
+        excluded_subtrees,
+    }
+}
+
+/// Parse ip address or subnet in 'ip/prefix' form
+fn parse_subnet(value: &str) -> Result<CidrSubnet> {
+    let (ip, prefix) = match value.split_once('/') {
+        Some((ip, prefix)) => (ip, Some(prefix)),
+        None => (value, None),
+    };
+    let ip = IpAddr::from_str(ip).map_err(|_| anyhow!("'{}' is not ip address", value))?;
+    let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        None => max_prefix,
+        Some(prefix) => prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|prefix| *prefix <= max_prefix)
+            .ok_or_else(|| anyhow!("Invalid prefix of subnet '{}'", value))?,
+    };
+    Ok(CidrSubnet::from_addr_prefix(ip, prefix))
+}
+
+/// Ip addresses of 'proxy.root-ca.subject-alt-names', they are added to every leaf certificate
+fn default_permitted_ips(props: &Properties) -> Vec<String> {
+    props
+        .proxy
+        .iter()
+        .filter_map(|proxy| proxy.root_ca.as_ref())
+        .flat_map(|tls_props| &tls_props.subject_alt_names)
+        .filter(|name| IpAddr::from_str(name).is_ok())
+        .cloned()
+        .collect()
+}
+
+/// Return 'proxy.root-ca.permitted-domains' or hosts served by kidns
+async fn default_permitted_domains(props: &Properties) -> Result<Vec<String>> {
+    if let Some(tls_props) = props
+        .proxy
+        .as_ref()
+        .and_then(|proxy| proxy.root_ca.as_ref())
+    {
+        if !tls_props.permitted_domains.is_empty() {
+            return Ok(tls_props.permitted_domains.clone());
+        }
+    }
+
+    let mut hosts = Vec::new();
+    for cache in &props.dns.cache {
+        if cache.eq_ignore_ascii_case("k8s") {
+            continue;
+        }
+        match load_local_cache(cache).await {
+            Ok(local_cache) => hosts.extend(local_cache.into_keys()),
+            Err(e) => warn!("Unable to load local cache {}, err: {:?}", cache, e),
+        }
+        // aliases are served by proxy with certificates of their own names
+        if let Ok(aliases) = load_local_aliases(cache).await {
+            hosts.extend(aliases.into_keys());
+        }
+    }
+
+    for k8s_props in props.k8s.iter().flatten() {
+        let urls = match K8sClient::new(k8s_props).await {
+            Ok(client) => client.ingress_urls().await,
+            Err(e) => Err(e),
+        };
+        match urls {
+            Ok(urls) => hosts.extend(urls),
+            Err(e) => warn!(
+                "Unable to load ingress hosts from {}, err: {:?}",
+                k8s_props.source(),
+                e
+            ),
+        }
+    }
+
+    served_domains(hosts)
+}
+
+/// Reduce hosts to name constraint subtrees, exact hosts are kept and wildcard hosts are
+/// reduced to their parent, ex. '*.dev.example.com' -> 'dev.example.com', domains covered
+/// by other domain are skipped, wildcard of top level domain(ex. '*.com') is refused
+pub(crate) fn served_domains(hosts: Vec<String>) -> Result<Vec<String>> {
+    let mut domains = Vec::with_capacity(hosts.len());
+    for host in &hosts {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let domain = match host.strip_prefix("*.") {
+            Some(parent) if parent.contains('.') => parent.to_string(),
+            Some(_) => {
+                return Err(anyhow!(
+                    "Wildcard host {} would permit whole top level domain, \
+                    set permitted domains with --permitted-domain",
+                    host
+                ))
+            }
+            None => host,
+        };
+        domains.push(domain);
+    }
+    domains.sort();
+    domains.dedup();
+
+    Ok(domains
+        .iter()
+        .filter(|domain| {
+            !domains
+                .iter()
+                .any(|other| other != *domain && name_within(domain, other))
+        })
+        .cloned()
+        .collect())
+}
+
+/// Write root CA certificate in requested format to file or stdout
+pub async fn export(args: &CaExportArgs) -> Result<()> {
+    let cert_pem = tokio::fs::read_to_string(&args.cert)
+        .await
+        .map_err(|e| anyhow!("Unable to read certificate {}, err: {}", args.cert, e))?;
+
+    let content = match args.format {
+        CertFormat::Pem => cert_pem.into_bytes(),
+        CertFormat::Der => pem::parse(cert_pem)?.into_contents(),
+    };
+
+    if args.out == "-" {
+        let mut stdout = tokio::io::stdout();
+        stdout.write_all(&content).await?;
+        stdout.flush().await?;
+    } else {
+        tokio::fs::write(&args.out, content).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(hosts: &[&str]) -> Vec<String> {
+        hosts.iter().map(|host| host.to_string()).collect()
+    }
+
+    #[test]
+    fn served_domains_keep_exact_hosts() {
+        let domains = served_domains(hosts(&[
+            "shop.co.uk",
+            "app.github.io",
+            "API.corp.com.",
+            "localhost",
+        ]))
+        .unwrap();
+        assert_eq!(
+            domains,
+            hosts(&["api.corp.com", "app.github.io", "localhost", "shop.co.uk"])
+        );
+    }
+
+    #[test]
+    fn served_domains_reduce_wildcard_to_its_parent() {
+        let domains = served_domains(hosts(&[
+            "*.dev.example.com",
+            "app.dev.example.com",
+            "dev.example.com",
+            "example.com",
+            "other.com",
+        ]))
+        .unwrap();
+        assert_eq!(domains, hosts(&["example.com", "other.com"]));
+
+        let domains = served_domains(hosts(&[
+            "*.dev.example.com",
+            "app.dev.example.com",
+            "api.example.com",
+        ]))
+        .unwrap();
+        assert_eq!(domains, hosts(&["api.example.com", "dev.example.com"]));
+    }
+
+    #[test]
+    fn served_domains_refuse_wildcard_of_top_level_domain() {
+        assert!(served_domains(hosts(&["app.example.com", "*.com"])).is_err());
+        assert!(served_domains(hosts(&["*.local"])).is_err());
+    }
+
+    #[test]
+    fn served_domains_of_no_hosts_are_empty() {
+        assert!(served_domains(vec![]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,123 @@
+use crate::cli::CaInstallArgs;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const DEBIAN_ANCHORS: &str = "/usr/local/share/ca-certificates";
+const FEDORA_ANCHORS: &str = "/etc/pki/ca-trust/source/anchors";
+
+/// Add root CA certificate to system trust store and user NSS databases
+pub fn install(args: &CaInstallArgs) -> Result<()> {
+    let cert = Path::new(&args.cert)
+        .canonicalize()
+        .map_err(|e| anyhow!("Unable to find certificate {}, err: {}", args.cert, e))?;
+
+    if !args.no_system {
+        install_system(&cert, &args.nickname)?;
+    }
+
+    if !args.no_nss {
+        install_nss(&cert, &args.nickname)?;
+    }
+    Ok(())
+}
+
+fn install_system(cert: &Path, nickname: &str) -> Result<()> {
+    if Path::new(DEBIAN_ANCHORS).is_dir() && find_executable("update-ca-certificates").is_some() {
+        // update-ca-certificates picks only files with '.crt' extension
+        let target = Path::new(DEBIAN_ANCHORS).join(format!("{}.crt", nickname));
+        std::fs::copy(cert, &target)?;
+        run(&mut Command::new("update-ca-certificates"))?;
+        info!("Installed root CA to {:?}", target);
+    } else if Path::new(FEDORA_ANCHORS).is_dir() && find_executable("update-ca-trust").is_some() {
+        let target = Path::new(FEDORA_ANCHORS).join(format!("{}.pem", nickname));
+        std::fs::copy(cert, &target)?;
+        run(Command::new("update-ca-trust").arg("extract"))?;
+        info!("Installed root CA to {:?}", target);
+    } else if find_executable("trust").is_some() {
+        run(Command::new("trust").arg("anchor").arg("--store").arg(cert))?;
+        info!("Installed root CA to p11-kit trust store");
+    } else {
+        return Err(anyhow!(
+            "Unable to find system trust store tool, supported: update-ca-certificates, update-ca-trust, trust(p11-kit)"
+        ));
+    }
+    Ok(())
+}
+
+fn install_nss(cert: &Path, nickname: &str) -> Result<()> {
+    let databases = nss_databases();
+    if databases.is_empty() {
+        info!("No NSS databases found, skipped");
+        return Ok(());
+    }
+
+    if find_executable("certutil").is_none() {
+        warn!(
+            "Found {} NSS databases, but 'certutil' is missing, install nss tools(libnss3-tools or nss-tools)",
+            databases.len()
+        );
+        return Ok(());
+    }
+
+    for db in databases {
+        let mut command = Command::new("certutil");
+        command
+            .arg("-d")
+            .arg(format!("sql:{}", db.display()))
+            .args(["-A", "-t", "C,,", "-n", nickname, "-i"])
+            .arg(cert);
+
+        match run(&mut command) {
+            Ok(_) => info!("Installed root CA to NSS database {:?}", db),
+            Err(e) => warn!("Unable to install root CA to {:?}, err: {:?}", db, e),
+        }
+    }
+    Ok(())
+}
+
+/// Return NSS databases of current user, shared one(Chrome) and Firefox profiles
+fn nss_databases() -> Vec<PathBuf> {
+    let Some(home) = env::var_os("HOME").map(PathBuf::from) else {
+        return vec![];
+    };
+
+    let mut databases = vec![
+        home.join(".pki/nssdb"),
+        home.join("snap/chromium/current/.pki/nssdb"),
+    ];
+    for firefox in [
+        home.join(".mozilla/firefox"),
+        home.join("snap/firefox/common/.mozilla/firefox"),
+    ] {
+        if let Ok(profiles) = std::fs::read_dir(firefox) {
+            databases.extend(profiles.flatten().map(|profile| profile.path()));
+        }
+    }
+
+    databases
+        .into_iter()
+        .filter(|db| db.join("cert9.db").is_file())
+        .collect()
+}
+
+fn find_executable(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Command {:?} failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
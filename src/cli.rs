@@ -0,0 +1,234 @@
+use crate::config::logs::LogFormat;
+use crate::config::properties::{default_ports, KeyPairAlgorithm, Properties, ProxyProps};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+const DEFAULT_CONFIG: &str = "config.yaml";
+
+#[derive(Parser)]
+#[command(version, about = "Kubernetes ingress dns and proxy server")]
+pub struct Cli {
+    /// Path to config file, relative paths inside of it are resolved from config directory.
+    /// If it is not set, optional 'config.yaml' from working directory is used
+    #[arg(short, long, global = true, env = "KIDNS_CONFIG")]
+    pub config: Option<String>,
+
+    #[command(flatten)]
+    pub overrides: Overrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    pub fn config_path(&self) -> &str {
+        self.config.as_deref().unwrap_or(DEFAULT_CONFIG)
+    }
+
+    /// Explicitly set config must exist, default one is optional
+    pub fn config_required(&self) -> bool {
+        self.config.is_some()
+    }
+}
+
+/// Override values from config file and environment variables
+#[derive(Args, Default)]
+pub struct Overrides {
+    /// Dns server listen addresses separated by comma, empty disable dns server
+    #[arg(long, global = true)]
+    pub dns_host: Option<String>,
+
+    #[arg(long, global = true)]
+    pub dns_port: Option<u16>,
+
+    /// Upstream dns server: ip address, udp://, tls:// or https:// url
+    #[arg(long, global = true)]
+    pub dns_public: Option<String>,
+
+    /// Proxy listen addresses separated by comma, enable proxy if it is missing in config
+    #[arg(long, global = true)]
+    pub proxy_host: Option<String>,
+
+    #[arg(long, global = true)]
+    pub http_port: Option<u16>,
+
+    #[arg(long, global = true)]
+    pub https_port: Option<u16>,
+
+    /// Level or filter with per-module levels, ex. 'info,kube_client=warn'
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    #[arg(long, global = true, value_enum)]
+    pub log_format: Option<LogFormat>,
+}
+
+impl Overrides {
+    /// Keys of properties set by flags, ex. `dns.server.port`
+    pub fn keys(&self) -> Vec<String> {
+        [
+            ("dns.server.host", self.dns_host.is_some()),
+            ("dns.server.port", self.dns_port.is_some()),
+            ("dns.server.public", self.dns_public.is_some()),
+            ("proxy.host", self.proxy_host.is_some()),
+            ("proxy.port.http", self.http_port.is_some()),
+            ("proxy.port.https", self.https_port.is_some()),
+            ("log-level", self.log_level.is_some()),
+            ("log-format", self.log_format.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(key, _)| key.to_string())
+        .collect()
+    }
+
+    pub fn apply(&self, props: &mut Properties) {
+        if let Some(host) = &self.dns_host {
+            props.dns.server.host = host.to_string();
+        }
+        if let Some(port) = self.dns_port {
+            props.dns.server.port = port;
+        }
+        if let Some(public) = &self.dns_public {
+            props.dns.server.public = public.to_string();
+        }
+        if let Some(log_level) = &self.log_level {
+            props.log_level = log_level.to_string();
+        }
+        if let Some(log_format) = self.log_format {
+            props.log_format = log_format;
+        }
+
+        let proxy_override =
+            self.proxy_host.is_some() || self.http_port.is_some() || self.https_port.is_some();
+        if proxy_override && props.proxy.is_none() {
+            props.proxy = Some(ProxyProps {
+                host: "".to_string(),
+                port: default_ports(),
+                answer: None,
+                root_ca: None,
+            });
+        }
+        if let Some(proxy) = props.proxy.as_mut() {
+            if let Some(host) = &self.proxy_host {
+                proxy.host = host.to_string();
+            }
+            if let Some(port) = self.http_port {
+                proxy.port.http = port;
+            }
+            if let Some(port) = self.https_port {
+                proxy.port.https = port;
+            }
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run dns and proxy servers, default command
+    Run,
+    /// Parse config and check local caches and root CA, without starting servers
+    CheckConfig,
+    /// Print hosts loaded from kubernetes ingresses and local caches
+    ListHosts,
+    /// Print effective config merged from defaults, config file, environment variables and flags
+    PrintConfig,
+    /// Resolve name same as dns server would do
+    Resolve {
+        name: String,
+
+        /// Query type, ex. A, AAAA, CNAME, TXT, MX
+        #[arg(short = 't', long = "type", default_value = "A")]
+        qtype: String,
+    },
+    /// Manage local root certificate authority used to sign proxy certificates
+    Ca {
+        #[command(subcommand)]
+        command: CaCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CaCommand {
+    /// Generate root certificate authority key and certificate
+    Init(CaInitArgs),
+    /// Export root certificate in pem or der format
+    Export(CaExportArgs),
+    /// Add root certificate to system trust store and NSS databases
+    Install(CaInstallArgs),
+}
+
+#[derive(Args)]
+pub struct CaInitArgs {
+    /// Path of generated private key
+    #[arg(long, default_value = "ca-root.key")]
+    pub key: String,
+
+    /// Path of generated certificate
+    #[arg(long, default_value = "ca-root.crt")]
+    pub cert: String,
+
+    /// Common name of certificate authority
+    #[arg(long, default_value = "kidns local CA")]
+    pub name: String,
+
+    #[arg(long, value_enum, default_value = "ecdsa-p256")]
+    pub key_algorithm: KeyPairAlgorithm,
+
+    #[arg(long, default_value_t = 1826)]
+    pub validity_days: u32,
+
+    /// Domain which CA is allowed to sign, can be repeated.
+    /// By default 'proxy.root-ca.permitted-domains' or domains served by kidns
+    #[arg(long = "permitted-domain")]
+    pub permitted_domains: Vec<String>,
+
+    /// Ip address or subnet(ex. 192.168.0.0/16) which CA is allowed to sign, can be repeated.
+    /// By default ip addresses of 'proxy.root-ca.subject-alt-names', other addresses are excluded
+    #[arg(long = "permitted-ip")]
+    pub permitted_ips: Vec<String>,
+
+    /// Generate CA without name constraints, it can sign certificate for any domain
+    #[arg(long, conflicts_with_all = ["permitted_domains", "permitted_ips"])]
+    pub no_name_constraints: bool,
+
+    /// Overwrite existing key and certificate
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct CaExportArgs {
+    #[arg(long, default_value = "ca-root.crt")]
+    pub cert: String,
+
+    #[arg(long, value_enum, default_value = "pem")]
+    pub format: CertFormat,
+
+    /// Output file, '-' for stdout
+    #[arg(long, default_value = "-")]
+    pub out: String,
+}
+
+#[derive(Args)]
+pub struct CaInstallArgs {
+    #[arg(long, default_value = "ca-root.crt")]
+    pub cert: String,
+
+    /// Name of certificate in trust stores
+    #[arg(long, default_value = "kidns")]
+    pub nickname: String,
+
+    /// Skip system trust store(update-ca-certificates, update-ca-trust or p11-kit)
+    #[arg(long)]
+    pub no_system: bool,
+
+    /// Skip NSS databases(Chrome, Firefox)
+    #[arg(long)]
+    pub no_nss: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum CertFormat {
+    Pem,
+    Der,
+}
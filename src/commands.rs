@@ -0,0 +1,107 @@
+use crate::admin::overrides::HostOverrides;
+use crate::config::properties::Properties;
+use crate::dns::header::QueryType;
+use crate::dns::local_cache::{load_local_aliases, load_local_cache};
+use crate::dns::packet::DnsPacket;
+use crate::dns::question::DnsQuestion;
+use crate::dns::server::dns::DnsServer;
+use crate::k8s::cluster::{ClusterState, K8sClusters};
+use crate::proxy::server::cert::get_root_ca_params;
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long commands wait for clusters, unreachable ones are skipped
+const CLUSTERS_WAIT: Duration = Duration::from_secs(30);
+
+/// Check that local caches and root CA can be loaded
+pub async fn check_config(props: &Properties) -> Result<()> {
+    for cache in props
+        .dns
+        .cache
+        .iter()
+        .filter(|c| !c.eq_ignore_ascii_case("k8s"))
+    {
+        load_local_cache(cache)
+            .await
+            .map_err(|e| anyhow!("Unable to load local cache {}, err: {}", cache, e))?;
+    }
+
+    if let Some(tls) = props
+        .proxy
+        .as_ref()
+        .and_then(|proxy| proxy.root_ca.as_ref())
+    {
+        get_root_ca_params(&tls.key, &tls.cert).await?;
+    }
+
+    println!("Config is valid");
+    Ok(())
+}
+
+/// Print ingress hosts of every reachable cluster and local cache hosts
+pub async fn list_hosts(props: &Properties) -> Result<()> {
+    let clusters = K8sClusters::connect(props, None, CLUSTERS_WAIT).await;
+    for cluster in clusters.clusters() {
+        let health = cluster.health();
+        if health.state != ClusterState::Ready {
+            warn!(
+                "Cluster {} is {:?} after {} attempts, err: {}",
+                health.source,
+                health.state,
+                health.attempts,
+                health.error.unwrap_or_default()
+            );
+            continue;
+        }
+        for host in cluster.hosts() {
+            println!("{}\tk8s\t{}", host, cluster.source());
+        }
+    }
+
+    for cache in props
+        .dns
+        .cache
+        .iter()
+        .filter(|c| !c.eq_ignore_ascii_case("k8s"))
+    {
+        let mut hosts: Vec<(String, String)> = load_local_cache(cache)
+            .await?
+            .into_iter()
+            .map(|(host, addr)| (host, addr.to_string()))
+            .chain(load_local_aliases(cache).await?)
+            .collect();
+        hosts.sort();
+        for (host, addr) in hosts {
+            println!("{}\t{}\t{}", host, addr, cache);
+        }
+    }
+    Ok(())
+}
+
+/// Print properties in yaml format
+pub fn print_config(props: &Properties) -> Result<()> {
+    print!("{}", serde_yaml::to_string(props)?);
+    Ok(())
+}
+
+/// Resolve name through cache and upstream, same as dns server
+pub async fn resolve(props: &Properties, name: &str, qtype: &str) -> Result<()> {
+    let clusters = K8sClusters::connect(props, None, CLUSTERS_WAIT).await;
+    let server = DnsServer::new(props, &clusters, &HostOverrides::default()).await?;
+
+    let mut request = DnsPacket::new();
+    request.header.recursion_desired = true;
+    request.questions.push(DnsQuestion::new(
+        name.to_lowercase(),
+        QueryType::from_str(qtype)?,
+    ));
+
+    let response = server.answer(request).await;
+    println!("{:?}", response.header.rescode);
+    for record in response.answers.iter().chain(response.authorities.iter()) {
+        println!("{:?}", record);
+    }
+    Ok(())
+}
@@ -1,2 +1,4 @@
 pub mod logs;
-pub mod properties;
\ No newline at end of file
+pub mod properties;
+pub mod validate;
+pub mod watch;
\ No newline at end of file
@@ -1,18 +1,117 @@
+use clap::ValueEnum;
+use env_logger::fmt::Formatter;
 use env_logger::Builder;
-use log::Level;
+use log::kv::{Key, Value, VisitSource};
+use log::{LevelFilter, Record};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map};
+use std::io::Write;
 use std::str::FromStr;
 
-pub fn init_logs(log_level: &String) {
+/// Target of dns query access logs, ex. 'log-level: info,kidns::access::dns=off' disable them
+pub const DNS_ACCESS: &str = "kidns::access::dns";
+/// Target of proxy connection access logs
+pub const PROXY_ACCESS: &str = "kidns::access::proxy";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One json object per line, structured fields are added as object keys
+    Json,
+}
+
+/// Configure logger, level is env_logger filter, ex. 'info,kube_client=warn,kidns::proxy=debug'
+pub fn init_logs(log_level: &str, format: LogFormat) {
     let mut builder = Builder::new();
-    builder.filter_level(
-        Level::from_str(log_level.as_str())
-            .unwrap()
-            .to_level_filter(),
-    );
-
-    if cfg!(not(debug_assertions)) {
-        builder.format_target(false);
+    // D-Bus handshake is logged on info, it can be enabled by filter, ex. 'info,zbus=info'
+    builder.filter_module("zbus", LevelFilter::Warn);
+    builder.filter_module("tracing::span", LevelFilter::Warn);
+    builder.parse_filters(log_level);
+
+    match format {
+        LogFormat::Text => {
+            if cfg!(not(debug_assertions)) {
+                builder.format_target(false);
+            }
+        }
+        LogFormat::Json => {
+            builder.format(write_json);
+        }
     }
 
     builder.init();
 }
+
+fn write_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let mut object = Map::new();
+    object.insert("ts".to_string(), json!(buf.timestamp().to_string()));
+    object.insert("level".to_string(), json!(record.level().as_str()));
+    object.insert("target".to_string(), json!(record.target()));
+    object.insert("message".to_string(), json!(record.args().to_string()));
+
+    let mut fields = JsonFields(&mut object);
+    if let Err(e) = record.key_values().visit(&mut fields) {
+        object.insert("kv_error".to_string(), json!(e.to_string()));
+    }
+
+    writeln!(buf, "{}", serde_json::Value::Object(object))
+}
+
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        // keep numbers and booleans typed
+        let value = if let Some(num) = value.to_u64() {
+            json!(num)
+        } else if let Some(num) = value.to_i64() {
+            json!(num)
+        } else if let Some(num) = value.to_f64() {
+            json!(num)
+        } else if let Some(flag) = value.to_bool() {
+            json!(flag)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Check env_logger filter directives, return description of first invalid one
+pub fn check_log_filter(filter: &str) -> Result<(), String> {
+    // part after '/' is regex of message, it is not validated
+    let directives = filter.split('/').next().unwrap_or_default();
+    for directive in directives
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+    {
+        match directive.split_once('=') {
+            Some((module, level)) => {
+                if !is_module_path(module) {
+                    return Err(format!("'{}' is not module path", module));
+                }
+                if LevelFilter::from_str(level).is_err() {
+                    return Err(format!(
+                        "unknown level '{}' of '{}', expected one of off, error, warn, info, debug, trace",
+                        level, module
+                    ));
+                }
+            }
+            // bare directive is level or module with all levels enabled
+            None if LevelFilter::from_str(directive).is_ok() || is_module_path(directive) => {}
+            None => return Err(format!("'{}' is not level or module path", directive)),
+        }
+    }
+    Ok(())
+}
+
+fn is_module_path(module: &str) -> bool {
+    !module.is_empty()
+        && module
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':' || c == '-')
+}
@@ -1,6 +1,10 @@
-use anyhow::Result;
-use serde::Deserialize;
-use std::fs::File;
+use crate::config::logs::LogFormat;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
 
 fn empty() -> String {
@@ -12,6 +16,15 @@ fn google_dns() -> String {
 const fn port_53() -> u16 {
     53
 }
+const fn port_9153() -> u16 {
+    9153
+}
+const fn port_9180() -> u16 {
+    9180
+}
+fn localhost() -> String {
+    "127.0.0.1".to_string()
+}
 const fn port_80() -> u16 {
     80
 }
@@ -24,50 +37,257 @@ fn default() -> String {
 fn ingress_label() -> String {
     "app.kubernetes.io/name=ingress".to_string()
 }
-const fn default_ports() -> PortProps {
-    PortProps{ http: port_80(), https: port_443() }
+const fn cert_validity_days() -> u32 {
+    365
+}
+const fn ecdsa_p256() -> KeyPairAlgorithm {
+    KeyPairAlgorithm::EcdsaP256
+}
+fn localhost_ns() -> String {
+    "localhost".to_string()
+}
+const fn negative_ttl() -> u32 {
+    60
+}
+const fn ingress_ttl() -> u32 {
+    300
+}
+const fn policy_refresh() -> u64 {
+    86400
+}
+const fn shutdown_timeout() -> u64 {
+    5
+}
+fn info() -> String {
+    "info".to_string()
+}
+fn default_dns() -> DnsProps {
+    DnsProps {
+        server: default_dns_server(),
+        cache: vec![],
+        zones: vec![],
+        policy: None,
+        resolved: None,
+    }
+}
+fn default_dns_server() -> DnsServerProps {
+    DnsServerProps {
+        public: google_dns(),
+        port: port_53(),
+        host: empty(),
+        doh_method: DohMethod::default(),
+    }
+}
+pub const fn default_ports() -> PortProps {
+    PortProps {
+        http: port_80(),
+        https: port_443(),
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Properties {
+    #[serde(default = "default_dns")]
     pub dns: DnsProps,
     pub k8s: Option<Vec<K8sProps>>,
     pub proxy: Option<ProxyProps>,
+    pub metrics: Option<MetricsProps>,
+    pub admin: Option<AdminProps>,
+    pub privileges: Option<PrivilegesProps>,
 
-    #[serde(rename = "log-level")]
+    /// Level or env_logger filter, ex. 'info,kube_client=warn'
+    #[serde(rename = "log-level", default = "info")]
     pub log_level: String,
+
+    #[serde(rename = "log-format", default)]
+    pub log_format: LogFormat,
+
+    /// Seconds to wait for dns queries and proxy connections on shutdown, before they are aborted
+    #[serde(rename = "shutdown-timeout-seconds", default = "shutdown_timeout")]
+    pub shutdown_timeout_seconds: u64,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct DnsProps {
+    #[serde(default = "default_dns_server")]
     pub server: DnsServerProps,
+
+    #[serde(default)]
     pub cache: Vec<String>,
+
+    #[serde(default)]
+    pub zones: Vec<ZoneProps>,
+
+    pub policy: Option<PolicyProps>,
+
+    pub resolved: Option<ResolvedProps>,
+}
+
+/// Zone which dns server is authoritative for, names in it are never sent to public dns
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneProps {
+    /// Zone apex, ex. 'dev.example.com'
+    pub name: String,
+
+    /// Name server returned in NS and SOA records of zone
+    #[serde(default = "localhost_ns")]
+    pub ns: String,
+
+    /// Seconds which negative answers are cached by clients, SOA minimum
+    #[serde(default = "negative_ttl")]
+    pub ttl: u32,
+}
+
+/// Blocking and rewriting of dns queries, allowlists take precedence over blocklists and rewrites
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyProps {
+    /// Files or http(s) urls of hosts files or domain lists,
+    /// listed domains and their subdomains are blocked
+    #[serde(default)]
+    pub blocklists: Vec<String>,
+
+    /// Files or urls same as blocklists, listed domains are never blocked or rewritten
+    #[serde(default)]
+    pub allowlists: Vec<String>,
+
+    /// Domains blocked in addition to blocklists
+    #[serde(default)]
+    pub block: Vec<String>,
+
+    /// Domains allowed in addition to allowlists
+    #[serde(default)]
+    pub allow: Vec<String>,
+
+    /// Names matching pattern are answered as alias(CNAME) of replacement, first match is used
+    #[serde(default)]
+    pub rewrites: Vec<RewriteProps>,
+
+    #[serde(rename = "block-answer", default)]
+    pub block_answer: BlockAnswer,
+
+    /// Seconds between downloads of url lists
+    #[serde(rename = "refresh-seconds", default = "policy_refresh")]
+    pub refresh_seconds: u64,
+}
+
+/// Answer of blocked names
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockAnswer {
+    #[default]
+    Nxdomain,
+    /// 0.0.0.0 for A and :: for AAAA queries, other types have no answers
+    Zero,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteProps {
+    /// Regular expression matched against whole name without trailing dot,
+    /// ex. '^(.+)\.staging\.example\.com$'
+    pub pattern: String,
+
+    /// Replacement with capture groups, ex. '$1.dev.example.com'
+    pub replace: String,
+}
+
+/// Policy list entry is downloaded instead of read from file
+pub fn is_url(source: &str) -> bool {
+    let source = source.to_ascii_lowercase();
+    source.starts_with("http://") || source.starts_with("https://")
 }
 
-#[derive(Deserialize)]
+/// Registration of dns server in systemd-resolved, linux only
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ResolvedProps {
+    /// Network link which dns server is set on, loopback link is ignored by systemd-resolved
+    pub link: String,
+
+    /// Routing domains, by default zones and hosts served by dns
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct DnsServerProps {
+    /// Upstream dns server: ip address or 'udp://ip[:port]', 'tls://host[:port]'(DNS over TLS)
+    /// or 'https://host[:port]/path'(DNS over HTTPS)
     #[serde(default = "google_dns")]
     pub public: String,
 
     #[serde(default = "port_53")]
     pub port: u16,
 
+    /// Listen addresses separated by comma, ex. '127.0.0.1,::1'
     #[serde(default = "empty")]
     pub host: String,
+
+    /// Http method of DNS over HTTPS queries
+    #[serde(rename = "doh-method", default)]
+    pub doh_method: DohMethod,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DohMethod {
+    #[default]
+    Post,
+    Get,
+}
+
+impl DnsServerProps {
+    pub fn hosts(&self) -> Vec<String> {
+        split_hosts(&self.host)
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct K8sProps {
     #[serde(rename = "ingress-namespace", default = "default")]
     pub ingress_namespace: String,
 
     pub pod: K8sPodProps,
 
+    /// 'default', 'in-cluster' or kubeconfig paths separated same as in KUBECONFIG
     #[serde(default = "default")]
     pub config: String,
+
+    /// Kubeconfig context, current-context if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+
+    /// Override cluster of context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+
+    /// Override user of context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// Seconds which dns answers of ingress hosts are cached by clients
+    #[serde(default = "ingress_ttl")]
+    pub ttl: u32,
+}
+
+impl K8sProps {
+    /// Human readable cluster source for logs, ex. 'config(dev)'
+    pub fn source(&self) -> String {
+        match &self.context {
+            None => self.config.to_string(),
+            Some(context) => format!("{}({})", self.config, context),
+        }
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct K8sPodProps {
     #[serde(default = "default")]
     pub namespace: String,
@@ -79,19 +299,107 @@ pub struct K8sPodProps {
     pub port: PortProps,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ProxyProps {
+    /// Listen addresses separated by comma, ex. '127.0.0.1,::1', '::' is dual-stack on linux
     #[serde(default = "empty")]
     pub host: String,
 
     #[serde(default = "default_ports")]
     pub port: PortProps,
 
+    /// Addresses returned by dns for ingress hosts: 'auto' - address of interface which query
+    /// arrived on, or ip addresses separated by comma. By default addresses of listeners
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer: Option<String>,
+
     #[serde(rename = "root-ca")]
     pub root_ca: Option<ProxyTlsProps>,
 }
 
-#[derive(Deserialize)]
+/// Ingress hosts are answered with address of interface which dns query arrived on
+pub const ANSWER_AUTO: &str = "auto";
+
+impl ProxyProps {
+    pub fn hosts(&self) -> Vec<String> {
+        split_hosts(&self.host)
+    }
+
+    /// Configured answer addresses, empty if answer is not set or it is 'auto'
+    pub fn answers(&self) -> Vec<String> {
+        match &self.answer {
+            Some(answer) if !self.answer_auto() => split_hosts(answer),
+            _ => vec![],
+        }
+    }
+
+    pub fn answer_auto(&self) -> bool {
+        self.answer
+            .as_ref()
+            .is_some_and(|answer| answer.trim().eq_ignore_ascii_case(ANSWER_AUTO))
+    }
+}
+
+/// Formats which can prefix local cache entry, ex. 'zone:dev.zone', 'hosts:/etc/hosts'
+const CACHE_FORMATS: [&str; 3] = ["local", "hosts", "zone"];
+
+/// Split local cache entry into declared format and file path
+pub fn split_cache_format(cache: &str) -> (Option<&str>, &str) {
+    match cache.split_once(':') {
+        Some((format, path)) if CACHE_FORMATS.contains(&format.to_ascii_lowercase().as_str()) => {
+            (Some(format), path)
+        }
+        _ => (None, cache),
+    }
+}
+
+/// Split addresses separated by comma, empty items are skipped
+fn split_hosts(hosts: &str) -> Vec<String> {
+    hosts
+        .split(',')
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Prometheus metrics listener
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsProps {
+    #[serde(default = "localhost")]
+    pub host: String,
+
+    #[serde(default = "port_9153")]
+    pub port: u16,
+}
+
+/// Admin api listener, it must listen on loopback address
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdminProps {
+    #[serde(default = "localhost")]
+    pub host: String,
+
+    #[serde(default = "port_9180")]
+    pub port: u16,
+}
+
+/// User and group which process switches to, once listeners are bound as root
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PrivilegesProps {
+    /// User name or uid
+    pub user: String,
+
+    /// Group name or gid, primary group of user if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct PortProps {
     #[serde(default = "port_80")]
     pub http: u16,
@@ -100,17 +408,354 @@ pub struct PortProps {
     pub https: u16,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ProxyTlsProps {
     #[serde(default = "empty")]
     pub cert: String,
 
     #[serde(default = "empty")]
     pub key: String,
+
+    #[serde(rename = "key-algorithm", default = "ecdsa_p256")]
+    pub key_algorithm: KeyPairAlgorithm,
+
+    #[serde(rename = "validity-days", default = "cert_validity_days")]
+    pub validity_days: u32,
+
+    #[serde(rename = "subject-alt-names", default)]
+    pub subject_alt_names: Vec<String>,
+
+    #[serde(default)]
+    pub wildcard: bool,
+
+    #[serde(default = "empty")]
+    pub store: String,
+
+    #[serde(rename = "permitted-domains", default)]
+    pub permitted_domains: Vec<String>,
 }
 
-pub fn parse_properties() -> Result<Properties> {
-    let config_file = File::open("config.yaml")?;
-    let config = serde_yaml::from_reader::<File, Properties>(config_file)?;
+/// Supported Keypair Algorithms
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyPairAlgorithm {
+    Ed25519,
+    EcdsaP256,
+    EcdsaP384,
+    #[serde(rename = "rsa")]
+    #[value(name = "rsa")]
+    Rsa,
+}
+
+/// Prefix of environment variables which override config file,
+/// ex. `KIDNS_DNS__SERVER__PORT` -> `dns.server.port`, `KIDNS_K8S__0__CONFIG` -> `k8s[0].config`
+const ENV_PREFIX: &str = "KIDNS_";
+/// Environment variable of config path, it is not a property
+const ENV_CONFIG: &str = "KIDNS_CONFIG";
+/// Values of `k8s[].config` selecting inferred or in-cluster config
+pub const KUBE_DEFAULT_CONFIG: &str = "default";
+pub const KUBE_IN_CLUSTER_CONFIG: &str = "in-cluster";
+
+/// Build properties from defaults, config file and `KIDNS_*` environment variables.
+/// Missing config file is allowed if it is not required
+pub fn parse_properties(path: &str, required: bool) -> Result<Properties> {
+    let mut value = match std::fs::read_to_string(path) {
+        Ok(source) if source.trim().is_empty() => Value::Mapping(Mapping::new()),
+        Ok(source) => {
+            // parse file alone first, so errors keep location in file
+            serde_yaml::from_str::<Properties>(&source)
+                .map_err(|e| anyhow!("Invalid config {}, {}", path, e))?;
+            serde_yaml::from_str::<Value>(&source)?
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound && !required => Value::Mapping(Mapping::new()),
+        Err(e) => return Err(anyhow!("Unable to open config {}, err: {}", path, e)),
+    };
+
+    let env_vars = apply_env(&mut value, std::env::vars())?;
+    let mut config = serde_yaml::from_value::<Properties>(value).map_err(|e| {
+        anyhow!(
+            "Invalid config {} with environment variables {:?}, {}",
+            path,
+            env_vars,
+            e
+        )
+    })?;
+
+    let config_dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    config.resolve_paths(config_dir);
     return Ok(config);
 }
+
+/// Set `KIDNS_*` variables into config tree, return applied variable names
+fn apply_env(
+    config: &mut Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<Vec<String>> {
+    let mut applied = Vec::new();
+    for (name, value) in vars {
+        let Some(path) = env_path(&name) else {
+            continue;
+        };
+        // parse as yaml, so numbers, booleans and lists like '[k8s, local.conf]' are typed
+        let value = match serde_yaml::from_str::<Value>(&value) {
+            Ok(Value::Null) | Err(_) => Value::String(value),
+            Ok(value) => value,
+        };
+
+        set_path(config, &path, value)
+            .map_err(|e| anyhow!("Unable to apply environment variable {}, {}", name, e))?;
+        applied.push(name);
+    }
+    applied.sort();
+    Ok(applied)
+}
+
+/// Config path of `KIDNS_*` variable, ex. `KIDNS_K8S__0__CONFIG` -> `[k8s, 0, config]`
+fn env_path(name: &str) -> Option<Vec<String>> {
+    let key = name.strip_prefix(ENV_PREFIX)?;
+    if name == ENV_CONFIG || key.is_empty() {
+        return None;
+    }
+    Some(
+        key.split("__")
+            .map(|segment| segment.to_ascii_lowercase().replace('_', "-"))
+            .collect(),
+    )
+}
+
+/// Keys overridden by `KIDNS_*` variables in yaml path form, ex. `k8s[0].config`
+pub fn env_overridden_keys(vars: impl Iterator<Item = (String, String)>) -> Vec<String> {
+    vars.filter_map(|(name, _)| env_path(&name))
+        .map(|path| {
+            path.iter().fold(String::new(), |key, segment| {
+                match (segment.parse::<usize>(), key.is_empty()) {
+                    (Ok(index), _) => format!("{}[{}]", key, index),
+                    (Err(_), true) => segment.to_string(),
+                    (Err(_), false) => format!("{}.{}", key, segment),
+                }
+            })
+        })
+        .collect()
+}
+
+fn set_path(node: &mut Value, path: &[String], value: Value) -> Result<()> {
+    let Some((head, rest)) = path.split_first() else {
+        *node = value;
+        return Ok(());
+    };
+    if head.is_empty() {
+        return Err(anyhow!("empty key segment"));
+    }
+
+    if let Ok(index) = head.parse::<usize>() {
+        if !node.is_sequence() {
+            *node = Value::Sequence(vec![]);
+        }
+        let sequence = node.as_sequence_mut().unwrap();
+        if index > sequence.len() {
+            return Err(anyhow!(
+                "index {} is out of list with {} items",
+                index,
+                sequence.len()
+            ));
+        }
+        if index == sequence.len() {
+            sequence.push(Value::Mapping(Mapping::new()));
+        }
+        return set_path(&mut sequence[index], rest, value);
+    }
+
+    if !node.is_mapping() {
+        *node = Value::Mapping(Mapping::new());
+    }
+    let child = node
+        .as_mapping_mut()
+        .unwrap()
+        .entry(Value::String(head.to_string()))
+        .or_insert(Value::Null);
+    set_path(child, rest, value)
+}
+
+impl Properties {
+    /// Make relative file paths relative to config directory instead of working directory
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut String| {
+            if !path.is_empty() && Path::new(path.as_str()).is_relative() {
+                *path = base.join(path.as_str()).to_string_lossy().to_string();
+            }
+        };
+
+        for cache in self
+            .dns
+            .cache
+            .iter_mut()
+            .filter(|cache| !cache.eq_ignore_ascii_case("k8s"))
+        {
+            let (format, path) = split_cache_format(cache);
+            let mut path = path.to_string();
+            resolve(&mut path);
+            *cache = match format {
+                Some(format) => format!("{}:{}", format, path),
+                None => path,
+            };
+        }
+
+        for list in self.dns.policy.iter_mut().flat_map(|policy| {
+            policy
+                .blocklists
+                .iter_mut()
+                .chain(policy.allowlists.iter_mut())
+        }) {
+            if !is_url(list) {
+                resolve(list);
+            }
+        }
+
+        for k8s in self.k8s.iter_mut().flatten() {
+            if is_kube_file_config(&k8s.config) {
+                // same as KUBECONFIG, config can contain several paths
+                let paths: Vec<PathBuf> = std::env::split_paths(&k8s.config)
+                    .filter(|path| !path.as_os_str().is_empty())
+                    .map(|path| base.join(path))
+                    .collect();
+                if let Ok(paths) = std::env::join_paths(paths) {
+                    k8s.config = paths.to_string_lossy().to_string();
+                }
+            }
+        }
+
+        if let Some(tls) = self.proxy.as_mut().and_then(|proxy| proxy.root_ca.as_mut()) {
+            resolve(&mut tls.key);
+            resolve(&mut tls.cert);
+            resolve(&mut tls.store);
+        }
+    }
+}
+
+/// Kubeconfig is read from files, not from default location or service account
+pub fn is_kube_file_config(config: &str) -> bool {
+    !config.eq_ignore_ascii_case(KUBE_DEFAULT_CONFIG)
+        && !config.eq_ignore_ascii_case(KUBE_IN_CLUSTER_CONFIG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Properties of yaml source with variables applied same as in `parse_properties`
+    fn properties(yaml: &str, env: &[(&str, &str)]) -> Result<Properties> {
+        let mut value = serde_yaml::from_str::<Value>(yaml)?;
+        if value.is_null() {
+            value = Value::Mapping(Mapping::new());
+        }
+        apply_env(&mut value, vars(env))?;
+        Ok(serde_yaml::from_value(value)?)
+    }
+
+    #[test]
+    fn env_paths_of_variables() {
+        let path = |name| env_path(name).map(|path| path.join("/"));
+        assert_eq!(
+            path("KIDNS_DNS__SERVER__DOH_METHOD").as_deref(),
+            Some("dns/server/doh-method")
+        );
+        assert_eq!(
+            path("KIDNS_K8S__0__CONFIG").as_deref(),
+            Some("k8s/0/config")
+        );
+        assert_eq!(path("KIDNS_CONFIG"), None);
+        assert_eq!(path("KIDNS_"), None);
+        assert_eq!(path("OTHER_DNS__SERVER__PORT"), None);
+    }
+
+    #[test]
+    fn env_overridden_keys_are_yaml_paths() {
+        let keys = env_overridden_keys(vars(&[
+            ("KIDNS_DNS__SERVER__PORT", "53"),
+            ("KIDNS_K8S__1__POD__NAMESPACE", "ingress"),
+            ("KIDNS_CONFIG", "kidns.yaml"),
+            ("HOME", "/root"),
+        ]));
+        assert_eq!(keys, vec!["dns.server.port", "k8s[1].pod.namespace"]);
+    }
+
+    #[test]
+    fn env_takes_precedence_over_yaml() {
+        let props = properties(
+            "dns:\n  server:\n    port: 5353\n    host: 127.0.0.1\nlog-level: debug\n",
+            &[
+                ("KIDNS_DNS__SERVER__PORT", "1053"),
+                ("KIDNS_LOG_LEVEL", "warn"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(props.dns.server.port, 1053);
+        assert_eq!(props.dns.server.host, "127.0.0.1");
+        assert_eq!(props.log_level, "warn");
+    }
+
+    #[test]
+    fn env_values_are_typed_as_yaml() {
+        let props = properties(
+            "",
+            &[
+                ("KIDNS_DNS__CACHE", "[k8s, 'hosts:/etc/hosts']"),
+                ("KIDNS_DNS__SERVER__DOH_METHOD", "get"),
+                ("KIDNS_SHUTDOWN_TIMEOUT_SECONDS", "3"),
+                ("KIDNS_DNS__SERVER__PUBLIC", "1.1.1.1"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(props.dns.cache, vec!["k8s", "hosts:/etc/hosts"]);
+        assert_eq!(props.dns.server.doh_method, DohMethod::Get);
+        assert_eq!(props.shutdown_timeout_seconds, 3);
+        assert_eq!(props.dns.server.public, "1.1.1.1");
+    }
+
+    #[test]
+    fn env_list_indices_extend_or_update_lists() {
+        let props = properties(
+            "k8s:\n  - pod: {}\n    context: dev\n",
+            &[
+                ("KIDNS_K8S__0__CONTEXT", "prod"),
+                ("KIDNS_K8S__1__POD__NAMESPACE", "ingress"),
+            ],
+        )
+        .unwrap();
+        let k8s = props.k8s.unwrap();
+        assert_eq!(k8s.len(), 2);
+        assert_eq!(k8s[0].context.as_deref(), Some("prod"));
+        assert_eq!(k8s[1].pod.namespace, "ingress");
+
+        let err = properties("", &[("KIDNS_K8S__2__CONFIG", "in-cluster")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unable to apply environment variable KIDNS_K8S__2__CONFIG, \
+             index 2 is out of list with 0 items"
+        );
+    }
+
+    #[test]
+    fn invalid_env_paths_and_values_are_errors() {
+        let err = properties("", &[("KIDNS_DNS____PORT", "53")]).unwrap_err();
+        assert!(err.to_string().contains("empty key segment"), "{}", err);
+
+        // unknown keys are refused same as in yaml
+        let err = properties("", &[("KIDNS_DNS__SERVER__PROT", "53")]).unwrap_err();
+        assert!(err.to_string().contains("unknown field `prot`"), "{}", err);
+
+        // value which is not yaml is kept as string, so it fails as number
+        let err = properties("", &[("KIDNS_DNS__SERVER__PORT", "[53")]).unwrap_err();
+        assert!(err.to_string().contains("invalid type"), "{}", err);
+        let err = properties("", &[("KIDNS_DNS__SERVER__PORT", "dns")]).unwrap_err();
+        assert!(err.to_string().contains("invalid type"), "{}", err);
+    }
+}
@@ -0,0 +1,928 @@
+use crate::config::logs::check_log_filter;
+use crate::config::properties::{
+    is_kube_file_config, is_url, split_cache_format, K8sProps, PolicyProps, PortProps,
+    PrivilegesProps, Properties, ProxyProps, ResolvedProps, ZoneProps, KUBE_IN_CLUSTER_CONFIG,
+};
+use crate::dns::server::upstream::check_upstream;
+use crate::k8s::client::read_kubeconfig;
+#[cfg(unix)]
+use crate::privileges::{find_group, find_user};
+use anyhow::{anyhow, Result};
+use kube::config::Kubeconfig;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Problem of one property, key is path in yaml, ex. `k8s[0].pod.label`
+struct ConfigError {
+    key: String,
+    message: String,
+    location: Option<(usize, usize)>,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.location {
+            None => write!(f, "{}: {}", self.key, self.message),
+            Some((line, column)) => {
+                write!(
+                    f,
+                    "line {} column {}: {}: {}",
+                    line, column, self.key, self.message
+                )
+            }
+        }
+    }
+}
+
+/// Check all properties and report every problem with its yaml location,
+/// overridden keys are set by environment variables or flags, so they have no location in file
+pub fn validate_properties(
+    props: &Properties,
+    config_path: &str,
+    overridden: &[String],
+) -> Result<()> {
+    let mut errors = Vec::new();
+    let mut error = |key: String, message: String| errors.push((key, message));
+
+    if let Err(e) = check_log_filter(&props.log_level) {
+        error("log-level".to_string(), e);
+    }
+
+    let server = &props.dns.server;
+    if !server.host.is_empty() {
+        for host in server.hosts() {
+            check_ip("dns.server.host", &host, &mut error);
+        }
+        check_port("dns.server.port", server.port, &mut error);
+    }
+    if let Err(e) = check_upstream(&server.public) {
+        error("dns.server.public".to_string(), e.to_string());
+    }
+
+    if let Some(resolved) = &props.dns.resolved {
+        check_resolved(props, resolved, &mut error);
+    }
+
+    check_zones(&props.dns.zones, &mut error);
+
+    if let Some(policy) = &props.dns.policy {
+        check_policy(policy, &mut error);
+    }
+
+    for (i, cache) in props.dns.cache.iter().enumerate() {
+        let key = format!("dns.cache[{}]", i);
+        if cache.eq_ignore_ascii_case("k8s") {
+            if matches!(props.k8s.as_deref(), None | Some([])) {
+                error(key, "'k8s' cache requires 'k8s' section".to_string());
+            }
+        } else {
+            check_file(&key, split_cache_format(cache).1, &mut error);
+        }
+    }
+
+    for (i, k8s) in props.k8s.iter().flatten().enumerate() {
+        check_k8s(&format!("k8s[{}]", i), k8s, &mut error);
+    }
+
+    if let Some(proxy) = &props.proxy {
+        check_proxy(proxy, &mut error);
+    }
+
+    if let Some(metrics) = &props.metrics {
+        check_ip("metrics.host", &metrics.host, &mut error);
+        check_port("metrics.port", metrics.port, &mut error);
+    }
+
+    if let Some(admin) = &props.admin {
+        match IpAddr::from_str(&admin.host) {
+            Ok(ip) if ip.is_loopback() => {}
+            _ => error(
+                "admin.host".to_string(),
+                format!("'{}' is not loopback ip address", admin.host),
+            ),
+        }
+        check_port("admin.port", admin.port, &mut error);
+    }
+
+    if let Some(privileges) = &props.privileges {
+        check_privileges(privileges, &mut error);
+        if props.dns.resolved.is_some() {
+            // polkit refuses to manage links of systemd-resolved for unprivileged user
+            error(
+                "privileges".to_string(),
+                "can't be used with 'dns.resolved', registration runs after privileges are dropped"
+                    .to_string(),
+            );
+        }
+    }
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    let source = std::fs::read_to_string(config_path).unwrap_or_default();
+    let lines = yaml_lines(&source);
+    let errors: Vec<String> = errors
+        .into_iter()
+        .map(|(key, message)| ConfigError {
+            location: match is_overridden(&key, overridden) {
+                true => None,
+                false => locate(&lines, &key),
+            },
+            key,
+            message,
+        })
+        .map(|e| e.to_string())
+        .collect();
+
+    Err(anyhow!(
+        "Invalid config {}, found {} problem(s):\n  {}",
+        config_path,
+        errors.len(),
+        errors.join("\n  ")
+    ))
+}
+
+fn check_k8s(key: &str, props: &K8sProps, error: &mut impl FnMut(String, String)) {
+    let config_key = format!("{}.config", key);
+    if props.config.eq_ignore_ascii_case(KUBE_IN_CLUSTER_CONFIG) {
+        for (name, value) in [
+            ("context", &props.context),
+            ("cluster", &props.cluster),
+            ("user", &props.user),
+        ] {
+            if value.is_some() {
+                error(
+                    format!("{}.{}", key, name),
+                    "is not used with 'in-cluster' config".to_string(),
+                );
+            }
+        }
+    } else if is_kube_file_config(&props.config) {
+        let paths: Vec<String> = std::env::split_paths(&props.config)
+            .filter(|path| !path.as_os_str().is_empty())
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+        if paths.is_empty() {
+            error(config_key, "kubeconfig path is empty".to_string());
+        } else {
+            // every missing file is reported, kubeconfig is parsed only if all of them exist
+            let found: Vec<bool> = paths
+                .iter()
+                .map(|path| check_file(&config_key, path, error))
+                .collect();
+            if found.iter().all(|found| *found) {
+                match read_kubeconfig(&props.config) {
+                    Err(e) => error(config_key, format!("unable to parse kubeconfig, {}", e)),
+                    Ok(kubeconfig) => check_kubeconfig(key, props, &kubeconfig, error),
+                }
+            }
+        }
+    } else if props.context.is_some() || props.cluster.is_some() || props.user.is_some() {
+        // without overrides default config may fall back to service account, so it is optional
+        match read_kubeconfig(&props.config) {
+            Err(e) => error(
+                config_key,
+                format!("unable to read default kubeconfig, {}", e),
+            ),
+            Ok(kubeconfig) => check_kubeconfig(key, props, &kubeconfig, error),
+        }
+    }
+
+    check_namespace(
+        &format!("{}.ingress-namespace", key),
+        &props.ingress_namespace,
+        error,
+    );
+    check_namespace(
+        &format!("{}.pod.namespace", key),
+        &props.pod.namespace,
+        error,
+    );
+    if let Err(e) = check_label_selector(&props.pod.label) {
+        error(format!("{}.pod.label", key), e);
+    }
+    check_ports(&format!("{}.pod.port", key), &props.pod.port, error);
+}
+
+/// Check that selected context, cluster and user exist in kubeconfig
+fn check_kubeconfig(
+    key: &str,
+    props: &K8sProps,
+    kubeconfig: &Kubeconfig,
+    error: &mut impl FnMut(String, String),
+) {
+    let contexts: Vec<&String> = kubeconfig.contexts.iter().map(|ctx| &ctx.name).collect();
+    let context = match (&props.context, &kubeconfig.current_context) {
+        (Some(context), _) => (format!("{}.context", key), context),
+        (None, Some(current)) => (format!("{}.config", key), current),
+        (None, None) => {
+            error(
+                format!("{}.config", key),
+                "kubeconfig has no current-context, set 'context'".to_string(),
+            );
+            return;
+        }
+    };
+    if !contexts.contains(&context.1) {
+        error(
+            context.0,
+            format!(
+                "context '{}' is not found in kubeconfig contexts {:?}",
+                context.1, contexts
+            ),
+        );
+    }
+
+    if let Some(cluster) = &props.cluster {
+        let clusters: Vec<&String> = kubeconfig.clusters.iter().map(|c| &c.name).collect();
+        if !clusters.contains(&cluster) {
+            error(
+                format!("{}.cluster", key),
+                format!(
+                    "cluster '{}' is not found in kubeconfig clusters {:?}",
+                    cluster, clusters
+                ),
+            );
+        }
+    }
+    if let Some(user) = &props.user {
+        let users: Vec<&String> = kubeconfig.auth_infos.iter().map(|u| &u.name).collect();
+        if !users.contains(&user) {
+            error(
+                format!("{}.user", key),
+                format!(
+                    "user '{}' is not found in kubeconfig users {:?}",
+                    user, users
+                ),
+            );
+        }
+    }
+}
+
+fn check_zones(zones: &[ZoneProps], error: &mut impl FnMut(String, String)) {
+    for (i, zone) in zones.iter().enumerate() {
+        let key = format!("dns.zones[{}]", i);
+        if !is_dns_name(&zone.name) {
+            error(
+                format!("{}.name", key),
+                format!("'{}' is not dns name", zone.name),
+            );
+        } else if zones[..i].iter().any(|other| {
+            other
+                .name
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(zone.name.trim_end_matches('.'))
+        }) {
+            error(
+                format!("{}.name", key),
+                format!("zone '{}' is duplicated", zone.name),
+            );
+        }
+        if !is_dns_name(&zone.ns) {
+            error(
+                format!("{}.ns", key),
+                format!("'{}' is not dns name", zone.ns),
+            );
+        }
+    }
+}
+
+fn check_policy(policy: &PolicyProps, error: &mut impl FnMut(String, String)) {
+    for (name, lists) in [
+        ("blocklists", &policy.blocklists),
+        ("allowlists", &policy.allowlists),
+    ] {
+        for (i, list) in lists.iter().enumerate() {
+            let key = format!("dns.policy.{}[{}]", name, i);
+            if is_url(list) {
+                if matches!(list.split_once("://"), None | Some((_, ""))) {
+                    error(key, format!("'{}' has no host", list));
+                }
+            } else {
+                check_file(&key, list, error);
+            }
+        }
+    }
+
+    for (name, domains) in [("block", &policy.block), ("allow", &policy.allow)] {
+        for (i, domain) in domains.iter().enumerate() {
+            if !is_dns_name(domain.trim_start_matches("*.")) {
+                error(
+                    format!("dns.policy.{}[{}]", name, i),
+                    format!("'{}' is not dns name", domain),
+                );
+            }
+        }
+    }
+
+    for (i, rewrite) in policy.rewrites.iter().enumerate() {
+        if let Err(e) = regex::Regex::new(&rewrite.pattern) {
+            error(
+                format!("dns.policy.rewrites[{}].pattern", i),
+                // last line of parse error is its reason, previous ones point to position
+                format!(
+                    "invalid regular expression, {}",
+                    e.to_string().lines().last().unwrap_or_default().trim()
+                ),
+            );
+        }
+        if rewrite.replace.trim().is_empty() {
+            error(
+                format!("dns.policy.rewrites[{}].replace", i),
+                "replacement is empty".to_string(),
+            );
+        }
+    }
+
+    if policy.refresh_seconds == 0 {
+        error(
+            "dns.policy.refresh-seconds".to_string(),
+            "must be greater than 0".to_string(),
+        );
+    }
+}
+
+fn check_resolved(
+    props: &Properties,
+    resolved: &ResolvedProps,
+    error: &mut impl FnMut(String, String),
+) {
+    if cfg!(not(target_os = "linux")) {
+        error(
+            "dns.resolved".to_string(),
+            "systemd-resolved is supported only on linux".to_string(),
+        );
+        return;
+    }
+    if props.dns.server.host.is_empty() {
+        error(
+            "dns.resolved".to_string(),
+            "requires 'dns.server.host'".to_string(),
+        );
+    }
+
+    if resolved.link.is_empty() {
+        error("dns.resolved.link".to_string(), "link is empty".to_string());
+    } else if resolved.link == "lo" {
+        error(
+            "dns.resolved.link".to_string(),
+            "loopback link is ignored by systemd-resolved".to_string(),
+        );
+    } else if !Path::new("/sys/class/net").join(&resolved.link).exists() {
+        error(
+            "dns.resolved.link".to_string(),
+            format!("network link '{}' is not found", resolved.link),
+        );
+    }
+    for (i, domain) in resolved.domains.iter().enumerate() {
+        if !is_dns_name(domain.trim_start_matches('~')) {
+            error(
+                format!("dns.resolved.domains[{}]", i),
+                format!("'{}' is not dns name", domain),
+            );
+        }
+    }
+}
+
+#[cfg(unix)]
+fn check_privileges(props: &PrivilegesProps, error: &mut impl FnMut(String, String)) {
+    if let Err(e) = find_user(&props.user) {
+        error("privileges.user".to_string(), e.to_string());
+    }
+    if let Some(Err(e)) = props.group.as_deref().map(find_group) {
+        error("privileges.group".to_string(), e.to_string());
+    }
+}
+
+#[cfg(not(unix))]
+fn check_privileges(_: &PrivilegesProps, error: &mut impl FnMut(String, String)) {
+    error(
+        "privileges".to_string(),
+        "dropping privileges is supported only on unix".to_string(),
+    );
+}
+
+fn check_proxy(props: &ProxyProps, error: &mut impl FnMut(String, String)) {
+    let hosts = props.hosts();
+    if hosts.is_empty() {
+        error("proxy.host".to_string(), "address is empty".to_string());
+    }
+    for host in hosts {
+        check_ip("proxy.host", &host, error);
+    }
+    check_ports("proxy.port", &props.port, error);
+
+    if props.answer.is_some() && !props.answer_auto() {
+        let answers = props.answers();
+        if answers.is_empty() {
+            error(
+                "proxy.answer".to_string(),
+                "expected 'auto' or ip addresses".to_string(),
+            );
+        }
+        for answer in answers {
+            match IpAddr::from_str(&answer) {
+                Ok(ip) if ip.is_unspecified() => error(
+                    "proxy.answer".to_string(),
+                    format!("'{}' is not reachable by clients", answer),
+                ),
+                Ok(_) => {}
+                Err(_) => error(
+                    "proxy.answer".to_string(),
+                    format!("'{}' is not ip address or 'auto'", answer),
+                ),
+            }
+        }
+    }
+
+    let Some(tls) = &props.root_ca else {
+        return;
+    };
+    check_file("proxy.root-ca.key", &tls.key, error);
+    check_file("proxy.root-ca.cert", &tls.cert, error);
+
+    if tls.validity_days == 0 {
+        error(
+            "proxy.root-ca.validity-days".to_string(),
+            "must be greater than 0".to_string(),
+        );
+    }
+    if !tls.store.is_empty() && Path::new(&tls.store).is_file() {
+        error(
+            "proxy.root-ca.store".to_string(),
+            format!("'{}' is file, expected directory", tls.store),
+        );
+    }
+    for (i, name) in tls.subject_alt_names.iter().enumerate() {
+        if IpAddr::from_str(name).is_err() && !is_dns_name(name.trim_start_matches("*.")) {
+            error(
+                format!("proxy.root-ca.subject-alt-names[{}]", i),
+                format!("'{}' is neither dns name nor ip address", name),
+            );
+        }
+    }
+    for (i, domain) in tls.permitted_domains.iter().enumerate() {
+        if !is_dns_name(domain.trim_start_matches('.')) {
+            error(
+                format!("proxy.root-ca.permitted-domains[{}]", i),
+                format!("'{}' is not dns name", domain),
+            );
+        }
+    }
+}
+
+fn check_ip(key: &str, value: &str, error: &mut impl FnMut(String, String)) {
+    if IpAddr::from_str(value).is_err() {
+        error(key.to_string(), format!("'{}' is not ip address", value));
+    }
+}
+
+fn check_port(key: &str, port: u16, error: &mut impl FnMut(String, String)) {
+    if port == 0 {
+        error(key.to_string(), "port must be greater than 0".to_string());
+    }
+}
+
+fn check_ports(key: &str, ports: &PortProps, error: &mut impl FnMut(String, String)) {
+    check_port(&format!("{}.http", key), ports.http, error);
+    check_port(&format!("{}.https", key), ports.https, error);
+    if ports.http == ports.https {
+        error(
+            format!("{}.https", key),
+            format!("http and https ports must differ, both are {}", ports.http),
+        );
+    }
+}
+
+/// Return if file exists
+fn check_file(key: &str, path: &str, error: &mut impl FnMut(String, String)) -> bool {
+    if path.is_empty() {
+        error(key.to_string(), "file path is empty".to_string());
+        return false;
+    }
+    if !Path::new(path).is_file() {
+        error(key.to_string(), format!("file '{}' is not found", path));
+        return false;
+    }
+    true
+}
+
+fn check_namespace(key: &str, namespace: &str, error: &mut impl FnMut(String, String)) {
+    if !is_dns_label(namespace) {
+        error(
+            key.to_string(),
+            format!(
+                "'{}' is not valid namespace name(RFC 1123 label)",
+                namespace
+            ),
+        );
+    }
+}
+
+/// Validate kubernetes label selector, ex. `app=nginx,tier!=db,env in (dev,qa),!canary`
+fn check_label_selector(selector: &str) -> std::result::Result<(), String> {
+    if selector.trim().is_empty() {
+        return Err("label selector is empty".to_string());
+    }
+
+    for requirement in split_requirements(selector) {
+        let requirement = requirement.trim();
+        let words: Vec<&str> = requirement.split_whitespace().collect();
+
+        if let [key, op, values @ ..] = words.as_slice() {
+            if *op == "in" || *op == "notin" {
+                check_label_key(key)?;
+                let values = values.join("");
+                let values = values
+                    .strip_prefix('(')
+                    .and_then(|v| v.strip_suffix(')'))
+                    .ok_or(format!("'{}' values must be in parentheses", requirement))?;
+                for value in values.split(',') {
+                    check_label_value(value)?;
+                }
+                continue;
+            }
+        }
+
+        if let Some((key, value)) = requirement
+            .split_once("!=")
+            .or_else(|| requirement.split_once("=="))
+            .or_else(|| requirement.split_once('='))
+        {
+            check_label_key(key.trim())?;
+            check_label_value(value.trim())?;
+        } else {
+            check_label_key(requirement.strip_prefix('!').unwrap_or(requirement).trim())?;
+        }
+    }
+    Ok(())
+}
+
+/// Split selector by commas, which are not inside of parentheses
+fn split_requirements(selector: &str) -> Vec<&str> {
+    let mut requirements = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in selector.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                requirements.push(&selector[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    requirements.push(&selector[start..]);
+    requirements
+}
+
+fn check_label_key(key: &str) -> std::result::Result<(), String> {
+    let (prefix, name) = match key.split_once('/') {
+        None => (None, key),
+        Some((prefix, name)) => (Some(prefix), name),
+    };
+    if let Some(prefix) = prefix {
+        if prefix.len() > 253 || !is_dns_name(prefix) {
+            return Err(format!(
+                "label key prefix '{}' is not dns subdomain",
+                prefix
+            ));
+        }
+    }
+    if name.is_empty() || !is_label_value(name) {
+        return Err(format!("label key '{}' is not valid", key));
+    }
+    Ok(())
+}
+
+fn check_label_value(value: &str) -> std::result::Result<(), String> {
+    if !value.is_empty() && !is_label_value(value) {
+        return Err(format!("label value '{}' is not valid", value));
+    }
+    Ok(())
+}
+
+/// Up to 63 alphanumeric, '-', '_', '.' characters, begin and end with alphanumeric
+fn is_label_value(value: &str) -> bool {
+    value.len() <= 63
+        && value.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && value.ends_with(|c: char| c.is_ascii_alphanumeric())
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// RFC 1123 label, lowercase alphanumeric or '-', begin and end with alphanumeric
+fn is_dns_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 63
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+pub(crate) fn is_dns_name(name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    !name.is_empty() && name.len() <= 253 && name.split('.').all(is_dns_label)
+}
+
+/// Non empty and non comment yaml line
+struct YamlLine {
+    number: usize,
+    /// column of first key on line, after list item dash
+    key_column: usize,
+    /// content starting from key column, empty for item dash without content
+    content: String,
+    /// column of list item dash
+    dash_column: Option<usize>,
+}
+
+fn yaml_lines(source: &str) -> Vec<YamlLine> {
+    source
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(number, line)| {
+            let indent = line.len() - line.trim_start().len();
+            let trimmed = line.trim_start();
+            match trimmed.strip_prefix("- ") {
+                None if trimmed == "-" => YamlLine {
+                    number,
+                    key_column: indent,
+                    content: String::new(),
+                    dash_column: Some(indent),
+                },
+                None => YamlLine {
+                    number,
+                    key_column: indent,
+                    content: trimmed.to_string(),
+                    dash_column: None,
+                },
+                Some(item) => YamlLine {
+                    number,
+                    key_column: indent + 2 + (item.len() - item.trim_start().len()),
+                    content: item.trim_start().to_string(),
+                    dash_column: Some(indent),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Find (line, column) of key path, ex. `k8s[0].pod.label`, both 1-based
+fn locate(lines: &[YamlLine], key: &str) -> Option<(usize, usize)> {
+    let mut scope = lines;
+    let mut found = None;
+
+    for segment in key.split('.') {
+        let (name, index) = match segment.split_once('[') {
+            None => (segment, None),
+            Some((name, index)) => (name, index.trim_end_matches(']').parse::<usize>().ok()),
+        };
+
+        // direct children of scope have the smallest key column
+        let column = scope
+            .iter()
+            .filter(|line| !line.content.is_empty())
+            .map(|line| line.key_column)
+            .min()?;
+        let position = scope.iter().position(|line| {
+            line.key_column == column
+                && (line.content.starts_with(&format!("{}:", name))
+                    || line.content.starts_with(&format!("\"{}\":", name))
+                    || line.content.starts_with(&format!("'{}':", name)))
+        })?;
+        found = Some(&scope[position]);
+        scope = child_scope(&scope[position + 1..], column);
+
+        if let Some(index) = index {
+            // items of sequence have the smallest dash column, deeper ones belong to items
+            let dash_column = scope.iter().filter_map(|line| line.dash_column).min();
+            let items: Vec<usize> = scope
+                .iter()
+                .enumerate()
+                .filter(|(_, line)| line.dash_column.is_some() && line.dash_column == dash_column)
+                .map(|(i, _)| i)
+                .collect();
+
+            match items.get(index) {
+                Some(&start) => {
+                    let end = items.get(index + 1).copied().unwrap_or(scope.len());
+                    found = Some(&scope[start]);
+                    scope = &scope[start..end];
+                }
+                None => {
+                    // list in flow style, ex. `[a, b]`
+                    break;
+                }
+            }
+        }
+    }
+
+    found.map(|line| (line.number + 1, line.key_column + 1))
+}
+
+/// Key is overridden itself or it is inside of overridden key, ex. `dns.cache[1]` of `dns.cache`
+fn is_overridden(key: &str, overridden: &[String]) -> bool {
+    overridden.iter().any(|over| {
+        key == over
+            || key
+                .strip_prefix(over.as_str())
+                .is_some_and(|rest| rest.starts_with('.') || rest.starts_with('['))
+    })
+}
+
+/// Lines which belong to key on given column
+fn child_scope(lines: &[YamlLine], column: usize) -> &[YamlLine] {
+    let end = lines
+        .iter()
+        .position(|line| line.key_column <= column)
+        .unwrap_or(lines.len());
+    &lines[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::properties::parse_properties;
+    use assert_fs::prelude::*;
+    use assert_fs::NamedTempFile;
+
+    const CONFIG: &str = r#"# kidns config
+dns:
+  server:
+    host: 127.0.0.1
+
+    port: 53
+  cache: [k8s, local.conf]
+  policy:
+    blocklists:
+      - ads.txt
+      - tracking.txt
+"k8s":
+  - config: default
+    pod:
+      label: app=one
+  -
+    'config': other
+    pod:
+      label: app=two
+"#;
+
+    #[test]
+    fn locate_nested_keys() {
+        let lines = yaml_lines(CONFIG);
+        assert_eq!(locate(&lines, "dns"), Some((2, 1)));
+        assert_eq!(locate(&lines, "dns.server.host"), Some((4, 5)));
+        // blank and comment lines keep line numbers
+        assert_eq!(locate(&lines, "dns.server.port"), Some((6, 5)));
+        assert_eq!(locate(&lines, "dns.server.public"), None);
+        assert_eq!(locate(&lines, "proxy.host"), None);
+    }
+
+    #[test]
+    fn locate_sequence_items() {
+        let lines = yaml_lines(CONFIG);
+        assert_eq!(locate(&lines, "dns.policy.blocklists[0]"), Some((10, 9)));
+        assert_eq!(locate(&lines, "dns.policy.blocklists[1]"), Some((11, 9)));
+        assert_eq!(locate(&lines, "k8s[0].config"), Some((13, 5)));
+        assert_eq!(locate(&lines, "k8s[0].pod.label"), Some((15, 7)));
+        assert_eq!(locate(&lines, "k8s[1].pod.label"), Some((19, 7)));
+    }
+
+    #[test]
+    fn locate_flow_list_item_at_its_key() {
+        let lines = yaml_lines(CONFIG);
+        assert_eq!(locate(&lines, "dns.cache[1]"), Some((7, 3)));
+        // out of block sequence falls back to sequence key
+        assert_eq!(locate(&lines, "dns.policy.blocklists[5]"), Some((9, 5)));
+    }
+
+    #[test]
+    fn locate_quoted_keys() {
+        let lines = yaml_lines(CONFIG);
+        assert_eq!(locate(&lines, "k8s"), Some((12, 1)));
+        assert_eq!(locate(&lines, "k8s[1].config"), Some((17, 5)));
+    }
+
+    #[test]
+    fn overridden_keys_cover_their_children() {
+        let overridden = vec!["dns.cache".to_string(), "k8s[0].config".to_string()];
+        assert!(is_overridden("dns.cache", &overridden));
+        assert!(is_overridden("dns.cache[1]", &overridden));
+        assert!(is_overridden("k8s[0].config", &overridden));
+        assert!(!is_overridden("dns.cache-size", &overridden));
+        assert!(!is_overridden("k8s[0].context", &overridden));
+        assert!(!is_overridden("dns", &overridden));
+    }
+
+    #[test]
+    fn overridden_keys_are_reported_without_location() {
+        let config = NamedTempFile::new("config.yaml").unwrap();
+        config
+            .write_str("dns:\n  server:\n    host: 127.0.0.1\n    port: 0\nlog-level: info\n")
+            .unwrap();
+        let path = config.path().to_str().unwrap();
+        let mut props = parse_properties(path, true).unwrap();
+        props.log_level = "kidns=loud".to_string();
+
+        let located = validate_properties(&props, path, &[])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            located.contains("line 4 column 5: dns.server.port"),
+            "{}",
+            located
+        );
+        assert!(
+            located.contains("line 5 column 1: log-level"),
+            "{}",
+            located
+        );
+
+        let overridden = ["log-level".to_string()];
+        let errors = validate_properties(&props, path, &overridden)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            errors.contains("line 4 column 5: dns.server.port"),
+            "{}",
+            errors
+        );
+        assert!(errors.contains("\n  log-level: "), "{}", errors);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn privileges_are_refused_with_resolved() {
+        let config = NamedTempFile::new("config.yaml").unwrap();
+        config
+            .write_str("dns:\n  resolved:\n    link: eth0\nprivileges:\n  user: root\n")
+            .unwrap();
+        let path = config.path().to_str().unwrap();
+        let props = parse_properties(path, true).unwrap();
+
+        let errors = validate_properties(&props, path, &[])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            errors.contains("line 4 column 1: privileges: can't be used with 'dns.resolved'"),
+            "{}",
+            errors
+        );
+    }
+
+    #[test]
+    fn valid_label_selectors() {
+        for selector in [
+            "app=nginx",
+            "app==nginx,tier!=db",
+            "env in (dev, qa),!canary",
+            "env notin (prod),example.com/team=core",
+            "app=",
+            "canary",
+        ] {
+            assert!(
+                check_label_selector(selector).is_ok(),
+                "{} is valid",
+                selector
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_label_selectors() {
+        for selector in [
+            "",
+            " ",
+            "app=-nginx",
+            "app=nginx,",
+            "env in dev,qa",
+            "env in (dev,-qa)",
+            "Example_.com/app=one",
+            "app=a/b",
+            "-app",
+            &format!("app={}", "a".repeat(64)),
+        ] {
+            assert!(
+                check_label_selector(selector).is_err(),
+                "{} is invalid",
+                selector
+            );
+        }
+    }
+
+    #[test]
+    fn label_selector_is_split_outside_of_parentheses() {
+        assert_eq!(
+            split_requirements("app=one,env in (dev,qa),!canary"),
+            vec!["app=one", "env in (dev,qa)", "!canary"]
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use log::{debug, warn};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+/// Wait after first change, editors usually write file in several steps
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Notify about changes of config and local cache files
+pub struct ConfigWatcher {
+    _watcher: RecommendedWatcher,
+    changes: UnboundedReceiver<()>,
+}
+
+impl ConfigWatcher {
+    pub fn new(files: Vec<PathBuf>) -> Result<ConfigWatcher> {
+        let files: HashSet<PathBuf> = files.iter().map(|file| absolute(file)).collect();
+        // watch directories, because editors replace files instead of writing them
+        let dirs: HashSet<PathBuf> = files
+            .iter()
+            .filter_map(|file| file.parent().map(|dir| dir.to_path_buf()))
+            .collect();
+
+        let (sender, changes) = unbounded_channel();
+        let watched_files = files.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+                Ok(event) => {
+                    if event.kind.is_access() {
+                        return;
+                    }
+                    if event.paths.iter().any(|path| watched_files.contains(path)) {
+                        debug!("Watched file changed: {:?}", event.paths);
+                        let _ = sender.send(());
+                    }
+                }
+                Err(e) => warn!("Unable to watch config files, err: {:?}", e),
+            })?;
+
+        for dir in dirs {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        }
+
+        Ok(ConfigWatcher {
+            _watcher: watcher,
+            changes,
+        })
+    }
+
+    /// Wait for change of any watched file
+    pub async fn changed(&mut self) {
+        if self.changes.recv().await.is_none() {
+            // watcher is stopped, never notify again
+            std::future::pending::<()>().await;
+        }
+
+        tokio::time::sleep(DEBOUNCE).await;
+        while self.changes.try_recv().is_ok() {}
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    match std::env::current_dir() {
+        Ok(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
@@ -1,6 +1,8 @@
+pub mod buffer;
 pub mod header;
+pub mod local_cache;
 pub mod packet;
-pub mod record;
 pub mod question;
-pub mod buffer;
+pub mod record;
+pub mod resolved;
 pub mod server;
@@ -2,20 +2,26 @@ use anyhow::{anyhow, Result};
 
 pub const PACKET_SIZE: usize = 1432; // for IPv4 is enough
 // pub const PACKET_SIZE: usize = 508; // 576 IPv4 (every host must be able to reassemble) - 60 IPv4 header - 8 UDP header
+/// Limit of message over tcp, its length prefix is two bytes
+pub const MESSAGE_SIZE: usize = u16::MAX as usize;
 
 pub struct BytePacketBuffer {
-    pub buf: [u8; PACKET_SIZE],
+    pub buf: Vec<u8>,
     pub pos: usize,
     pub max_size: usize,
 }
 
 impl BytePacketBuffer {
     pub fn new() -> BytePacketBuffer {
-        return BytePacketBuffer {
-            buf: [0; PACKET_SIZE],
+        BytePacketBuffer::with_size(PACKET_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: vec![0; size],
             pos: 0,
-            max_size: PACKET_SIZE,
-        };
+            max_size: size,
+        }
     }
 
     pub fn pos(&self) -> usize {
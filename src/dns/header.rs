@@ -1,4 +1,7 @@
 use crate::dns::buffer::BytePacketBuffer;
+use anyhow::anyhow;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ResultCode {
@@ -72,6 +75,37 @@ impl QueryType {
     }
 }
 
+impl FromStr for QueryType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" => Ok(QueryType::A),
+            "NS" => Ok(QueryType::NS),
+            "CNAME" => Ok(QueryType::CNAME),
+            "SOA" => Ok(QueryType::SOA),
+            "PTR" => Ok(QueryType::PTR),
+            "MX" => Ok(QueryType::MX),
+            "TXT" => Ok(QueryType::TXT),
+            "AAAA" => Ok(QueryType::AAAA),
+            "SRV" => Ok(QueryType::SRV),
+            other => match other.strip_prefix("TYPE").map(u16::from_str) {
+                Some(Ok(num)) => Ok(QueryType::from_num(num)),
+                _ => Err(anyhow!("Unknown query type {}", s)),
+            },
+        }
+    }
+}
+
+impl Display for QueryType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{}", num),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct DnsHeader {
     pub id: u16,
@@ -0,0 +1,767 @@
+use crate::config::properties::split_cache_format;
+use crate::config::validate::is_dns_name;
+use crate::dns::record::DnsRecord;
+use anyhow::{anyhow, Result};
+use log::debug;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Ttl of records without explicit ttl
+const DEFAULT_TTL: u32 = 300;
+
+/// Format of local cache file, declared as prefix of `dns.cache` entry or detected by content
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheFormat {
+    /// `name=ip[:port]` lines
+    Local,
+    /// `/etc/hosts` lines, `ip name [aliases]`
+    Hosts,
+    /// RFC 1035 master file with $ORIGIN and $TTL
+    Zone,
+}
+
+impl FromStr for CacheFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<CacheFormat> {
+        match format.to_ascii_lowercase().as_str() {
+            "local" => Ok(CacheFormat::Local),
+            "hosts" => Ok(CacheFormat::Hosts),
+            "zone" => Ok(CacheFormat::Zone),
+            _ => Err(anyhow!("Unknown local cache format '{}'", format)),
+        }
+    }
+}
+
+/// Record of local cache file
+#[derive(Clone, Debug)]
+pub struct LocalEntry {
+    pub host: String,
+    pub record: DnsRecord,
+    /// Port of proxied service, 0 if it is not set
+    pub port: u16,
+}
+
+/// Load records of `dns.cache` entry, ex. 'local_cache.conf' or 'zone:dev.zone'
+pub async fn load_local_entries(cache: &str) -> Result<Vec<LocalEntry>> {
+    let (format, path) = split_cache_format(cache);
+    let source = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow!("Can't open file {}, err: {}", path, e))?;
+    let format = match format {
+        Some(format) => format.parse()?,
+        None => detect_format(path, &source),
+    };
+    parse_local_cache(&source, format).map_err(|e| anyhow!("{} {}", path, e))
+}
+
+/// Addresses of local cache hosts, first address of host is used
+pub async fn load_local_cache(cache: &str) -> Result<HashMap<String, SocketAddr>> {
+    let mut hosts = HashMap::new();
+    for entry in load_local_entries(cache).await? {
+        let ip = match entry.record {
+            DnsRecord::A { addr, .. } => IpAddr::V4(addr),
+            DnsRecord::AAAA { addr, .. } => IpAddr::V6(addr),
+            _ => continue,
+        };
+        hosts
+            .entry(entry.host)
+            .or_insert(SocketAddr::new(ip, entry.port));
+    }
+    Ok(hosts)
+}
+
+/// Aliases of local cache hosts(CNAME records) with their target names
+pub async fn load_local_aliases(cache: &str) -> Result<HashMap<String, String>> {
+    let mut aliases = HashMap::new();
+    for entry in load_local_entries(cache).await? {
+        if let DnsRecord::CNAME { host, .. } = entry.record {
+            aliases.entry(entry.host).or_insert(host);
+        }
+    }
+    Ok(aliases)
+}
+
+/// Zone file by extension or first directive, hosts file by name or first ip address,
+/// local format by '='
+fn detect_format(path: &str, source: &str) -> CacheFormat {
+    let path = Path::new(path);
+    if path.extension().is_some_and(|ext| ext == "zone") {
+        return CacheFormat::Zone;
+    }
+    if path.file_name().is_some_and(|name| name == "hosts") {
+        return CacheFormat::Hosts;
+    }
+
+    let first = source
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with(';'));
+    match first {
+        Some(line) if line.starts_with('$') => CacheFormat::Zone,
+        Some(line) if line.contains('=') => CacheFormat::Local,
+        Some(line)
+            if line
+                .split_whitespace()
+                .next()
+                .is_some_and(|ip| IpAddr::from_str(ip).is_ok()) =>
+        {
+            CacheFormat::Hosts
+        }
+        Some(_) => CacheFormat::Zone,
+        None => CacheFormat::Local,
+    }
+}
+
+pub fn parse_local_cache(source: &str, format: CacheFormat) -> Result<Vec<LocalEntry>> {
+    match format {
+        CacheFormat::Local => parse_local(source),
+        CacheFormat::Hosts => parse_hosts(source),
+        CacheFormat::Zone => ZoneParser::default().parse(source),
+    }
+}
+
+fn line_error(number: usize, message: impl std::fmt::Display) -> anyhow::Error {
+    anyhow!("line {}: {}", number, message)
+}
+
+fn address_entry(host: &str, ip: IpAddr, port: u16, ttl: u32) -> LocalEntry {
+    let domain = host.trim_end_matches('.').to_ascii_lowercase();
+    let record = match ip {
+        IpAddr::V4(addr) => DnsRecord::A {
+            domain: domain.to_string(),
+            addr,
+            ttl,
+        },
+        IpAddr::V6(addr) => DnsRecord::AAAA {
+            domain: domain.to_string(),
+            addr,
+            ttl,
+        },
+    };
+    LocalEntry {
+        host: domain,
+        record,
+        port,
+    }
+}
+
+/// `name=ip[:port]` or `name=alias-target` lines, ipv6 with port is written in brackets,
+/// ex. '[::1]:3000'
+fn parse_local(source: &str) -> Result<Vec<LocalEntry>> {
+    let mut entries = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (host, addr) = line
+            .split_once('=')
+            .ok_or_else(|| line_error(i + 1, "expected 'name=ip[:port]'"))?;
+        let (host, addr) = (host.trim(), addr.trim());
+        if host.is_empty() {
+            return Err(line_error(i + 1, "name is empty"));
+        }
+        let addr = match SocketAddr::from_str(addr) {
+            Ok(addr) => addr,
+            Err(_) => match IpAddr::from_str(addr) {
+                Ok(ip) => SocketAddr::new(ip, 0),
+                Err(_) if is_alias_target(addr) => {
+                    entries.push(alias_entry(host, addr));
+                    continue;
+                }
+                Err(_) => {
+                    return Err(line_error(
+                        i + 1,
+                        format!("'{}' is not ip address or host name", addr),
+                    ))
+                }
+            },
+        };
+        entries.push(address_entry(host, addr.ip(), addr.port(), DEFAULT_TTL));
+    }
+    Ok(entries)
+}
+
+/// Host name, which can't be mistaken for invalid ip address, ex. '10.0.0.300'
+fn is_alias_target(name: &str) -> bool {
+    is_dns_name(name)
+        && name
+            .trim_end_matches('.')
+            .rsplit('.')
+            .next()
+            .is_some_and(|tld| !tld.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn alias_entry(host: &str, target: &str) -> LocalEntry {
+    let domain = host.trim_end_matches('.').to_ascii_lowercase();
+    LocalEntry {
+        host: domain.to_string(),
+        record: DnsRecord::CNAME {
+            domain,
+            host: target.trim_end_matches('.').to_ascii_lowercase(),
+            ttl: DEFAULT_TTL,
+        },
+        port: 0,
+    }
+}
+
+/// `ip name [aliases]` lines, same as /etc/hosts
+fn parse_hosts(source: &str) -> Result<Vec<LocalEntry>> {
+    let mut entries = vec![];
+    for (i, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut tokens = line.split_whitespace();
+        let Some(ip) = tokens.next() else {
+            continue;
+        };
+        let ip = IpAddr::from_str(ip)
+            .map_err(|_| line_error(i + 1, format!("'{}' is not ip address", ip)))?;
+        let names: Vec<&str> = tokens.collect();
+        if names.is_empty() {
+            return Err(line_error(i + 1, format!("address {} has no names", ip)));
+        }
+        for name in names {
+            entries.push(address_entry(name, ip, 0, DEFAULT_TTL));
+        }
+    }
+    Ok(entries)
+}
+
+/// Parser of master file records, RFC 1035 section 5
+#[derive(Default)]
+struct ZoneParser {
+    origin: Option<String>,
+    /// Ttl set by $TTL
+    default_ttl: Option<u32>,
+    /// Owner of previous record, it is used by records starting with space
+    owner: Option<String>,
+}
+
+impl ZoneParser {
+    fn parse(mut self, source: &str) -> Result<Vec<LocalEntry>> {
+        let mut entries = vec![];
+        for (number, indented, tokens) in zone_records(source)? {
+            if let Some(entry) = self
+                .parse_record(indented, tokens)
+                .map_err(|e| line_error(number, e))?
+            {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn parse_record(&mut self, indented: bool, tokens: Vec<String>) -> Result<Option<LocalEntry>> {
+        let mut tokens = tokens.into_iter().peekable();
+        let first = tokens.peek().cloned().unwrap_or_default();
+
+        if first.starts_with('$') {
+            tokens.next();
+            let value = tokens
+                .next()
+                .ok_or_else(|| anyhow!("{} requires value", first))?;
+            match first.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => self.origin = Some(self.absolute_name(&value)?),
+                "$TTL" => self.default_ttl = Some(parse_ttl(&value)?),
+                _ => return Err(anyhow!("directive {} is not supported", first)),
+            }
+            return Ok(None);
+        }
+
+        let owner = if indented {
+            self.owner
+                .clone()
+                .ok_or_else(|| anyhow!("record without owner name"))?
+        } else {
+            let owner = self.absolute_name(&tokens.next().unwrap_or_default())?;
+            self.owner = Some(owner.to_string());
+            owner
+        };
+
+        // ttl and class can be in any order before type
+        let mut ttl = None;
+        let mut record_type = None;
+        for token in tokens.by_ref() {
+            if token.eq_ignore_ascii_case("IN") {
+                continue;
+            }
+            if token.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(&token)?);
+                continue;
+            }
+            record_type = Some(token.to_ascii_uppercase());
+            break;
+        }
+        let record_type = record_type.ok_or_else(|| anyhow!("record type is missing"))?;
+        let ttl = ttl.or(self.default_ttl).unwrap_or(DEFAULT_TTL);
+        let data: Vec<String> = tokens.collect();
+        let domain = owner.to_string();
+
+        let field = |index: usize, name: &str| -> Result<&str> {
+            data.get(index)
+                .map(String::as_str)
+                .ok_or_else(|| anyhow!("{} record requires {}", record_type, name))
+        };
+        let number = |index: usize, name: &str| -> Result<u16> {
+            let value = field(index, name)?;
+            value
+                .parse()
+                .map_err(|_| anyhow!("{} '{}' is not number", name, value))
+        };
+
+        let record = match record_type.as_str() {
+            "A" => {
+                let addr = field(0, "address")?;
+                DnsRecord::A {
+                    domain,
+                    addr: Ipv4Addr::from_str(addr)
+                        .map_err(|_| anyhow!("'{}' is not ipv4 address", addr))?,
+                    ttl,
+                }
+            }
+            "AAAA" => {
+                let addr = field(0, "address")?;
+                DnsRecord::AAAA {
+                    domain,
+                    addr: Ipv6Addr::from_str(addr)
+                        .map_err(|_| anyhow!("'{}' is not ipv6 address", addr))?,
+                    ttl,
+                }
+            }
+            "CNAME" => DnsRecord::CNAME {
+                domain,
+                host: self.absolute_name(field(0, "target")?)?,
+                ttl,
+            },
+            "NS" => DnsRecord::NS {
+                domain,
+                host: self.absolute_name(field(0, "name server")?)?,
+                ttl,
+            },
+            "PTR" => DnsRecord::PTR {
+                domain,
+                host: self.absolute_name(field(0, "target")?)?,
+                ttl,
+            },
+            "MX" => DnsRecord::MX {
+                domain,
+                priority: number(0, "preference")?,
+                host: self.absolute_name(field(1, "exchange")?)?,
+                ttl,
+            },
+            "SRV" => DnsRecord::SRV {
+                domain,
+                priority: number(0, "priority")?,
+                weight: number(1, "weight")?,
+                port: number(2, "port")?,
+                host: self.absolute_name(field(3, "target")?)?,
+                ttl,
+            },
+            "TXT" => {
+                field(0, "text")?;
+                DnsRecord::TXT {
+                    domain,
+                    data: data.concat(),
+                    ttl,
+                }
+            }
+            "SOA" => {
+                debug!("Skip SOA of {}, authority is set by 'dns.zones'", owner);
+                return Ok(None);
+            }
+            _ => return Err(anyhow!("record type {} is not supported", record_type)),
+        };
+        Ok(Some(LocalEntry {
+            host: owner,
+            record,
+            port: 0,
+        }))
+    }
+
+    /// Name with trailing dot is absolute, other names are relative to $ORIGIN,
+    /// names are kept without trailing dot
+    fn absolute_name(&self, name: &str) -> Result<String> {
+        if name.is_empty() {
+            return Err(anyhow!("name is empty"));
+        }
+        let name = name.to_ascii_lowercase();
+        if name == "@" {
+            return self
+                .origin
+                .clone()
+                .ok_or_else(|| anyhow!("'@' is used without $ORIGIN"));
+        }
+        if let Some(name) = name.strip_suffix('.') {
+            return Ok(name.to_string());
+        }
+        match &self.origin {
+            Some(origin) if !origin.is_empty() => Ok(format!("{}.{}", name, origin)),
+            _ => Ok(name),
+        }
+    }
+}
+
+/// Ttl in seconds or with units, ex. '3600', '1h30m', '1d'
+fn parse_ttl(value: &str) -> Result<u32> {
+    if let Ok(ttl) = value.parse() {
+        return Ok(ttl);
+    }
+    let mut ttl: u32 = 0;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(anyhow!("'{}' is not ttl", value)),
+        };
+        let count: u32 = number
+            .parse()
+            .map_err(|_| anyhow!("'{}' is not ttl", value))?;
+        ttl = ttl.saturating_add(count.saturating_mul(unit));
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(anyhow!("'{}' is not ttl", value));
+    }
+    Ok(ttl)
+}
+
+/// Split master file into records: line number of record start, whether it starts with space
+/// and its tokens. Comments are dropped, records in parentheses span several lines
+fn zone_records(source: &str) -> Result<Vec<(usize, bool, Vec<String>)>> {
+    let mut records = vec![];
+    let mut current: Option<(usize, bool, Vec<String>)> = None;
+    let mut depth = 0;
+
+    for (i, line) in source.lines().enumerate() {
+        let number = i + 1;
+        let (tokens, delta) = tokenize(line).map_err(|e| line_error(number, e))?;
+        depth += delta;
+        if depth < 0 {
+            return Err(line_error(number, "unbalanced ')'"));
+        }
+
+        match current.as_mut() {
+            Some((_, _, record)) => record.extend(tokens),
+            None if !tokens.is_empty() => {
+                let indented = line.starts_with(|c: char| c.is_whitespace());
+                current = Some((number, indented, tokens));
+            }
+            None => {}
+        }
+        if depth == 0 {
+            records.extend(current.take());
+        }
+    }
+    if let Some((number, _, _)) = current {
+        return Err(line_error(number, "'(' is not closed"));
+    }
+    Ok(records)
+}
+
+/// Tokens of line and change of parentheses depth, quoted strings are single tokens
+fn tokenize(line: &str) -> Result<(Vec<String>, i32)> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut depth = 0;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => break,
+            '"' => {
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => token.extend(chars.next()),
+                        Some(c) => token.push(c),
+                        None => return Err(anyhow!("quote is not closed")),
+                    }
+                }
+                tokens.push(std::mem::take(&mut token));
+            }
+            '(' | ')' => {
+                depth += if c == '(' { 1 } else { -1 };
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c if c.is_whitespace() => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    Ok((tokens, depth))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(source: &str) -> Vec<DnsRecord> {
+        ZoneParser::default()
+            .parse(source)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.record)
+            .collect()
+    }
+
+    fn zone_error(source: &str) -> String {
+        ZoneParser::default().parse(source).unwrap_err().to_string()
+    }
+
+    fn a(domain: &str, addr: [u8; 4], ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: Ipv4Addr::from(addr),
+            ttl,
+        }
+    }
+
+    #[test]
+    fn relative_names_are_completed_by_origin() {
+        let records = zone(
+            "$ORIGIN Dev.Example.com.\n\
+             app A 10.0.0.1\n\
+             other.example.org. A 10.0.0.2\n\
+             web CNAME app\n\
+             ext CNAME example.net.\n",
+        );
+        assert_eq!(
+            records,
+            vec![
+                a("app.dev.example.com", [10, 0, 0, 1], DEFAULT_TTL),
+                a("other.example.org", [10, 0, 0, 2], DEFAULT_TTL),
+                DnsRecord::CNAME {
+                    domain: "web.dev.example.com".to_string(),
+                    host: "app.dev.example.com".to_string(),
+                    ttl: DEFAULT_TTL,
+                },
+                DnsRecord::CNAME {
+                    domain: "ext.dev.example.com".to_string(),
+                    host: "example.net".to_string(),
+                    ttl: DEFAULT_TTL,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_names_without_origin_are_kept() {
+        assert_eq!(
+            zone("app.test A 10.0.0.1"),
+            vec![a("app.test", [10, 0, 0, 1], DEFAULT_TTL)]
+        );
+    }
+
+    #[test]
+    fn at_sign_is_origin() {
+        let records = zone(
+            "$ORIGIN dev.example.com.\n\
+             @ A 10.0.0.1\n\
+             @ MX 10 mail\n",
+        );
+        assert_eq!(
+            records,
+            vec![
+                a("dev.example.com", [10, 0, 0, 1], DEFAULT_TTL),
+                DnsRecord::MX {
+                    domain: "dev.example.com".to_string(),
+                    priority: 10,
+                    host: "mail.dev.example.com".to_string(),
+                    ttl: DEFAULT_TTL,
+                },
+            ]
+        );
+        assert_eq!(
+            zone_error("@ A 10.0.0.1"),
+            "line 1: '@' is used without $ORIGIN"
+        );
+    }
+
+    #[test]
+    fn records_in_parentheses_span_lines() {
+        let records = zone(
+            "$ORIGIN example.com.\n\
+             _http._tcp SRV ( 10 ; priority\n\
+             \x20   20 ; weight\n\
+             \x20   8080 app )\n\
+             \x20   A 10.0.0.1\n\
+             txt TXT ( \"first part\"\n\
+             \x20   \"second; part\" )\n",
+        );
+        assert_eq!(
+            records,
+            vec![
+                DnsRecord::SRV {
+                    domain: "_http._tcp.example.com".to_string(),
+                    priority: 10,
+                    weight: 20,
+                    port: 8080,
+                    host: "app.example.com".to_string(),
+                    ttl: DEFAULT_TTL,
+                },
+                // indented record belongs to owner of previous record
+                a("_http._tcp.example.com", [10, 0, 0, 1], DEFAULT_TTL),
+                DnsRecord::TXT {
+                    domain: "txt.example.com".to_string(),
+                    data: "first partsecond; part".to_string(),
+                    ttl: DEFAULT_TTL,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn soa_is_skipped() {
+        let records = zone(
+            "$ORIGIN example.com.\n\
+             @ IN SOA ns admin (\n\
+             \x20   1 3600 600 86400 300 )\n\
+             app A 10.0.0.1\n",
+        );
+        assert_eq!(
+            records,
+            vec![a("app.example.com", [10, 0, 0, 1], DEFAULT_TTL)]
+        );
+    }
+
+    #[test]
+    fn ttl_units() {
+        assert_eq!(parse_ttl("3600").unwrap(), 3600);
+        assert_eq!(parse_ttl("30s").unwrap(), 30);
+        assert_eq!(parse_ttl("1h30m").unwrap(), 5400);
+        assert_eq!(parse_ttl("1D").unwrap(), 86400);
+        assert_eq!(parse_ttl("2w1d").unwrap(), 1296000);
+        for value in ["h", "1x", "1h30", "-1"] {
+            assert!(parse_ttl(value).is_err(), "{:?} is not ttl", value);
+        }
+    }
+
+    #[test]
+    fn ttl_of_record_overrides_default() {
+        let records = zone(
+            "$TTL 1h\n\
+             a.test A 10.0.0.1\n\
+             b.test 60 IN A 10.0.0.2\n\
+             c.test IN 5m A 10.0.0.3\n",
+        );
+        assert_eq!(
+            records,
+            vec![
+                a("a.test", [10, 0, 0, 1], 3600),
+                a("b.test", [10, 0, 0, 2], 60),
+                a("c.test", [10, 0, 0, 3], 300),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_quotes_and_parentheses() {
+        let (tokens, depth) = tokenize(r#"txt TXT ("a \"b\" c"  d) ; comment"#).unwrap();
+        assert_eq!(tokens, vec!["txt", "TXT", "a \"b\" c", "d"]);
+        assert_eq!(depth, 0);
+
+        let (tokens, depth) = tokenize("@ SOA ns admin (").unwrap();
+        assert_eq!(tokens, vec!["@", "SOA", "ns", "admin"]);
+        assert_eq!(depth, 1);
+
+        assert!(tokenize("txt TXT \"open").is_err());
+    }
+
+    #[test]
+    fn zone_records_start_at_first_line() {
+        let records = zone_records("; header\n\nmx MX (\n 10\n mail )\n  A 10.0.0.1\n").unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (
+                    3,
+                    false,
+                    vec!["mx", "MX", "10", "mail"]
+                        .into_iter()
+                        .map(String::from)
+                        .collect()
+                ),
+                (6, true, vec!["A".to_string(), "10.0.0.1".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_report_line_of_record() {
+        // record spanning lines is reported at its first line
+        assert_eq!(
+            zone_error("a.test A 10.0.0.1\nsrv.test SRV ( 10\n 20 x target. )\n"),
+            "line 2: port 'x' is not number"
+        );
+        assert_eq!(
+            zone_error("a.test A 10.0.0.1\n\n; comment\nb.test A 10.0.0.300\n"),
+            "line 4: '10.0.0.300' is not ipv4 address"
+        );
+        assert_eq!(
+            zone_error("a.test A 10.0.0.1\nb.test TXT ( \"text\"\n"),
+            "line 2: '(' is not closed"
+        );
+        assert_eq!(zone_error("a.test A 10.0.0.1 )"), "line 1: unbalanced ')'");
+        assert_eq!(
+            zone_error("\n$INCLUDE other.zone"),
+            "line 2: directive $INCLUDE is not supported"
+        );
+        assert_eq!(
+            zone_error(" A 10.0.0.1"),
+            "line 1: record without owner name"
+        );
+
+        let error = parse_local_cache("# hosts\n10.0.0.1 a.test\n10.0.0.2\n", CacheFormat::Hosts);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "line 3: address 10.0.0.2 has no names"
+        );
+        let error = parse_local_cache("a.test=10.0.0.1\nb.test\n", CacheFormat::Local);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "line 2: expected 'name=ip[:port]'"
+        );
+    }
+
+    #[test]
+    fn format_is_detected_by_name_or_content() {
+        assert_eq!(detect_format("dev.zone", "a=10.0.0.1"), CacheFormat::Zone);
+        assert_eq!(detect_format("/etc/hosts", ""), CacheFormat::Hosts);
+        assert_eq!(
+            detect_format("cache", "; zone\n$ORIGIN example.com.\n"),
+            CacheFormat::Zone
+        );
+        assert_eq!(
+            detect_format("cache", "# local\napp.test=10.0.0.1:3000\n"),
+            CacheFormat::Local
+        );
+        assert_eq!(
+            detect_format("cache", "\n::1 localhost\n"),
+            CacheFormat::Hosts
+        );
+        assert_eq!(
+            detect_format("cache", "app.test. IN A 10.0.0.1\n"),
+            CacheFormat::Zone
+        );
+        assert_eq!(detect_format("cache", "# empty\n"), CacheFormat::Local);
+    }
+}
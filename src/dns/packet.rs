@@ -1,4 +1,4 @@
-use crate::dns::buffer::BytePacketBuffer;
+use crate::dns::buffer::{BytePacketBuffer, MESSAGE_SIZE};
 use crate::dns::header::{DnsHeader, QueryType};
 use crate::dns::question::DnsQuestion;
 use crate::dns::record::DnsRecord;
@@ -53,20 +53,16 @@ impl DnsPacket {
     }
 
     pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
-        self.header.questions = self.questions.len() as u16;
-        self.header.answers = self.answers.len() as u16;
-        self.header.authoritative_entries = self.authorities.len() as u16;
-        self.header.resource_entries = self.resources.len() as u16;
-
-        let mut size = 0usize;
+        // records are measured in scratch buffer of tcp message size first, so record which
+        // doesn't fit is dropped whole instead of being cut at the end of buffer
+        let mut scratch = BytePacketBuffer::with_size(MESSAGE_SIZE);
+        let mut size = self.header.write(&mut scratch);
 
-        size += self.header.write(buffer);
-
-        for ref question in &self.questions {
-            size += question.write(buffer)?;
+        for question in &self.questions {
+            size += question.write(&mut scratch)?;
         }
 
-        let mut record_count = self.answers.len() + self.authorities.len() + self.resources.len();
+        let mut record_count = 0;
 
         // reset header
         self.header.questions = 0;
@@ -81,14 +77,19 @@ impl DnsPacket {
             .chain(self.resources.iter())
             .enumerate()
         {
-            size += rec.write(buffer)?;
-            if size >= buffer.max_size {
-                record_count = i;
-                // self.header.truncated_message = true;
-
-                // error!("Caught buffer overflow for: {:?}", rec);
+            scratch.seek(0);
+            let rec_size = rec.write(&mut scratch)?;
+            if size + rec_size > buffer.max_size {
+                // dropped additional records are not required for answer, RFC 2181
+                if i < self.answers.len() + self.authorities.len() {
+                    self.header.truncated_message = true;
+                }
                 break;
-            } else if i < self.answers.len() {
+            }
+
+            size += rec_size;
+            record_count += 1;
+            if i < self.answers.len() {
                 self.header.answers += 1;
             } else if i < self.answers.len() + self.authorities.len() {
                 self.header.authoritative_entries += 1;
@@ -97,9 +98,6 @@ impl DnsPacket {
             }
         }
 
-        // reset all data
-        // buffer.buf.iter_mut().for_each(|x| *x = 0);
-        // for i in &mut buffer.buf[..] { *i = 0 }
         buffer.buf.fill(0);
         buffer.pos = 0;
 
@@ -124,3 +122,90 @@ impl DnsPacket {
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::buffer::PACKET_SIZE;
+
+    fn response(answers: Vec<DnsRecord>) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::TXT));
+        packet.answers = answers;
+        packet
+    }
+
+    fn txt(len: usize) -> DnsRecord {
+        DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            data: "x".repeat(len),
+            ttl: 60,
+        }
+    }
+
+    fn cname(host: &str) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: "example.com".to_string(),
+            host: host.to_string(),
+            ttl: 60,
+        }
+    }
+
+    /// Write packet into udp sized buffer and read it back
+    fn round_trip(packet: &mut DnsPacket) -> DnsPacket {
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        assert!(buffer.pos() <= PACKET_SIZE);
+        buffer.seek(0);
+        DnsPacket::from_buffer(&mut buffer).unwrap()
+    }
+
+    #[test]
+    fn large_txt_answer_is_truncated_by_whole_records() {
+        let mut packet = response(vec![txt(1000), txt(1000), txt(1000)]);
+        let written = round_trip(&mut packet);
+
+        assert!(written.header.truncated_message);
+        assert_eq!(written.answers, vec![txt(1000)]);
+    }
+
+    #[test]
+    fn record_crossing_end_of_buffer_is_dropped() {
+        // filler moves next record over every position near the end of buffer
+        for filler in 1200..1370 {
+            for last in [txt(300), cname(&format!("{}.example.com", "a".repeat(60)))] {
+                let answers = vec![txt(filler), last];
+                let mut packet = response(answers.clone());
+                let written = round_trip(&mut packet);
+
+                let fits = written.answers.len() == answers.len();
+                assert_eq!(written.header.truncated_message, !fits, "filler {}", filler);
+                assert_eq!(written.answers, answers[..written.answers.len()]);
+                assert!(!written.answers.is_empty(), "filler {}", filler);
+            }
+        }
+    }
+
+    #[test]
+    fn answer_which_fits_is_not_truncated() {
+        let mut packet = response(vec![txt(500), cname("alias.example.com")]);
+        let written = round_trip(&mut packet);
+
+        assert!(!written.header.truncated_message);
+        assert_eq!(written.answers, vec![txt(500), cname("alias.example.com")]);
+    }
+
+    #[test]
+    fn dropped_additional_records_do_not_truncate_answer() {
+        let mut packet = response(vec![txt(1000)]);
+        packet.resources = vec![txt(1000)];
+        let written = round_trip(&mut packet);
+
+        assert!(!written.header.truncated_message);
+        assert_eq!(written.answers, vec![txt(1000)]);
+        assert!(written.resources.is_empty());
+    }
+}
@@ -1,9 +1,10 @@
 use crate::dns::buffer::BytePacketBuffer;
 use crate::dns::header::QueryType;
 use anyhow::Result;
+use serde::Serialize;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize)]
 #[allow(dead_code)]
 pub enum DnsRecord {
     UNKNOWN {
@@ -56,6 +57,7 @@ pub enum DnsRecord {
         ttl: u32,
     },
     // 15
+    /// Text joined from character strings of record
     TXT {
         domain: String,
         data: String,
@@ -84,7 +86,31 @@ pub enum DnsRecord {
     }, // 41
 }
 
+/// Query type asking for all records of host
+const ANY: u16 = 255;
+
 impl DnsRecord {
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(*qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+        }
+    }
+
+    /// Return if record answers query of type, ANY query is answered by all records
+    pub fn answers(&self, qtype: QueryType) -> bool {
+        qtype == QueryType::UNKNOWN(ANY) || self.qtype() == qtype
+    }
+
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
         let mut domain = String::new();
         buffer.read_qname(&mut domain)?;
@@ -126,7 +152,7 @@ impl DnsRecord {
                     ttl,
                 })
             }
-            QueryType::PTR | QueryType::CNAME => {
+            QueryType::CNAME => {
                 let mut cname = String::new();
                 buffer.read_qname(&mut cname)?;
 
@@ -136,6 +162,16 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::PTR => {
+                let mut ptr = String::new();
+                buffer.read_qname(&mut ptr)?;
+
+                Ok(DnsRecord::PTR {
+                    domain,
+                    host: ptr,
+                    ttl,
+                })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16();
                 let mut mx = String::new();
@@ -193,14 +229,16 @@ impl DnsRecord {
                 })
             }
             QueryType::TXT => {
-                let mut txt = String::new();
-
-                let cur_pos = buffer.pos();
-                txt.push_str(&String::from_utf8_lossy(
-                    buffer.get_range(cur_pos, data_len as usize),
-                ));
-
-                buffer.step(data_len as usize);
+                // character strings are prefixed by their length, text is joined from them
+                let mut bytes = Vec::with_capacity(data_len as usize);
+                let end = buffer.pos() + data_len as usize;
+                while buffer.pos() < end {
+                    let len = buffer.read() as usize;
+                    let cur_pos = buffer.pos();
+                    bytes.extend_from_slice(buffer.get_range(cur_pos, len.min(end - cur_pos)));
+                    buffer.step(len.min(end - cur_pos));
+                }
+                let txt = String::from_utf8_lossy(&bytes).to_string();
 
                 Ok(DnsRecord::TXT {
                     domain,
@@ -296,7 +334,7 @@ impl DnsRecord {
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
-                buffer.write_u16(QueryType::CNAME.to_num());
+                buffer.write_u16(self.qtype().to_num());
                 buffer.write_u16(1);
                 buffer.write_u32(ttl);
 
@@ -382,11 +420,23 @@ impl DnsRecord {
                 buffer.write_u16(QueryType::TXT.to_num());
                 buffer.write_u16(1);
                 buffer.write_u32(ttl);
-                buffer.write_u16(data.len() as u16);
 
-                for b in data.as_bytes() {
-                    buffer.write_u8(*b);
+                let pos = buffer.pos();
+                buffer.write_u16(0);
+
+                // character string is up to 255 bytes, empty text is one empty string
+                if data.is_empty() {
+                    buffer.write_u8(0);
+                }
+                for chunk in data.as_bytes().chunks(255) {
+                    buffer.write_u8(chunk.len() as u8);
+                    for b in chunk {
+                        buffer.write_u8(*b);
+                    }
                 }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16);
             }
             DnsRecord::SRV {
                 ref domain,
@@ -418,3 +468,45 @@ impl DnsRecord {
         return Ok(buffer.pos() - start_pos);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ptr_round_trip() {
+        let record = DnsRecord::PTR {
+            domain: "1.0.0.10.in-addr.arpa".to_string(),
+            host: "app.dev.example.com".to_string(),
+            ttl: 300,
+        };
+        let mut buffer = BytePacketBuffer::new();
+        let size = record.write(&mut buffer).unwrap();
+        assert_eq!(size, buffer.pos());
+
+        // name(23) type(2) class(2) ttl(4) length(2) host(21)
+        assert_eq!(size, 23 + 2 + 2 + 4 + 2 + 21);
+        assert_eq!(buffer.get_range(23, 2), &[0, 12]);
+        assert_eq!(buffer.get_range(31, 2), &[0, 21]);
+
+        buffer.seek(0);
+        assert_eq!(DnsRecord::read(&mut buffer).unwrap(), record);
+        assert_eq!(buffer.pos(), size);
+        assert_eq!(record.qtype(), QueryType::PTR);
+    }
+
+    #[test]
+    fn ptr_of_ipv6_round_trip() {
+        let record = DnsRecord::PTR {
+            domain: format!("{}ip6.arpa", "0.".repeat(31) + "1."),
+            host: "localhost".to_string(),
+            ttl: 60,
+        };
+        let mut buffer = BytePacketBuffer::new();
+        let size = record.write(&mut buffer).unwrap();
+
+        buffer.seek(0);
+        assert_eq!(DnsRecord::read(&mut buffer).unwrap(), record);
+        assert_eq!(buffer.pos(), size);
+    }
+}
@@ -0,0 +1,479 @@
+use crate::ca::generate::served_domains;
+use crate::config::properties::ResolvedProps;
+use crate::dns::server::cache::Cache;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// How often routing domains are synced with served hosts
+const DOMAINS_REFRESH: Duration = Duration::from_secs(30);
+
+/// Dns server of kidns registered on one link of systemd-resolved,
+/// only names under routing domains are sent to it, other names are resolved as before
+#[derive(Clone)]
+pub struct Resolved {
+    link: String,
+    ifindex: i32,
+    addrs: Vec<SocketAddr>,
+    domains: Vec<String>,
+    /// Local zones, they are routed to kidns with served hosts
+    zones: Vec<String>,
+    cache: Cache,
+}
+
+impl Resolved {
+    pub fn new(
+        props: &ResolvedProps,
+        hosts: &[String],
+        port: u16,
+        zones: Vec<String>,
+        cache: Cache,
+    ) -> Result<Resolved> {
+        let mut addrs = vec![];
+        for host in hosts {
+            let ip = host
+                .parse::<IpAddr>()
+                .map_err(|_| anyhow!("'{}' is not ip address", host))?;
+            // server listening on all interfaces is reachable through loopback
+            let ip = match ip {
+                IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                ip => ip,
+            };
+            addrs.push(SocketAddr::new(ip, port));
+        }
+
+        Ok(Resolved {
+            link: props.link.to_string(),
+            ifindex: link_index(&props.link)?,
+            addrs,
+            domains: props.domains.clone(),
+            zones,
+            cache,
+        })
+    }
+
+    pub fn link(&self) -> &str {
+        &self.link
+    }
+
+    /// Register dns server on link and keep its routing domains in sync with served hosts
+    pub async fn serve(self) -> Result<()> {
+        let manager = bus::manager().await?;
+        self.register(&manager).await?;
+
+        let mut current = vec![];
+        loop {
+            current = self.sync_domains(&manager, current).await?;
+            tokio::time::sleep(DOMAINS_REFRESH).await;
+        }
+    }
+
+    /// Drop dns server and routing domains of link, they are restored to network manager values
+    pub async fn revert(&self) -> Result<()> {
+        let manager = bus::manager().await?;
+        self.revert_link(&manager).await
+    }
+
+    async fn register(&self, manager: &bus::Manager) -> Result<()> {
+        bus::set_dns(manager, self.ifindex, &self.addrs).await?;
+        // names without routing domain must not be sent to kidns
+        bus::set_default_route(manager, self.ifindex, false).await?;
+        info!(
+            "Registered dns server {:?} on link {} of systemd-resolved",
+            self.addrs, self.link
+        );
+        Ok(())
+    }
+
+    /// Set routing domains if they differ from current ones, return domains of link
+    async fn sync_domains(
+        &self,
+        manager: &bus::Manager,
+        current: Vec<String>,
+    ) -> Result<Vec<String>> {
+        let domains = self.routing_domains().await;
+        if domains == current {
+            return Ok(current);
+        }
+        bus::set_domains(manager, self.ifindex, &domains).await?;
+        info!("Routing domains of link {}: {:?}", self.link, domains);
+        Ok(domains)
+    }
+
+    async fn revert_link(&self, manager: &bus::Manager) -> Result<()> {
+        bus::revert(manager, self.ifindex).await?;
+        info!("Reverted link {} of systemd-resolved", self.link);
+        Ok(())
+    }
+
+    /// Configured domains or local zones with hosts served by dns
+    async fn routing_domains(&self) -> Vec<String> {
+        if !self.domains.is_empty() {
+            return self.domains.clone();
+        }
+        let hosts = self
+            .cache
+            .entries()
+            .await
+            .into_iter()
+            .filter(|(_, source, _)| *source != "reverse")
+            .map(|(host, _, _)| host)
+            .collect();
+        let mut domains = served_domains(hosts).unwrap_or_else(|e| {
+            warn!(
+                "Unable to find routing domains of served hosts, err: {:?}",
+                e
+            );
+            vec![]
+        });
+        domains.extend(self.zones.iter().cloned());
+        domains.sort();
+        domains.dedup();
+        domains
+    }
+}
+
+/// Interface index of link name, ex. 'eth0'
+fn link_index(link: &str) -> Result<i32> {
+    let path = format!("/sys/class/net/{}/ifindex", link);
+    let index = std::fs::read_to_string(&path)
+        .map_err(|e| anyhow!("Unable to find network link {}, err: {}", link, e))?;
+    index.trim().parse().map_err(|e| {
+        anyhow!(
+            "Invalid index '{}' of link {}, err: {}",
+            index.trim(),
+            link,
+            e
+        )
+    })
+}
+
+#[cfg(target_os = "linux")]
+mod bus {
+    use anyhow::{anyhow, Result};
+    use std::net::{IpAddr, SocketAddr};
+    use zbus::{proxy, Connection};
+
+    const AF_INET: i32 = 2;
+    const AF_INET6: i32 = 10;
+
+    /// Part of org.freedesktop.resolve1.Manager, see org.freedesktop.resolve1(5)
+    #[proxy(
+        interface = "org.freedesktop.resolve1.Manager",
+        default_service = "org.freedesktop.resolve1",
+        default_path = "/org/freedesktop/resolve1"
+    )]
+    pub(super) trait Manager {
+        #[zbus(name = "SetLinkDNS")]
+        fn set_link_dns(&self, ifindex: i32, addresses: &[(i32, Vec<u8>)]) -> zbus::Result<()>;
+
+        /// Same as SetLinkDNS with port and server name, available since systemd 246
+        #[zbus(name = "SetLinkDNSEx")]
+        fn set_link_dns_ex(
+            &self,
+            ifindex: i32,
+            addresses: &[(i32, Vec<u8>, u16, &str)],
+        ) -> zbus::Result<()>;
+
+        fn set_link_domains(&self, ifindex: i32, domains: &[(&str, bool)]) -> zbus::Result<()>;
+
+        fn set_link_default_route(&self, ifindex: i32, enable: bool) -> zbus::Result<()>;
+
+        fn revert_link(&self, ifindex: i32) -> zbus::Result<()>;
+    }
+
+    pub(super) type Manager = ManagerProxy<'static>;
+
+    /// Connect to system bus, DBUS_SYSTEM_BUS_ADDRESS overrides its address
+    pub(super) async fn manager() -> Result<Manager> {
+        let connection = Connection::system()
+            .await
+            .map_err(|e| anyhow!("Unable to connect to system D-Bus, err: {}", e))?;
+        Ok(ManagerProxy::new(&connection).await?)
+    }
+
+    pub(super) async fn set_dns(
+        manager: &ManagerProxy<'_>,
+        ifindex: i32,
+        addrs: &[SocketAddr],
+    ) -> Result<()> {
+        let servers: Vec<(i32, Vec<u8>, u16, &str)> = addrs
+            .iter()
+            .map(|addr| match addr.ip() {
+                IpAddr::V4(ip) => (AF_INET, ip.octets().to_vec(), addr.port(), ""),
+                IpAddr::V6(ip) => (AF_INET6, ip.octets().to_vec(), addr.port(), ""),
+            })
+            .collect();
+        let result = if addrs.iter().all(|addr| addr.port() == 53) {
+            let servers: Vec<(i32, Vec<u8>)> = servers
+                .into_iter()
+                .map(|(family, ip, _, _)| (family, ip))
+                .collect();
+            manager.set_link_dns(ifindex, &servers).await
+        } else {
+            manager.set_link_dns_ex(ifindex, &servers).await
+        };
+        result.map_err(|e| {
+            anyhow!(
+                "Unable to set dns servers {:?} in systemd-resolved, err: {}",
+                addrs,
+                e
+            )
+        })
+    }
+
+    pub(super) async fn set_default_route(
+        manager: &ManagerProxy<'_>,
+        ifindex: i32,
+        enable: bool,
+    ) -> Result<()> {
+        manager
+            .set_link_default_route(ifindex, enable)
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "Unable to set default route in systemd-resolved, err: {}",
+                    e
+                )
+            })
+    }
+
+    /// Domains are set as routing only('~domain'), so they are not used as search domains
+    pub(super) async fn set_domains(
+        manager: &ManagerProxy<'_>,
+        ifindex: i32,
+        domains: &[String],
+    ) -> Result<()> {
+        let domains: Vec<(&str, bool)> = domains
+            .iter()
+            .map(|domain| (domain.trim_start_matches('~'), true))
+            .collect();
+        manager
+            .set_link_domains(ifindex, &domains)
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "Unable to set routing domains in systemd-resolved, err: {}",
+                    e
+                )
+            })
+    }
+
+    pub(super) async fn revert(manager: &ManagerProxy<'_>, ifindex: i32) -> Result<()> {
+        manager
+            .revert_link(ifindex)
+            .await
+            .map_err(|e| anyhow!("Unable to revert link in systemd-resolved, err: {}", e))
+    }
+}
+
+/// systemd-resolved exists only on linux, config validation reject it on other platforms
+#[cfg(not(target_os = "linux"))]
+mod bus {
+    use anyhow::{anyhow, Result};
+    use std::net::SocketAddr;
+
+    pub(super) struct Manager;
+
+    pub(super) async fn manager() -> Result<Manager> {
+        Err(anyhow!("systemd-resolved is supported only on linux"))
+    }
+
+    pub(super) async fn set_dns(_: &Manager, _: i32, _: &[SocketAddr]) -> Result<()> {
+        Ok(())
+    }
+
+    pub(super) async fn set_default_route(_: &Manager, _: i32, _: bool) -> Result<()> {
+        Ok(())
+    }
+
+    pub(super) async fn set_domains(_: &Manager, _: i32, _: &[String]) -> Result<()> {
+        Ok(())
+    }
+
+    pub(super) async fn revert(_: &Manager, _: i32) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::admin::overrides::HostOverrides;
+    use crate::config::properties::parse_properties;
+    use crate::k8s::cluster::K8sClusters;
+    use assert_fs::fixture::FileWriteStr;
+    use assert_fs::NamedTempFile;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::UnixStream;
+    use zbus::connection::Builder;
+    use zbus::{Connection, Guid};
+
+    /// Calls received by mock systemd-resolved, in order
+    #[derive(Clone, Default)]
+    struct MockManager {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockManager {
+        fn take_calls(&self) -> Vec<String> {
+            std::mem::take(&mut self.calls.lock().unwrap())
+        }
+
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    #[zbus::interface(name = "org.freedesktop.resolve1.Manager")]
+    impl MockManager {
+        #[zbus(name = "SetLinkDNS")]
+        fn set_link_dns(&self, ifindex: i32, addresses: Vec<(i32, Vec<u8>)>) {
+            self.record(format!("SetLinkDNS {} {:?}", ifindex, addresses));
+        }
+
+        #[zbus(name = "SetLinkDNSEx")]
+        fn set_link_dns_ex(&self, ifindex: i32, addresses: Vec<(i32, Vec<u8>, u16, String)>) {
+            self.record(format!("SetLinkDNSEx {} {:?}", ifindex, addresses));
+        }
+
+        fn set_link_domains(&self, ifindex: i32, domains: Vec<(String, bool)>) {
+            self.record(format!("SetLinkDomains {} {:?}", ifindex, domains));
+        }
+
+        fn set_link_default_route(&self, ifindex: i32, enable: bool) {
+            self.record(format!("SetLinkDefaultRoute {} {}", ifindex, enable));
+        }
+
+        fn revert_link(&self, ifindex: i32) {
+            self.record(format!("RevertLink {}", ifindex));
+        }
+    }
+
+    /// Proxy of mock manager over private peer to peer connection, server connection must be kept
+    async fn mock_bus() -> (MockManager, bus::Manager, Connection) {
+        let mock = MockManager::default();
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = Builder::unix_stream(server)
+            .server(Guid::generate())
+            .unwrap()
+            .p2p()
+            .serve_at("/org/freedesktop/resolve1", mock.clone())
+            .unwrap()
+            .build();
+        let client = Builder::unix_stream(client).p2p().build();
+        let (server, client) = tokio::try_join!(server, client).unwrap();
+
+        let manager = bus::ManagerProxy::new(&client).await.unwrap();
+        (mock, manager, server)
+    }
+
+    /// Resolved on loopback link, local cache serves 'app.dev.example.com'
+    async fn loopback_resolved(port: u16, domains: Vec<String>) -> Resolved {
+        let cache_file = NamedTempFile::new("local_cache.conf").unwrap();
+        cache_file
+            .write_str("app.dev.example.com=127.0.0.1\n")
+            .unwrap();
+
+        let mut props = parse_properties("", false).unwrap();
+        props.dns.cache = vec![cache_file.path().to_str().unwrap().to_string()];
+        let clusters = K8sClusters::connect(&props, None, Duration::ZERO).await;
+        let cache = Cache::new(&props, &clusters, &HostOverrides::default())
+            .await
+            .unwrap();
+
+        let resolved_props = ResolvedProps {
+            link: "lo".to_string(),
+            domains,
+        };
+        let hosts = ["0.0.0.0".to_string(), "::1".to_string()];
+        Resolved::new(
+            &resolved_props,
+            &hosts,
+            port,
+            vec!["home.test".to_string()],
+            cache,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn register_sets_dns_and_disables_default_route() {
+        let (mock, manager, _server) = mock_bus().await;
+
+        let resolved = loopback_resolved(53, vec![]).await;
+        resolved.register(&manager).await.unwrap();
+        assert_eq!(
+            mock.take_calls(),
+            vec![
+                format!(
+                    "SetLinkDNS {} [(2, [127, 0, 0, 1]), (10, {:?})]",
+                    resolved.ifindex,
+                    Ipv6Addr::LOCALHOST.octets()
+                ),
+                format!("SetLinkDefaultRoute {} false", resolved.ifindex),
+            ]
+        );
+
+        // dns server on other port needs extended call with port
+        let resolved = loopback_resolved(5353, vec![]).await;
+        resolved.register(&manager).await.unwrap();
+        assert_eq!(
+            mock.take_calls()[0],
+            format!(
+                "SetLinkDNSEx {} [(2, [127, 0, 0, 1], 5353, \"\"), (10, {:?}, 5353, \"\")]",
+                resolved.ifindex,
+                Ipv6Addr::LOCALHOST.octets()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn routing_domains_of_served_hosts_are_set_once() {
+        let (mock, manager, _server) = mock_bus().await;
+        let resolved = loopback_resolved(53, vec![]).await;
+
+        let current = resolved.sync_domains(&manager, vec![]).await.unwrap();
+        assert_eq!(current, vec!["app.dev.example.com", "home.test"]);
+        assert_eq!(
+            mock.take_calls(),
+            vec![format!(
+                "SetLinkDomains {} [(\"app.dev.example.com\", true), (\"home.test\", true)]",
+                resolved.ifindex
+            )]
+        );
+
+        // unchanged domains are not set again
+        resolved.sync_domains(&manager, current).await.unwrap();
+        assert!(mock.take_calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn configured_domains_are_routing_only() {
+        let (mock, manager, _server) = mock_bus().await;
+        let resolved = loopback_resolved(53, vec!["~corp.test".to_string()]).await;
+
+        resolved.sync_domains(&manager, vec![]).await.unwrap();
+        assert_eq!(
+            mock.take_calls(),
+            vec![format!(
+                "SetLinkDomains {} [(\"corp.test\", true)]",
+                resolved.ifindex
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn revert_reverts_link() {
+        let (mock, manager, _server) = mock_bus().await;
+        let resolved = loopback_resolved(53, vec![]).await;
+
+        resolved.revert_link(&manager).await.unwrap();
+        assert_eq!(
+            mock.take_calls(),
+            vec![format!("RevertLink {}", resolved.ifindex)]
+        );
+    }
+}
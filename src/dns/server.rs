@@ -1,3 +1,7 @@
 pub mod handler;
 pub mod dns;
-pub mod cache;
\ No newline at end of file
+pub mod cache;
+pub mod policy;
+pub mod reverse;
+pub mod upstream;
+pub mod zone;
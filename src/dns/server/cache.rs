@@ -1,127 +1,339 @@
+use crate::admin::overrides::HostOverrides;
 use crate::config::properties::Properties;
+use crate::dns::local_cache::load_local_entries;
 use crate::dns::record::DnsRecord;
-use crate::k8s::client::K8sClient;
+use crate::dns::server::reverse::reverse_name;
+use crate::k8s::cluster::K8sClusters;
 use anyhow::Result;
-use log::info;
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::Add;
 use std::sync::Arc;
 use std::vec;
 use time::{Duration, OffsetDateTime};
 use tokio::sync::RwLock;
 
+const OVERRIDE_TTL: u32 = 5;
+
 #[derive(Debug, Clone)]
 pub struct CacheRecord {
     pub records: Vec<DnsRecord>,
     pub expires: OffsetDateTime,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Cache {
     pub domains: Arc<RwLock<HashMap<String, CacheRecord>>>,
+    /// PTR records of local cache addresses, ex. '1.0.0.10.in-addr.arpa'
+    reverse: Arc<HashMap<String, CacheRecord>>,
+    /// Ingress hosts of ready clusters, if 'k8s' cache is enabled
+    k8s: Option<K8sClusters>,
+    /// Addresses of proxy listeners returned for ingress hosts
+    ingress: Vec<IpAddr>,
+    overrides: HostOverrides,
 }
 
 impl Cache {
-    pub async fn new(props: &Properties) -> Result<Cache> {
-        let k8s_clients = match &props.k8s {
-            None => {
-                vec![]
-            }
-            Some(k8s_props) => {
-                let mut clients = Vec::default();
-                for props in k8s_props {
-                    let client = K8sClient::new(props).await?;
-                    clients.push(client);
-                }
-                clients
-            }
-        };
-
+    pub async fn new(
+        props: &Properties,
+        clusters: &K8sClusters,
+        overrides: &HostOverrides,
+    ) -> Result<Cache> {
         let mut cache: HashMap<String, CacheRecord> = HashMap::new();
+        let mut k8s = None;
 
         for cache_type in &props.dns.cache {
             if cache_type.eq_ignore_ascii_case("k8s") {
-                let k8s_cache = load_k8s_ingress_cache(&k8s_clients).await?;
-                cache = cache.into_iter().chain(k8s_cache).collect();
+                k8s = Some(clusters.clone());
             } else {
-                let file_cache = load_local_dns_cache(&cache_type).await?;
+                let file_cache = load_local_dns_cache(cache_type).await?;
                 cache = cache.into_iter().chain(file_cache).collect();
             }
         }
 
         return Ok(Cache {
+            reverse: Arc::new(reverse_records(&cache)),
             domains: Arc::new(RwLock::new(cache)),
+            k8s,
+            ingress: ingress_addrs(props),
+            overrides: overrides.clone(),
         });
     }
 
-    pub async fn find(&self, domain: &str) -> Option<CacheRecord> {
+    /// Runtime overrides take precedence over local cache entries and they over ingress hosts.
+    /// Ingress hosts are answered with `local` address, if it is set
+    pub async fn find(&self, domain: &str, local: Option<IpAddr>) -> Option<CacheRecord> {
+        if let Some(addr) = self.overrides.get(domain) {
+            return Some(override_record(domain, addr));
+        }
+        if let Some(record) = self.reverse.get(&domain.to_ascii_lowercase()) {
+            return Some(record.to_owned());
+        }
+
         let record = match self.domains.read().await.get(domain) {
-            None => return None,
+            None => return self.find_ingress(domain, local),
             Some(record) => record.to_owned(),
         };
 
         if record.expires < OffsetDateTime::now_utc() {
             self.domains.write().await.remove(domain);
-            return None;
+            return self.find_ingress(domain, local);
         }
         return Some(record);
     }
+
+    /// Local cache hosts and ingress hosts of ready clusters
+    pub async fn hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self.domains.read().await.keys().cloned().collect();
+        if let Some(k8s) = &self.k8s {
+            hosts.extend(k8s.hosts());
+        }
+        hosts
+    }
+
+    /// Return if some host is below name, so name exists without own records,
+    /// ex. 'b.example.com' of 'a.b.example.com'
+    pub async fn has_below(&self, name: &str) -> bool {
+        let suffix = format!(".{}", name.trim_end_matches('.').to_ascii_lowercase());
+        let overrides = self.overrides.list().into_iter().map(|(host, _)| host);
+        self.hosts()
+            .await
+            .into_iter()
+            .chain(overrides)
+            .any(|host| host.to_ascii_lowercase().ends_with(&suffix))
+    }
+
+    /// All entries with their source: 'override', 'local', 'reverse' or 'k8s', in precedence order
+    pub async fn entries(&self) -> Vec<(String, &'static str, CacheRecord)> {
+        let mut entries: Vec<(String, &'static str, CacheRecord)> = self
+            .overrides
+            .list()
+            .into_iter()
+            .map(|(host, addr)| (host.to_string(), "override", override_record(&host, addr)))
+            .collect();
+
+        let mut local: Vec<(String, &'static str, CacheRecord)> = self
+            .domains
+            .read()
+            .await
+            .iter()
+            .map(|(host, record)| (host.to_string(), "local", record.clone()))
+            .collect();
+        local.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.extend(local);
+
+        let mut reverse: Vec<(String, &'static str, CacheRecord)> = self
+            .reverse
+            .iter()
+            .map(|(name, record)| (name.to_string(), "reverse", record.clone()))
+            .collect();
+        reverse.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.extend(reverse);
+
+        if let Some(k8s) = &self.k8s {
+            let mut hosts = k8s.hosts();
+            hosts.sort();
+            entries.extend(hosts.into_iter().filter_map(|host| {
+                let ttl = k8s.ttl(&host)?;
+                let record = ingress_record(&host, &self.ingress, ttl);
+                Some((host.to_string(), "k8s", record))
+            }));
+        }
+        entries
+    }
+
+    fn find_ingress(&self, domain: &str, local: Option<IpAddr>) -> Option<CacheRecord> {
+        let ttl = self.k8s.as_ref()?.ttl(domain)?;
+        match local {
+            Some(local) => Some(ingress_record(domain, &[local], ttl)),
+            None => Some(ingress_record(domain, &self.ingress, ttl)),
+        }
+    }
 }
 
-async fn load_k8s_ingress_cache(
-    k8s_clients: &Vec<K8sClient>,
-) -> Result<HashMap<String, CacheRecord>> {
-    let mut urls = Vec::default();
-    for client in k8s_clients {
-        urls.extend(client.ingress_urls().await?);
-    }
-
-    return Ok(urls
-        .iter()
-        .map(|host| {
-            return (
-                host.to_string(),
-                CacheRecord {
-                    expires: OffsetDateTime::now_utc().add(Duration::days(365)),
-                    records: vec![DnsRecord::A {
-                        domain: host.to_owned(),
-                        addr: Ipv4Addr::new(127, 0, 0, 1),
-                        ttl: 300u32,
-                    }],
-                },
-            );
-        })
-        .inspect(|host| info!("Ingress: {}", host.0))
-        .collect());
+/// Overrides are temporary, so they are cached by clients only for short time
+fn override_record(host: &str, addr: SocketAddr) -> CacheRecord {
+    let record = match addr.ip() {
+        IpAddr::V4(addr) => DnsRecord::A {
+            domain: host.to_owned(),
+            addr,
+            ttl: OVERRIDE_TTL,
+        },
+        IpAddr::V6(addr) => DnsRecord::AAAA {
+            domain: host.to_owned(),
+            addr,
+            ttl: OVERRIDE_TTL,
+        },
+    };
+    CacheRecord {
+        expires: OffsetDateTime::now_utc().add(Duration::days(365)),
+        records: vec![record],
+    }
+}
+
+/// Configured answer addresses or first ipv4 and ipv6 address of proxy listeners, unspecified
+/// ones are reachable through loopback and '::' accepts both families.
+/// Without proxy ingress hosts point to localhost
+fn ingress_addrs(props: &Properties) -> Vec<IpAddr> {
+    let hosts = match &props.proxy {
+        Some(proxy) if !proxy.answers().is_empty() => {
+            return proxy
+                .answers()
+                .iter()
+                .filter_map(|answer| answer.parse().ok())
+                .collect();
+        }
+        Some(proxy) => proxy.hosts(),
+        None => vec![],
+    };
+    let mut v4 = None;
+    let mut v6 = None;
+    for ip in hosts.iter().filter_map(|host| host.parse::<IpAddr>().ok()) {
+        match ip {
+            IpAddr::V4(ip) if ip.is_unspecified() => {
+                v4.get_or_insert(Ipv4Addr::LOCALHOST);
+            }
+            IpAddr::V4(ip) => {
+                v4.get_or_insert(ip);
+            }
+            IpAddr::V6(ip) if ip.is_unspecified() => {
+                v4.get_or_insert(Ipv4Addr::LOCALHOST);
+                v6.get_or_insert(Ipv6Addr::LOCALHOST);
+            }
+            IpAddr::V6(ip) => {
+                v6.get_or_insert(ip);
+            }
+        }
+    }
+    if v4.is_none() && v6.is_none() {
+        v4 = Some(Ipv4Addr::LOCALHOST);
+    }
+    v4.map(IpAddr::V4)
+        .into_iter()
+        .chain(v6.map(IpAddr::V6))
+        .collect()
 }
 
-async fn load_local_dns_cache(path: &String) -> Result<HashMap<String, CacheRecord>> {
-    let lines = crate::util::load_local_cache(path)
-        .await?
-        .iter()
-        .map(|(url, ip)| {
-            let dns_record = match ip {
-                SocketAddr::V4(ip4) => DnsRecord::A {
-                    domain: url.to_string(),
-                    addr: ip4.ip().clone(),
-                    ttl: 300u32,
+/// Ingress hosts are served by proxy, so they resolve to its listeners
+fn ingress_record(host: &str, addrs: &[IpAddr], ttl: u32) -> CacheRecord {
+    CacheRecord {
+        expires: OffsetDateTime::now_utc().add(Duration::days(365)),
+        records: addrs
+            .iter()
+            .map(|addr| match addr {
+                IpAddr::V4(addr) => DnsRecord::A {
+                    domain: host.to_owned(),
+                    addr: *addr,
+                    ttl,
                 },
-                SocketAddr::V6(ip6) => DnsRecord::AAAA {
-                    domain: url.to_string(),
-                    addr: ip6.ip().clone(),
-                    ttl: 300u32,
+                IpAddr::V6(addr) => DnsRecord::AAAA {
+                    domain: host.to_owned(),
+                    addr: *addr,
+                    ttl,
                 },
+            })
+            .collect(),
+    }
+}
+
+/// Reverse zone of local cache, address of several hosts has PTR record for each of them
+fn reverse_records(cache: &HashMap<String, CacheRecord>) -> HashMap<String, CacheRecord> {
+    let mut reverse: HashMap<String, CacheRecord> = HashMap::new();
+    let mut hosts: Vec<(&String, &CacheRecord)> = cache.iter().collect();
+    hosts.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (host, record) in hosts {
+        for dns_record in &record.records {
+            let (addr, ttl) = match dns_record {
+                DnsRecord::A { addr, ttl, .. } => (IpAddr::V4(*addr), *ttl),
+                DnsRecord::AAAA { addr, ttl, .. } => (IpAddr::V6(*addr), *ttl),
+                _ => continue,
             };
-            return (
-                url.to_string(),
-                CacheRecord {
-                    expires: OffsetDateTime::now_utc().add(Duration::days(365)),
-                    records: vec![dns_record],
-                },
-            );
-        })
-        .collect();
+            let name = reverse_name(addr);
+            reverse
+                .entry(name.to_string())
+                .or_insert_with(|| CacheRecord {
+                    records: vec![],
+                    expires: record.expires,
+                })
+                .records
+                .push(DnsRecord::PTR {
+                    domain: name,
+                    host: host.to_string(),
+                    ttl,
+                });
+        }
+    }
+    reverse
+}
 
-    return Ok(lines);
+/// Records of local cache file grouped by host
+async fn load_local_dns_cache(cache: &str) -> Result<HashMap<String, CacheRecord>> {
+    let mut hosts: HashMap<String, CacheRecord> = HashMap::new();
+    for entry in load_local_entries(cache).await? {
+        hosts
+            .entry(entry.host)
+            .or_insert_with(|| CacheRecord {
+                expires: OffsetDateTime::now_utc().add(Duration::days(365)),
+                records: vec![],
+            })
+            .records
+            .push(entry.record);
+    }
+    Ok(hosts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::properties::parse_properties;
+
+    /// Ingress addresses with proxy properties
+    fn addrs(proxy: Option<&str>) -> Vec<String> {
+        let mut props = parse_properties("", false).unwrap();
+        props.proxy = proxy.map(|yaml| serde_yaml::from_str(yaml).unwrap());
+        ingress_addrs(&props)
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn configured_answer_addresses_are_used() {
+        assert_eq!(
+            addrs(Some(
+                "host: 0.0.0.0\nanswer: '192.168.1.10, fd00::10, invalid'",
+            )),
+            vec!["192.168.1.10", "fd00::10"]
+        );
+    }
+
+    #[test]
+    fn first_listener_address_of_each_family_is_fallback() {
+        assert_eq!(
+            addrs(Some("host: '10.0.0.1,10.0.0.2,fd00::1,fd00::2'")),
+            vec!["10.0.0.1", "fd00::1"]
+        );
+        assert_eq!(addrs(Some("host: fd00::1")), vec!["fd00::1"]);
+
+        // auto answers are chosen per query, listeners are fallback
+        assert_eq!(
+            addrs(Some("host: 10.0.0.1\nanswer: auto")),
+            vec!["10.0.0.1"]
+        );
+    }
+
+    #[test]
+    fn unspecified_listeners_are_answered_with_loopback() {
+        assert_eq!(addrs(Some("host: 0.0.0.0")), vec!["127.0.0.1"]);
+
+        // dual-stack listener accepts both families
+        assert_eq!(addrs(Some("host: '::'")), vec!["127.0.0.1", "::1"]);
+    }
+
+    #[test]
+    fn without_proxy_or_addresses_ingress_is_localhost() {
+        assert_eq!(addrs(None), vec!["127.0.0.1"]);
+        assert_eq!(addrs(Some("host: localhost")), vec!["127.0.0.1"]);
+    }
 }
@@ -1,48 +1,222 @@
+use crate::admin::overrides::HostOverrides;
 use crate::config::properties::Properties;
 use crate::dns::buffer::BytePacketBuffer;
+use crate::dns::buffer::PACKET_SIZE;
 use crate::dns::server::cache::Cache;
-use anyhow::Result;
-use log::info;
+use crate::dns::server::policy::Policy;
+use crate::dns::server::upstream::Upstream;
+use crate::dns::server::zone::Zone;
+use crate::k8s::cluster::K8sClusters;
+use crate::listeners::Listeners;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::net::UdpSocket;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
 
-#[derive(Debug, Clone)]
+/// Idle tcp connection is closed after this time
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
 pub struct DnsServer {
-    pub(crate) public_dns_server: String,
-    pub(crate) host: String,
+    pub(crate) upstream: Arc<Upstream>,
+    pub(crate) hosts: Vec<String>,
     pub(crate) port: u16,
     pub(crate) cache: Cache,
+    /// Local zones, unknown names in them are answered with NXDOMAIN
+    pub(crate) zones: Arc<Vec<Zone>>,
+    /// Blocklists, allowlists and rewrites, they are checked before cache
+    pub(crate) policy: Option<Arc<Policy>>,
+    /// Ingress hosts are answered with address of interface which query arrived on
+    pub(crate) answer_auto: bool,
+    /// Queries which are not answered yet, they are waited on shutdown
+    pub(crate) in_flight: Arc<AtomicUsize>,
 }
 
 impl DnsServer {
-    pub async fn new(props: &Properties) -> Result<DnsServer> {
+    pub async fn new(
+        props: &Properties,
+        clusters: &K8sClusters,
+        overrides: &HostOverrides,
+    ) -> Result<DnsServer> {
         return Ok(DnsServer {
-            public_dns_server: props.dns.server.public.to_string(),
-            host: props.dns.server.host.to_string(),
+            upstream: Arc::new(Upstream::new(
+                &props.dns.server.public,
+                props.dns.server.doh_method,
+            )?),
+            hosts: props.dns.server.hosts(),
             port: props.dns.server.port,
-            cache: Cache::new(props).await?,
+            cache: Cache::new(props, clusters, overrides).await?,
+            zones: Arc::new(props.dns.zones.iter().map(Zone::new).collect()),
+            policy: match &props.dns.policy {
+                Some(policy) => Some(Arc::new(Policy::new(policy).await?)),
+                None => None,
+            },
+            answer_auto: props
+                .proxy
+                .as_ref()
+                .is_some_and(|proxy| proxy.answer_auto()),
+            in_flight: Arc::new(AtomicUsize::new(0)),
         });
     }
 
-    pub async fn serve(self) -> Result<()> {
-        let socket = Arc::new(UdpSocket::bind((self.host.as_str(), self.port)).await?);
+    pub async fn serve(self, sockets: Arc<Listeners>) -> Result<()> {
+        // listeners are owned by join set of current task, so aborting it close all of them
+        let mut listeners = JoinSet::new();
         let server = Arc::new(self);
 
-        info!("DNS Server Initialized");
+        for host in &server.hosts {
+            let socket = sockets.udp(host, server.port)?;
+            listeners.spawn(server.clone().serve_udp(Arc::new(socket)));
+
+            // tcp is optional, it is skipped if it couldn't be bound
+            if let Ok(listener) = sockets.tcp(host, server.port) {
+                listeners.spawn(server.clone().serve_tcp(listener));
+            }
+        }
+
+        if let Some(policy) = server.policy.clone().filter(|policy| policy.has_urls()) {
+            listeners.spawn(policy.refresh());
+        }
+
+        info!("DNS Server Initialized on {:?}", server.hosts);
 
+        while let Some(result) = listeners.join_next().await {
+            result??;
+        }
+        Ok(())
+    }
+
+    async fn serve_udp(self: Arc<Self>, socket: Arc<UdpSocket>) -> Result<()> {
         loop {
             let mut req_buffer = BytePacketBuffer::new();
             let (_, src) = socket.recv_from(&mut req_buffer.buf).await?;
             let dns_socket = socket.to_owned();
-            let dns_server = server.to_owned();
+            let dns_server = self.clone();
 
+            dns_server.in_flight.fetch_add(1, Ordering::Relaxed);
             tokio::spawn(async move {
-                match dns_server.handle_query(req_buffer, &dns_socket, src).await {
-                    Ok(_) => {}
-                    // Err(e) => error!("An error occurred: {}", e),
-                    Err(_) => {} //suppress temporary errors
+                if let Err(e) = dns_server.handle_query(req_buffer, &dns_socket, src).await {
+                    warn!("Unable to handle dns query from {}, err: {:?}", src, e)
                 }
+                dns_server.in_flight.fetch_sub(1, Ordering::Relaxed);
             });
         }
     }
+
+    async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, src) = listener.accept().await?;
+            let dns_server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = dns_server.handle_tcp(stream, src).await {
+                    warn!("Unable to handle dns query from {}, err: {:?}", src, e)
+                }
+            });
+        }
+    }
+
+    /// Answer length prefixed queries until client closes idle connection, RFC 7766
+    async fn handle_tcp(&self, mut stream: TcpStream, src: SocketAddr) -> Result<()> {
+        loop {
+            let len = match timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+                Err(_) => return Ok(()),
+                Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(len) => len? as usize,
+            };
+            if len > PACKET_SIZE {
+                return Err(anyhow!(
+                    "Query of {} bytes exceeds {} bytes",
+                    len,
+                    PACKET_SIZE
+                ));
+            }
+
+            let mut req_buffer = BytePacketBuffer::new();
+            stream.read_exact(&mut req_buffer.buf[..len]).await?;
+
+            self.in_flight.fetch_add(1, Ordering::Relaxed);
+            let response = self
+                .response(req_buffer, src, stream.local_addr()?, "tcp")
+                .await;
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            let response = response?;
+
+            stream.write_u16(response.len() as u16).await?;
+            stream.write_all(&response).await?;
+        }
+    }
+}
+
+/// Address of interface which query from client arrived on. Socket listening on all
+/// interfaces doesn't know it, so it is source address of route to client
+pub(crate) fn receiving_ip(local: SocketAddr, client: SocketAddr) -> Option<IpAddr> {
+    let ip = if local.ip().is_unspecified() {
+        let socket = std::net::UdpSocket::bind(SocketAddr::new(local.ip(), 0)).ok()?;
+        socket.connect(client).ok()?;
+        socket.local_addr().ok()?.ip()
+    } else {
+        local.ip()
+    };
+    // ipv4 client of dual-stack socket
+    match ip {
+        IpAddr::V6(v6) => Some(v6.to_ipv4_mapped().map_or(ip, IpAddr::V4)),
+        ip => Some(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receiving(local: &str, client: &str) -> Option<IpAddr> {
+        receiving_ip(local.parse().unwrap(), client.parse().unwrap())
+    }
+
+    #[test]
+    fn specific_bind_address_is_receiving_address() {
+        assert_eq!(
+            receiving("10.1.2.3:53", "192.168.0.5:40000"),
+            Some(IpAddr::from([10, 1, 2, 3]))
+        );
+        assert_eq!(
+            receiving("[fd00::53]:53", "[fd00::5]:40000"),
+            Some("fd00::53".parse().unwrap())
+        );
+        // ipv4 address of dual-stack socket is answered as ipv4
+        assert_eq!(
+            receiving("[::ffff:10.1.2.3]:53", "[::ffff:192.168.0.5]:40000"),
+            Some(IpAddr::from([10, 1, 2, 3]))
+        );
+    }
+
+    #[test]
+    fn wildcard_bind_address_is_source_of_route_to_client() {
+        assert_eq!(
+            receiving("0.0.0.0:53", "127.0.0.1:40000"),
+            Some(IpAddr::from([127, 0, 0, 1]))
+        );
+    }
+
+    #[test]
+    fn wildcard_dual_stack_address_answers_ipv4_clients_with_ipv4() {
+        // host without ipv6 can't bind dual-stack socket
+        if std::net::UdpSocket::bind("[::1]:0").is_err() {
+            return;
+        }
+        assert_eq!(
+            receiving("[::]:53", "[::1]:40000"),
+            Some(IpAddr::from(std::net::Ipv6Addr::LOCALHOST))
+        );
+        assert_eq!(
+            receiving("[::]:53", "[::ffff:127.0.0.1]:40000"),
+            Some(IpAddr::from([127, 0, 0, 1]))
+        );
+    }
 }
@@ -1,22 +1,109 @@
-use crate::dns::buffer::BytePacketBuffer;
+use crate::config::logs::DNS_ACCESS;
+use crate::dns::buffer::{BytePacketBuffer, MESSAGE_SIZE, PACKET_SIZE};
 use crate::dns::header::ResultCode::NOERROR;
-use crate::dns::header::ResultCode;
+use crate::dns::header::{QueryType, ResultCode};
 use crate::dns::packet::DnsPacket;
-use crate::dns::server::dns::DnsServer;
+use crate::dns::question::DnsQuestion;
+
+use crate::dns::record::DnsRecord;
+use crate::dns::server::dns::{receiving_ip, DnsServer};
+use crate::dns::server::policy::PolicyAction;
+use crate::dns::server::reverse::is_private_reverse;
+use crate::dns::server::zone::find_zone;
+use crate::metrics::{elapsed, METRICS};
 use anyhow::Result;
-use log::{debug, warn};
-use std::net::SocketAddr;
+use log::{debug, info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
 use tokio::net::UdpSocket;
 
+/// Aliases followed for one query, longer chains are answered with SERVFAIL
+const MAX_CNAME_CHAIN: usize = 8;
+/// Ttl of aliases made by policy rewrites
+const REWRITE_TTL: u32 = 60;
+
 impl DnsServer {
     pub async fn handle_query(
         &self,
-        mut req_buffer: BytePacketBuffer,
+        req_buffer: BytePacketBuffer,
         server_socket: &UdpSocket,
         client_socket: SocketAddr,
     ) -> Result<()> {
+        let response = self
+            .response(
+                req_buffer,
+                client_socket,
+                server_socket.local_addr()?,
+                "udp",
+            )
+            .await?;
+        server_socket.send_to(&response, client_socket).await?;
+
+        Ok(())
+    }
+
+    /// Answer query from buffer and return encoded response, query is written to access log.
+    /// `server_socket` is address of listener which received query
+    pub async fn response(
+        &self,
+        mut req_buffer: BytePacketBuffer,
+        client_socket: SocketAddr,
+        server_socket: SocketAddr,
+        protocol: &str,
+    ) -> Result<Vec<u8>> {
+        let start = Instant::now();
         let request = DnsPacket::from_buffer(&mut req_buffer)?;
+        let question = request.questions.last().cloned();
+        let local = if self.answer_auto {
+            receiving_ip(server_socket, client_socket)
+        } else {
+            None
+        };
+        let (mut packet, source) = self.answer_with_source(request, local).await;
+
+        // records which don't fit into udp packet are dropped with truncated flag,
+        // so client retries over tcp and gets the whole answer
+        let mut res_buffer = match protocol {
+            "tcp" => BytePacketBuffer::with_size(MESSAGE_SIZE),
+            _ => BytePacketBuffer::new(),
+        };
+        packet.write(&mut res_buffer)?;
+
+        let len = res_buffer.pos();
+        let data = res_buffer.get_range(0, len);
+
+        let (name, qtype) = match question {
+            Some(question) => (question.name, question.qtype.to_string()),
+            None => (String::new(), String::new()),
+        };
+        info!(
+            target: DNS_ACCESS,
+            client:% = client_socket,
+            protocol,
+            name:% = name,
+            qtype:% = qtype,
+            rcode:? = packet.header.rescode,
+            source,
+            answers = packet.header.answers,
+            latency_ms = elapsed(start) * 1000.0;
+            "dns query"
+        );
+
+        Ok(data.to_vec())
+    }
 
+    /// Build response for request from cache or upstream dns server
+    pub async fn answer(&self, request: DnsPacket) -> DnsPacket {
+        self.answer_with_source(request, None).await.0
+    }
+
+    /// Answer request and return source of answer: cache, zone, upstream or none,
+    /// ingress hosts are answered with `local` address if it is set
+    async fn answer_with_source(
+        &self,
+        request: DnsPacket,
+        local: Option<IpAddr>,
+    ) -> (DnsPacket, &'static str) {
         let mut packet = DnsPacket::new();
         packet.header.id = request.header.id;
         packet.header.recursion_desired = true;
@@ -28,53 +115,360 @@ impl DnsServer {
 
             let question_name = question.name.to_string();
 
-            if let Some(dns_record) = self.cache.find(&question.name).await {
+            let qtype = question.qtype;
+            let zone = find_zone(&self.zones, &question.name);
+            let action = self
+                .policy
+                .as_ref()
+                .and_then(|policy| policy.check(&question.name).map(|action| (policy, action)));
+            let source = if let Some((policy, action)) = action {
+                packet.questions.push(question.to_owned());
+                let (answers, rescode) = match action {
+                    PolicyAction::Block => policy.blocked(&question_name, qtype),
+                    PolicyAction::Rewrite(target) => {
+                        let alias = rewrite_alias(&question_name, target);
+                        self.chase(&question_name, vec![alias], qtype, local).await
+                    }
+                };
+                packet.answers = answers;
+                packet.header.rescode = rescode;
+                "policy"
+            } else if let Some(dns_record) = self.cache.find(&question.name, local).await {
+                packet.questions.push(question.to_owned());
+                let (answers, rescode) = self
+                    .chase(&question_name, dns_record.records, qtype, local)
+                    .await;
+                packet.answers = answers;
+                packet.header.rescode = rescode;
+                "cache"
+            } else if let Some(zone) = zone {
+                // names of local zone are never sent upstream
                 packet.questions.push(question.to_owned());
                 packet.header.rescode = NOERROR;
-                packet.answers = dns_record.records;
-            } else if let Ok(result) = self.lookup(request).await {
-                if result.header.truncated_message {
-                    warn!("Request to {} was truncated", question_name)
+                if !zone.is_apex(&question.name) && !self.cache.has_below(&question.name).await {
+                    packet.header.rescode = ResultCode::NXDOMAIN;
                 }
-
-                packet = result;
+                "zone"
+            } else if is_private_reverse(&question.name) {
+                // addresses of private networks are unknown to public dns
+                packet.questions.push(question.to_owned());
+                packet.header.rescode = ResultCode::NXDOMAIN;
+                "cache"
             } else {
-                packet.header.rescode = ResultCode::SERVFAIL;
+                match self.lookup(request).await {
+                    Ok(result) => {
+                        if result.header.truncated_message {
+                            warn!("Request to {} was truncated", question_name)
+                        }
+                        packet = result;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Unable to resolve {} through {}, err: {:?}",
+                            question_name,
+                            self.upstream.url(),
+                            e
+                        );
+                        packet.header.rescode = ResultCode::SERVFAIL;
+                    }
+                }
+                "upstream"
+            };
+
+            if let Some(zone) = zone {
+                packet.header.authoritative_answer = true;
+                if zone.is_apex(&question_name) {
+                    packet.answers.extend(zone.apex_records(qtype));
+                }
+                if packet.answers.is_empty() {
+                    packet.authorities.push(zone.soa());
+                }
             }
 
+            METRICS
+                .dns_queries
+                .with_label_values(&[
+                    &qtype.to_string(),
+                    &format!("{:?}", packet.header.rescode),
+                    source,
+                ])
+                .inc();
+            (packet, source)
         } else {
             packet.header.rescode = ResultCode::FORMERR;
+            METRICS
+                .dns_queries
+                .with_label_values(&["none", "FORMERR", "none"])
+                .inc();
+            (packet, "none")
         }
+    }
 
-        let mut res_buffer = BytePacketBuffer::new();
-        packet.write(&mut res_buffer)?;
-
-        let len = res_buffer.pos();
-        let data = res_buffer.get_range(0, len);
+    /// Answers of cache records, alias(CNAME) is followed through cache and then upstream,
+    /// so answer contains the whole chain. Blocked or rewritten targets are answered by policy
+    async fn chase(
+        &self,
+        name: &str,
+        mut records: Vec<DnsRecord>,
+        qtype: QueryType,
+        local: Option<IpAddr>,
+    ) -> (Vec<DnsRecord>, ResultCode) {
+        let mut answers = vec![];
+        let mut visited = vec![name.to_ascii_lowercase()];
+        loop {
+            let target = records.iter().find_map(|record| match record {
+                // names are case insensitive, so loops are detected regardless of case
+                DnsRecord::CNAME { host, .. } if !record.answers(qtype) => {
+                    Some(host.to_ascii_lowercase())
+                }
+                _ => None,
+            });
+            let Some(target) = target else {
+                // host without records of asked type has no answers, but still exists
+                answers.extend(records.into_iter().filter(|r| r.answers(qtype)));
+                return (answers, NOERROR);
+            };
+            answers.extend(
+                records
+                    .into_iter()
+                    .filter(|r| matches!(r, DnsRecord::CNAME { .. })),
+            );
+            if visited.contains(&target) || visited.len() > MAX_CNAME_CHAIN {
+                warn!("Unable to follow aliases of {}, chain {:?}", name, visited);
+                return (answers, ResultCode::SERVFAIL);
+            }
+            visited.push(target.clone());
 
-        server_socket.send_to(data, client_socket).await?;
+            // aliases must not bypass blocklists, ex. tracker behind first-party name
+            if let Some(policy) = &self.policy {
+                match policy.check(&target) {
+                    Some(PolicyAction::Block) => {
+                        let (blocked, rescode) = policy.blocked(&target, qtype);
+                        answers.extend(blocked);
+                        return (answers, rescode);
+                    }
+                    Some(PolicyAction::Rewrite(rewrite)) => {
+                        records = vec![rewrite_alias(&target, rewrite)];
+                        continue;
+                    }
+                    None => {}
+                }
+            }
 
-        return Ok(());
+            records = match self.cache.find(&target, local).await {
+                Some(record) => record.records,
+                None if find_zone(&self.zones, &target).is_some() => {
+                    return (answers, ResultCode::NXDOMAIN)
+                }
+                None => {
+                    let mut request = DnsPacket::new();
+                    request.header.id = rand::random();
+                    request.header.recursion_desired = true;
+                    request
+                        .questions
+                        .push(DnsQuestion::new(target.to_string(), qtype));
+                    return match self.lookup(request).await {
+                        Ok(result) => {
+                            answers.extend(result.answers);
+                            (answers, result.header.rescode)
+                        }
+                        Err(e) => {
+                            warn!(
+                                "Unable to resolve {} through {}, err: {:?}",
+                                target,
+                                self.upstream.url(),
+                                e
+                            );
+                            (answers, ResultCode::SERVFAIL)
+                        }
+                    };
+                }
+            };
+        }
     }
 
+    /// Resolve request through upstream over its transport: udp, tls or https
     pub async fn lookup(&self, mut packet: DnsPacket) -> Result<DnsPacket> {
-        let server = (self.public_dns_server.as_str(), 53);
-
-        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
-
         packet.header.resource_entries = 0;
         packet.resources.clear();
 
         let mut req_buffer = BytePacketBuffer::new();
         packet.write(&mut req_buffer)?;
 
-        socket
-            .send_to(&req_buffer.buf[0..req_buffer.pos], server)
+        let start = Instant::now();
+        let answer = self
+            .upstream
+            .exchange(&req_buffer.buf[0..req_buffer.pos])
             .await?;
+        METRICS
+            .dns_upstream_duration
+            .with_label_values(&[])
+            .observe(elapsed(start));
 
-        let mut res_buffer = BytePacketBuffer::new();
-        socket.recv_from(&mut res_buffer.buf).await?;
-
+        let mut res_buffer = BytePacketBuffer::with_size(answer.len().max(PACKET_SIZE));
+        res_buffer.buf[..answer.len()].copy_from_slice(&answer);
         return DnsPacket::from_buffer(&mut res_buffer);
     }
 }
+
+/// Alias of name rewritten by policy
+fn rewrite_alias(name: &str, target: String) -> DnsRecord {
+    DnsRecord::CNAME {
+        domain: name.to_string(),
+        host: target,
+        ttl: REWRITE_TTL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::overrides::HostOverrides;
+    use crate::config::properties::parse_properties;
+    use crate::k8s::cluster::K8sClusters;
+    use assert_fs::prelude::*;
+    use assert_fs::NamedTempFile;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    /// Dns server with local zone file cache and policy blocking 'tracker.example.com'
+    async fn server(zone: &str) -> DnsServer {
+        let file = NamedTempFile::new("cache.zone").unwrap();
+        file.write_str(zone).unwrap();
+        let mut props = parse_properties("", false).unwrap();
+        props.dns.cache = vec![format!("zone:{}", file.path().to_str().unwrap())];
+        props.dns.policy = Some(serde_yaml::from_str("block: [tracker.example.com]").unwrap());
+        let clusters = K8sClusters::connect(&props, None, Duration::ZERO).await;
+        DnsServer::new(&props, &clusters, &HostOverrides::default())
+            .await
+            .unwrap()
+    }
+
+    fn cname(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 300,
+        }
+    }
+
+    fn a(domain: &str, addr: [u8; 4]) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: Ipv4Addr::from(addr),
+            ttl: 300,
+        }
+    }
+
+    /// Zone with chain of `len` aliases of 'app.example.com', first is 'alias0.example.com'
+    fn alias_chain(len: usize) -> String {
+        let mut zone = String::from("$TTL 300\napp.example.com. A 10.0.0.1\n");
+        for i in 0..len {
+            let host = match i + 1 {
+                next if next == len => "app.example.com.".to_string(),
+                next => format!("alias{}.example.com.", next),
+            };
+            zone.push_str(&format!("alias{}.example.com. CNAME {}\n", i, host));
+        }
+        zone
+    }
+
+    async fn answer(server: &DnsServer, name: &str) -> DnsPacket {
+        let mut request = DnsPacket::new();
+        request
+            .questions
+            .push(DnsQuestion::new(name.to_string(), QueryType::A));
+        server.answer(request).await
+    }
+
+    #[tokio::test]
+    async fn alias_chain_is_followed_through_cache() {
+        let server = server(&alias_chain(2)).await;
+        let packet = answer(&server, "alias0.example.com").await;
+        assert_eq!(packet.header.rescode, NOERROR);
+        assert_eq!(
+            packet.answers,
+            vec![
+                cname("alias0.example.com", "alias1.example.com"),
+                cname("alias1.example.com", "app.example.com"),
+                a("app.example.com", [10, 0, 0, 1]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn alias_target_is_case_insensitive() {
+        let server = server("$TTL 300\napp.example.com. A 10.0.0.1\n").await;
+        let (answers, rescode) = server
+            .chase(
+                "www.example.com",
+                vec![cname("www.example.com", "App.Example.COM")],
+                QueryType::A,
+                None,
+            )
+            .await;
+        assert_eq!(rescode, NOERROR);
+        assert_eq!(answers[1], a("app.example.com", [10, 0, 0, 1]));
+
+        // loop is detected even when alias differs in case
+        let (_, rescode) = server
+            .chase(
+                "www.example.com",
+                vec![cname("www.example.com", "WWW.example.com")],
+                QueryType::A,
+                None,
+            )
+            .await;
+        assert_eq!(rescode, ResultCode::SERVFAIL);
+    }
+
+    #[tokio::test]
+    async fn alias_loop_is_servfail() {
+        let server = server(
+            "$TTL 300\n\
+             a.example.com. CNAME b.example.com.\n\
+             b.example.com. CNAME a.example.com.\n",
+        )
+        .await;
+        let packet = answer(&server, "a.example.com").await;
+        assert_eq!(packet.header.rescode, ResultCode::SERVFAIL);
+        assert_eq!(
+            packet.answers,
+            vec![
+                cname("a.example.com", "b.example.com"),
+                cname("b.example.com", "a.example.com"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn alias_to_blocked_name_is_blocked() {
+        let server = server("$TTL 300\ncdn.example.com. CNAME tracker.example.com.\n").await;
+        let packet = answer(&server, "cdn.example.com").await;
+        let policy = server.policy.as_ref().unwrap();
+        let (blocked, rescode) = policy.blocked("tracker.example.com", QueryType::A);
+        assert_eq!(packet.header.rescode, rescode);
+        assert_eq!(
+            packet.answers[0],
+            cname("cdn.example.com", "tracker.example.com")
+        );
+        assert_eq!(packet.answers[1..], blocked);
+    }
+
+    #[tokio::test]
+    async fn alias_chain_is_limited() {
+        let within = server(&alias_chain(MAX_CNAME_CHAIN)).await;
+        let packet = answer(&within, "alias0.example.com").await;
+        assert_eq!(packet.header.rescode, NOERROR);
+        assert_eq!(packet.answers.len(), MAX_CNAME_CHAIN + 1);
+
+        let beyond = server(&alias_chain(MAX_CNAME_CHAIN + 1)).await;
+        let packet = answer(&beyond, "alias0.example.com").await;
+        assert_eq!(packet.header.rescode, ResultCode::SERVFAIL);
+        assert_eq!(packet.answers.len(), MAX_CNAME_CHAIN + 1);
+        assert!(packet
+            .answers
+            .iter()
+            .all(|record| matches!(record, DnsRecord::CNAME { .. })));
+    }
+}
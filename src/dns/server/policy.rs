@@ -0,0 +1,442 @@
+use crate::config::properties::{is_url, BlockAnswer, PolicyProps};
+use crate::config::validate::is_dns_name;
+use crate::dns::header::{QueryType, ResultCode};
+use crate::dns::record::DnsRecord;
+use crate::metrics::METRICS;
+use crate::util::http_get;
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Ttl of blocked answers
+const BLOCK_TTL: u32 = 60;
+/// Download of url list is abandoned after this time, list keeps previous domains
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+/// Names of hosts files which are never blocked, ex. '127.0.0.1 localhost'
+const HOSTS_IGNORED: [&str; 8] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-allnodes",
+];
+
+/// Action of matched policy rule
+pub enum PolicyAction {
+    Block,
+    /// Name is answered as alias(CNAME) of replacement
+    Rewrite(String),
+}
+
+/// Rule with count of queries matched by it
+#[derive(Serialize)]
+pub struct RuleHits {
+    /// Source of list or rewrite, ex. 'blocklist.txt', '^(.+)\.test$ -> $1.dev'
+    pub rule: String,
+    /// allow, block or rewrite
+    pub action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domains: Option<usize>,
+    pub hits: u64,
+}
+
+/// Domains of list file, url or inline entries, subdomains of listed domain match too
+struct DomainList {
+    source: String,
+    action: &'static str,
+    domains: RwLock<HashSet<String>>,
+    hits: AtomicU64,
+}
+
+impl DomainList {
+    /// List file which can't be read fails, url which can't be downloaded is empty until refresh
+    async fn load(source: &str, action: &'static str) -> Result<DomainList> {
+        let list = DomainList::new(source, action, HashSet::new());
+        if is_url(source) {
+            list.reload().await;
+        } else {
+            let content = tokio::fs::read_to_string(source)
+                .await
+                .map_err(|e| anyhow!("Can't open file {}, err: {}", source, e))?;
+            *list.domains.write().unwrap() = parse_domains(&content);
+        }
+        Ok(list)
+    }
+
+    fn new(source: &str, action: &'static str, domains: HashSet<String>) -> DomainList {
+        DomainList {
+            source: source.to_string(),
+            action,
+            domains: RwLock::new(domains),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Download url list again, previous domains are kept on failure
+    async fn reload(&self) {
+        match tokio::time::timeout(DOWNLOAD_TIMEOUT, http_get(&self.source)).await {
+            Ok(Ok(content)) => {
+                let domains = parse_domains(&content);
+                info!("Loaded {} domains from {}", domains.len(), self.source);
+                *self.domains.write().unwrap() = domains;
+            }
+            Ok(Err(e)) => warn!("Unable to download {}, err: {:?}", self.source, e),
+            Err(_) => warn!(
+                "Unable to download {} in {:?}",
+                self.source, DOWNLOAD_TIMEOUT
+            ),
+        }
+    }
+
+    /// Return if name or one of its parent domains is listed, name is lowercase
+    fn matches(&self, name: &str) -> bool {
+        let domains = self.domains.read().unwrap();
+        let mut suffix = name;
+        loop {
+            if domains.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+
+    fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        METRICS
+            .dns_policy_hits
+            .with_label_values(&[&self.source, self.action])
+            .inc();
+    }
+}
+
+struct Rewrite {
+    pattern: Regex,
+    replace: String,
+    hits: AtomicU64,
+}
+
+/// Blocklists, allowlists and rewrites applied to dns queries before cache and upstream
+pub struct Policy {
+    allowlists: Vec<DomainList>,
+    blocklists: Vec<DomainList>,
+    rewrites: Vec<Rewrite>,
+    block_answer: BlockAnswer,
+    refresh: Duration,
+}
+
+impl Policy {
+    pub async fn new(props: &PolicyProps) -> Result<Policy> {
+        let mut allowlists = vec![];
+        for source in &props.allowlists {
+            allowlists.push(DomainList::load(source, "allow").await?);
+        }
+        if !props.allow.is_empty() {
+            let domains = parse_domains(&props.allow.join("\n"));
+            allowlists.push(DomainList::new("dns.policy.allow", "allow", domains));
+        }
+
+        let mut blocklists = vec![];
+        for source in &props.blocklists {
+            blocklists.push(DomainList::load(source, "block").await?);
+        }
+        if !props.block.is_empty() {
+            let domains = parse_domains(&props.block.join("\n"));
+            blocklists.push(DomainList::new("dns.policy.block", "block", domains));
+        }
+
+        let mut rewrites = vec![];
+        for rewrite in &props.rewrites {
+            let pattern = Regex::new(&rewrite.pattern).map_err(|e| {
+                anyhow!("Invalid rewrite pattern '{}', err: {}", rewrite.pattern, e)
+            })?;
+            rewrites.push(Rewrite {
+                pattern,
+                replace: rewrite.replace.to_string(),
+                hits: AtomicU64::new(0),
+            });
+        }
+
+        Ok(Policy {
+            allowlists,
+            blocklists,
+            rewrites,
+            block_answer: props.block_answer,
+            refresh: Duration::from_secs(props.refresh_seconds),
+        })
+    }
+
+    /// Action of first matching rule, None if name is allowed or no rule matches
+    pub fn check(&self, name: &str) -> Option<PolicyAction> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(list) = self.allowlists.iter().find(|list| list.matches(&name)) {
+            list.hit();
+            return None;
+        }
+        if let Some(list) = self.blocklists.iter().find(|list| list.matches(&name)) {
+            list.hit();
+            return Some(PolicyAction::Block);
+        }
+
+        for rewrite in &self.rewrites {
+            if !rewrite.pattern.is_match(&name) {
+                continue;
+            }
+            let target = rewrite
+                .pattern
+                .replace(&name, rewrite.replace.as_str())
+                .trim_end_matches('.')
+                .to_ascii_lowercase();
+            if target == name || !is_dns_name(&target) {
+                debug!("Skip rewrite of {} to '{}'", name, target);
+                continue;
+            }
+            rewrite.hits.fetch_add(1, Ordering::Relaxed);
+            METRICS
+                .dns_policy_hits
+                .with_label_values(&[rewrite.pattern.as_str(), "rewrite"])
+                .inc();
+            return Some(PolicyAction::Rewrite(target));
+        }
+        None
+    }
+
+    /// Answers and response code of blocked name
+    pub fn blocked(&self, name: &str, qtype: QueryType) -> (Vec<DnsRecord>, ResultCode) {
+        match self.block_answer {
+            BlockAnswer::Nxdomain => (vec![], ResultCode::NXDOMAIN),
+            BlockAnswer::Zero => {
+                let domain = name.to_string();
+                let records = match qtype {
+                    QueryType::A => vec![DnsRecord::A {
+                        domain,
+                        addr: Ipv4Addr::UNSPECIFIED,
+                        ttl: BLOCK_TTL,
+                    }],
+                    QueryType::AAAA => vec![DnsRecord::AAAA {
+                        domain,
+                        addr: Ipv6Addr::UNSPECIFIED,
+                        ttl: BLOCK_TTL,
+                    }],
+                    _ => vec![],
+                };
+                (records, ResultCode::NOERROR)
+            }
+        }
+    }
+
+    /// Rules in precedence order with their hits
+    pub fn hits(&self) -> Vec<RuleHits> {
+        let lists = self.allowlists.iter().chain(&self.blocklists);
+        let mut hits: Vec<RuleHits> = lists
+            .map(|list| RuleHits {
+                rule: list.source.to_string(),
+                action: list.action,
+                domains: Some(list.domains.read().unwrap().len()),
+                hits: list.hits.load(Ordering::Relaxed),
+            })
+            .collect();
+        hits.extend(self.rewrites.iter().map(|rewrite| RuleHits {
+            rule: format!("{} -> {}", rewrite.pattern, rewrite.replace),
+            action: "rewrite",
+            domains: None,
+            hits: rewrite.hits.load(Ordering::Relaxed),
+        }));
+        hits
+    }
+
+    pub fn has_urls(&self) -> bool {
+        self.allowlists
+            .iter()
+            .chain(&self.blocklists)
+            .any(|list| is_url(&list.source))
+    }
+
+    /// Download url lists every refresh interval
+    pub async fn refresh(self: Arc<Self>) -> Result<()> {
+        loop {
+            tokio::time::sleep(self.refresh).await;
+            for list in self
+                .allowlists
+                .iter()
+                .chain(&self.blocklists)
+                .filter(|list| is_url(&list.source))
+            {
+                list.reload().await;
+            }
+        }
+    }
+}
+
+/// Domains of hosts file(`0.0.0.0 ads.example.com`) or domain list(`ads.example.com`),
+/// '*.' and adblock '||domain^' forms are accepted, other lines are skipped
+fn parse_domains(content: &str) -> HashSet<String> {
+    let mut domains = HashSet::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut tokens = line.split_whitespace();
+        let Some(first) = tokens.next() else {
+            continue;
+        };
+        let names: Vec<&str> = if IpAddr::from_str(first).is_ok() {
+            tokens.collect()
+        } else {
+            vec![first]
+        };
+
+        for name in names {
+            let name = name
+                .trim_start_matches("||")
+                .trim_end_matches('^')
+                .trim_start_matches("*.")
+                .trim_end_matches('.')
+                .to_ascii_lowercase();
+            if HOSTS_IGNORED.contains(&name.as_str())
+                || IpAddr::from_str(&name).is_ok()
+                || !is_dns_name(&name)
+            {
+                continue;
+            }
+            domains.insert(name);
+        }
+    }
+    domains
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn policy(yaml: &str) -> Policy {
+        let props: PolicyProps = serde_yaml::from_str(yaml).unwrap();
+        Policy::new(&props).await.unwrap()
+    }
+
+    fn action(policy: &Policy, name: &str) -> Option<String> {
+        policy.check(name).map(|action| match action {
+            PolicyAction::Block => "block".to_string(),
+            PolicyAction::Rewrite(target) => target,
+        })
+    }
+
+    #[test]
+    fn parse_hosts_lines_and_domain_lists() {
+        let domains = parse_domains(
+            "# hosts file\n\
+            0.0.0.0 ads.example.com tracker.example.com # inline comment\n\
+            127.0.0.1\tmetrics.Example.org.\n\
+            \n\
+            ||telemetry.example.net^\n\
+            *.wild.example.com\n\
+            plain.example.com\n\
+            not_a_domain!\n",
+        );
+        let mut domains: Vec<String> = domains.into_iter().collect();
+        domains.sort();
+        assert_eq!(
+            domains,
+            vec![
+                "ads.example.com",
+                "metrics.example.org",
+                "plain.example.com",
+                "telemetry.example.net",
+                "tracker.example.com",
+                "wild.example.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_skips_hosts_ignored_names_and_addresses() {
+        let domains = parse_domains(
+            "127.0.0.1 localhost localhost.localdomain\n\
+            255.255.255.255 broadcasthost\n\
+            ::1 ip6-localhost ip6-loopback\n\
+            fe00::0 ip6-localnet\n\
+            ff02::1 ip6-allnodes\n\
+            0.0.0.0 local 10.0.0.1\n",
+        );
+        assert!(domains.is_empty(), "{:?}", domains);
+        for name in HOSTS_IGNORED {
+            assert!(parse_domains(name).is_empty(), "{} is ignored", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn blocked_domains_match_their_subdomains() {
+        let policy = policy("block: ['||ads.example.com^', '*.tracker.example.com']").await;
+        assert_eq!(
+            action(&policy, "ads.example.com"),
+            Some("block".to_string())
+        );
+        assert_eq!(
+            action(&policy, "Cdn.Ads.Example.com."),
+            Some("block".to_string())
+        );
+        assert_eq!(
+            action(&policy, "a.tracker.example.com"),
+            Some("block".to_string())
+        );
+        assert_eq!(action(&policy, "example.com"), None);
+        assert_eq!(action(&policy, "badads.example.com"), None);
+    }
+
+    #[tokio::test]
+    async fn allow_takes_precedence_over_block_and_rewrite() {
+        let policy = policy(
+            "block: [example.com]\n\
+            allow: [api.example.com]\n\
+            rewrites:\n\
+            - pattern: '^(.+)\\.example\\.com$'\n  replace: '$1.dev.test'\n",
+        )
+        .await;
+        assert_eq!(action(&policy, "api.example.com"), None);
+        assert_eq!(action(&policy, "v1.api.example.com"), None);
+        assert_eq!(
+            action(&policy, "web.example.com"),
+            Some("block".to_string())
+        );
+
+        let hits = policy.hits();
+        assert_eq!(hits[0].action, "allow");
+        assert_eq!(hits[0].hits, 2);
+        assert_eq!(hits[1].action, "block");
+        assert_eq!(hits[1].hits, 1);
+    }
+
+    #[tokio::test]
+    async fn rewrites_skip_invalid_or_identical_targets() {
+        let policy = policy(
+            "rewrites:\n\
+            - pattern: '^same\\.test$'\n  replace: 'same.test'\n\
+            - pattern: '^(.+)\\.bad\\.test$'\n  replace: '$1..test'\n\
+            - pattern: '^(.+)\\.(same|bad|staging)\\.test$'\n  replace: '$1.dev.test'\n",
+        )
+        .await;
+        assert_eq!(action(&policy, "same.test"), None);
+        assert_eq!(
+            action(&policy, "app.bad.test"),
+            Some("app.dev.test".to_string())
+        );
+        assert_eq!(
+            action(&policy, "APP.staging.test."),
+            Some("app.dev.test".to_string())
+        );
+        assert_eq!(action(&policy, "app.dev.test"), None);
+
+        let hits: Vec<u64> = policy.hits().iter().map(|rule| rule.hits).collect();
+        assert_eq!(hits, vec![0, 0, 2]);
+    }
+}
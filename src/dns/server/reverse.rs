@@ -0,0 +1,186 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const IPV4_ZONE: &str = "in-addr.arpa";
+const IPV6_ZONE: &str = "ip6.arpa";
+
+/// Private and local networks, their reverse lookups are answered locally
+/// instead of being sent to public dns, RFC 6303
+const PRIVATE_IPV4: [(Ipv4Addr, u32); 5] = [
+    (Ipv4Addr::new(10, 0, 0, 0), 8),
+    (Ipv4Addr::new(127, 0, 0, 0), 8),
+    (Ipv4Addr::new(169, 254, 0, 0), 16),
+    (Ipv4Addr::new(172, 16, 0, 0), 12),
+    (Ipv4Addr::new(192, 168, 0, 0), 16),
+];
+const PRIVATE_IPV6: [(Ipv6Addr, u32); 3] = [
+    (Ipv6Addr::LOCALHOST, 128),
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),
+    (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+];
+
+/// Name of PTR record for address, ex. '10.0.0.1' -> '1.0.0.10.in-addr.arpa'
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets: Vec<String> = ip.octets().iter().rev().map(u8::to_string).collect();
+            format!("{}.{}", octets.join("."), IPV4_ZONE)
+        }
+        IpAddr::V6(ip) => {
+            let nibbles: Vec<String> = ip
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|octet| [octet & 0xf, octet >> 4])
+                .map(|nibble| format!("{:x}", nibble))
+                .collect();
+            format!("{}.{}", nibbles.join("."), IPV6_ZONE)
+        }
+    }
+}
+
+/// Return if name is within reverse zone of private network, ex. '5.1.168.192.in-addr.arpa'.
+/// Zones wider than network, ex. '172.in-addr.arpa', are not private
+pub fn is_private_reverse(name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(labels) = zone_labels(&name, IPV4_ZONE) {
+        let Some(octets) = parse_labels::<4>(&labels, 10) else {
+            return false;
+        };
+        let (addr, bits) = (u32::from_be_bytes(octets), labels.len() as u32 * 8);
+        return PRIVATE_IPV4
+            .iter()
+            .any(|(net, len)| within(addr as u128, bits, u32::from(*net) as u128, *len, 32));
+    }
+    if let Some(labels) = zone_labels(&name, IPV6_ZONE) {
+        let Some(nibbles) = parse_labels::<32>(&labels, 16) else {
+            return false;
+        };
+        let addr = nibbles
+            .iter()
+            .fold(0u128, |addr, nibble| (addr << 4) | *nibble as u128);
+        let bits = labels.len() as u32 * 4;
+        return PRIVATE_IPV6
+            .iter()
+            .any(|(net, len)| within(addr, bits, u128::from(*net), *len, 128));
+    }
+    false
+}
+
+/// Labels of name under zone in address order, ex. '2.1.in-addr.arpa' -> ['1', '2']
+fn zone_labels<'a>(name: &'a str, zone: &str) -> Option<Vec<&'a str>> {
+    if name == zone {
+        return Some(vec![]);
+    }
+    let labels = name.strip_suffix(zone)?.strip_suffix('.')?;
+    Some(labels.split('.').rev().collect())
+}
+
+/// Parse octets or nibbles of address prefix, missing tail is filled with zeros
+fn parse_labels<const N: usize>(labels: &[&str], radix: u32) -> Option<[u8; N]> {
+    if labels.len() > N {
+        return None;
+    }
+    let mut parts = [0u8; N];
+    for (part, label) in parts.iter_mut().zip(labels) {
+        let value = u8::from_str_radix(label, radix).ok()?;
+        // nibbles are single hex digits
+        if radix == 16 && (label.len() != 1 || value > 0xf) {
+            return None;
+        }
+        *part = value;
+    }
+    Some(parts)
+}
+
+/// Return if address prefix of `bits` length is within network of `len` prefix,
+/// `width` is address length in bits
+fn within(addr: u128, bits: u32, net: u128, len: u32, width: u32) -> bool {
+    bits >= len && (addr ^ net).checked_shr(width - len).unwrap_or(0) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_name_of_ipv4_is_in_octet_order() {
+        assert_eq!(
+            reverse_name("192.168.1.5".parse().unwrap()),
+            "5.1.168.192.in-addr.arpa"
+        );
+    }
+
+    #[test]
+    fn reverse_name_of_ipv6_is_in_nibble_order() {
+        assert_eq!(
+            reverse_name("2001:db8::567:89ab".parse().unwrap()),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+        assert_eq!(
+            reverse_name(Ipv6Addr::LOCALHOST.into()),
+            format!("1.{}ip6.arpa", "0.".repeat(31))
+        );
+    }
+
+    #[test]
+    fn reverse_names_of_private_addresses_are_private() {
+        for ip in [
+            "10.1.2.3",
+            "127.0.0.1",
+            "169.254.10.1",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.5",
+            "::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+        ] {
+            let name = reverse_name(ip.parse().unwrap());
+            assert!(is_private_reverse(&name), "{} is private", name);
+        }
+        for ip in [
+            "8.8.8.8",
+            "172.15.0.1",
+            "172.32.0.1",
+            "2001:db8::1",
+            "fec0::1",
+        ] {
+            let name = reverse_name(ip.parse().unwrap());
+            assert!(!is_private_reverse(&name), "{} is not private", name);
+        }
+    }
+
+    #[test]
+    fn zones_within_private_network_are_private() {
+        assert!(is_private_reverse("10.in-addr.arpa"));
+        assert!(is_private_reverse("168.192.in-addr.arpa."));
+        assert!(is_private_reverse("16.172.IN-ADDR.ARPA"));
+        assert!(is_private_reverse("31.172.in-addr.arpa"));
+        assert!(is_private_reverse("c.f.ip6.arpa"));
+        assert!(is_private_reverse("d.f.ip6.arpa"));
+        assert!(is_private_reverse("8.e.f.ip6.arpa"));
+    }
+
+    #[test]
+    fn zones_wider_than_private_network_are_not_private() {
+        // 172/8 contains public addresses besides 172.16/12
+        assert!(!is_private_reverse("172.in-addr.arpa"));
+        assert!(!is_private_reverse("169.in-addr.arpa"));
+        assert!(!is_private_reverse("in-addr.arpa"));
+        assert!(!is_private_reverse("f.ip6.arpa"));
+        assert!(!is_private_reverse("e.f.ip6.arpa"));
+        assert!(!is_private_reverse("ip6.arpa"));
+    }
+
+    #[test]
+    fn invalid_reverse_names_are_not_private() {
+        assert!(!is_private_reverse("example.com"));
+        assert!(!is_private_reverse("256.10.in-addr.arpa"));
+        assert!(!is_private_reverse("1.2.3.4.10.in-addr.arpa"));
+        assert!(!is_private_reverse("cf.ip6.arpa"));
+        assert!(!is_private_reverse("g.f.ip6.arpa"));
+        assert!(!is_private_reverse("xin-addr.arpa"));
+        assert!(!is_private_reverse("1.0.0.10.in-addr.arpa.example.com"));
+    }
+}
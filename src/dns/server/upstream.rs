@@ -0,0 +1,886 @@
+use crate::config::properties::DohMethod;
+use crate::proxy::server::tls::get_root_cert_store;
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::debug;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
+use tokio::time::timeout;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::{rustls, TlsConnector};
+
+/// Upstream which doesn't answer in this time is failed
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DNS_PORT: u16 = 53;
+const DOT_PORT: u16 = 853;
+const HTTPS_PORT: u16 = 443;
+const DOH_PATH: &str = "/dns-query";
+const DNS_MESSAGE: &str = "application/dns-message";
+/// Limit of DoH response header, dns message itself is limited by its u16 length
+const HTTP_HEAD_LIMIT: usize = 16384;
+const MESSAGE_LIMIT: usize = u16::MAX as usize;
+/// TC bit in third byte of dns header
+const TRUNCATED_FLAG: u8 = 1 << 1;
+
+/// Answer or failure of query sent over pipelined connection
+type Answer = oneshot::Sender<Result<Vec<u8>>>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Transport {
+    Udp(SocketAddr),
+    /// DNS over TLS, RFC 7858
+    Tls(Endpoint),
+    /// DNS over HTTPS, RFC 8484
+    Https(Endpoint),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Endpoint {
+    host: String,
+    port: u16,
+    /// Host with port as written in url, it is sent in Host header
+    authority: String,
+    path: String,
+}
+
+/// Check upstream url of `dns.server.public`
+pub fn check_upstream(url: &str) -> Result<()> {
+    parse_transport(url.trim()).map(|_| ())
+}
+
+/// Ip address or 'udp://ip[:port]' is plain dns, 'tls://host[:port]' is DNS over TLS,
+/// 'https://host[:port][/path]' is DNS over HTTPS, path is '/dns-query' by default
+fn parse_transport(url: &str) -> Result<Transport> {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        let ip = IpAddr::from_str(url)
+            .map_err(|_| anyhow!("'{}' is not ip address or upstream url", url))?;
+        return Ok(Transport::Udp(SocketAddr::new(ip, DNS_PORT)));
+    };
+    let scheme = scheme.to_ascii_lowercase();
+    let default_port = match scheme.as_str() {
+        "udp" => DNS_PORT,
+        "tls" => DOT_PORT,
+        "https" => HTTPS_PORT,
+        _ => {
+            return Err(anyhow!(
+                "Unsupported scheme '{}' of {}, expected udp, tls or https",
+                scheme,
+                url
+            ))
+        }
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    let (host, port) = split_authority(authority, default_port)
+        .ok_or_else(|| anyhow!("'{}' of {} is not host[:port]", authority, url))?;
+
+    let endpoint = Endpoint {
+        host,
+        port,
+        authority: authority.to_string(),
+        path: path.to_string(),
+    };
+    match scheme.as_str() {
+        "udp" => {
+            let ip = IpAddr::from_str(&endpoint.host)
+                .map_err(|_| anyhow!("Host of udp upstream {} must be ip address", url))?;
+            Ok(Transport::Udp(SocketAddr::new(ip, port)))
+        }
+        "tls" => Ok(Transport::Tls(endpoint)),
+        _ if path.is_empty() => Ok(Transport::Https(Endpoint {
+            path: DOH_PATH.to_string(),
+            ..endpoint
+        })),
+        _ => Ok(Transport::Https(endpoint)),
+    }
+}
+
+/// Host and port of 'host', 'host:port' or '[ipv6]:port'
+fn split_authority(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, port) = rest.split_once(']')?;
+        let port = match port {
+            "" => default_port,
+            port => port.strip_prefix(':')?.parse().ok()?,
+        };
+        return Some((host.to_string(), port));
+    }
+    if IpAddr::from_str(authority).is_ok() {
+        return Some((authority.to_string(), default_port));
+    }
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, default_port),
+    };
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+/// Upstream dns server. Encrypted transports keep one connection,
+/// queries are pipelined over it without waiting for previous answers
+pub struct Upstream {
+    url: String,
+    transport: Transport,
+    method: DohMethod,
+    tls: Arc<rustls::ClientConfig>,
+    connection: tokio::sync::Mutex<Option<Arc<Connection>>>,
+}
+
+impl Upstream {
+    pub fn new(url: &str, method: DohMethod) -> Result<Upstream> {
+        let transport = parse_transport(url.trim())?;
+        let mut tls = rustls::ClientConfig::builder()
+            .with_root_certificates(get_root_cert_store())
+            .with_no_client_auth();
+        if let Transport::Https(_) = transport {
+            tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+        }
+        Ok(Upstream {
+            url: url.trim().to_string(),
+            transport,
+            method,
+            tls: Arc::new(tls),
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Send encoded query and return encoded answer with the same id
+    pub async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        if query.len() < 2 {
+            return Err(anyhow!("Query of {} bytes has no header", query.len()));
+        }
+        match &self.transport {
+            Transport::Udp(addr) => timeout(QUERY_TIMEOUT, exchange_udp(*addr, query))
+                .await
+                .map_err(|_| anyhow!("No answer from {} in {:?}", self.url, QUERY_TIMEOUT))?,
+            Transport::Tls(endpoint) | Transport::Https(endpoint) => {
+                let mut answer = self.exchange_pipelined(endpoint, query).await?;
+                if answer.len() < 2 {
+                    return Err(anyhow!("Answer of {} has no header", self.url));
+                }
+                answer[..2].copy_from_slice(&query[..2]);
+                Ok(answer)
+            }
+        }
+    }
+
+    /// Server may close idle connection at any time, so query is retried once on new connection
+    async fn exchange_pipelined(&self, endpoint: &Endpoint, query: &[u8]) -> Result<Vec<u8>> {
+        for attempt in 0..2 {
+            let connection = self.connection(endpoint).await?;
+            let receiver = match connection.send(query).await {
+                Ok(receiver) => receiver,
+                Err(e) => {
+                    debug!("Unable to send query to {}, err: {:?}", self.url, e);
+                    connection.close();
+                    continue;
+                }
+            };
+            match timeout(QUERY_TIMEOUT, receiver).await {
+                Ok(Ok(answer)) => return answer,
+                Ok(Err(_)) => debug!(
+                    "Connection to {} is closed before answer, attempt {}",
+                    self.url, attempt
+                ),
+                Err(_) => {
+                    // answers of http/1.1 are ordered, so lost answer blocks connection
+                    connection.close();
+                    return Err(anyhow!(
+                        "No answer from {} in {:?}",
+                        self.url,
+                        QUERY_TIMEOUT
+                    ));
+                }
+            }
+        }
+        Err(anyhow!(
+            "Connection to {} is closed before answer",
+            self.url
+        ))
+    }
+
+    /// Open connection or reuse current one
+    async fn connection(&self, endpoint: &Endpoint) -> Result<Arc<Connection>> {
+        let mut current = self.connection.lock().await;
+        if let Some(connection) = current.as_ref().filter(|c| !c.is_closed()) {
+            return Ok(connection.clone());
+        }
+
+        let stream = timeout(
+            CONNECT_TIMEOUT,
+            TcpStream::connect((endpoint.host.as_str(), endpoint.port)),
+        )
+        .await
+        .map_err(|_| anyhow!("Unable to connect {} in {:?}", self.url, CONNECT_TIMEOUT))??;
+        stream.set_nodelay(true)?;
+        let server_name = rustls::pki_types::ServerName::try_from(endpoint.host.to_string())?;
+        let stream = timeout(
+            CONNECT_TIMEOUT,
+            TlsConnector::from(self.tls.clone()).connect(server_name, stream),
+        )
+        .await
+        .map_err(|_| anyhow!("Tls handshake with {} timed out", self.url))??;
+        debug!("Connected to upstream {}", self.url);
+
+        let (reader, writer) = tokio::io::split(stream);
+        let http = match &self.transport {
+            Transport::Https(endpoint) => Some((endpoint.clone(), self.method)),
+            _ => None,
+        };
+        let connection = Arc::new(Connection {
+            http,
+            writer: tokio::sync::Mutex::new(writer),
+            pending: Mutex::new(VecDeque::new()),
+            closed: AtomicBool::new(false),
+            reader: Mutex::new(None),
+        });
+        let task = tokio::spawn(connection.clone().read(reader));
+        *connection.reader.lock().unwrap() = Some(task.abort_handle());
+        *current = Some(connection.clone());
+        Ok(connection)
+    }
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.get_mut().take() {
+            connection.close();
+        }
+    }
+}
+
+/// Answers from other addresses are refused by connected socket and answers with other id
+/// are dropped, so spoofed answer has to guess random id. Truncated answer is queried again
+/// over tcp, RFC 7766
+async fn exchange_udp(addr: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let bind = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+    let id: u16 = rand::random();
+    let mut random_query = query.to_vec();
+    random_query[..2].copy_from_slice(&id.to_be_bytes());
+    socket.send(&random_query).await?;
+
+    let mut answer = vec![0; MESSAGE_LIMIT];
+    loop {
+        let len = socket.recv(&mut answer).await?;
+        if len >= 3 && answer[..2] == id.to_be_bytes() {
+            answer.truncate(len);
+            break;
+        }
+        debug!("Drop answer of {} bytes with unknown id from {}", len, addr);
+    }
+
+    if answer[2] & TRUNCATED_FLAG != 0 {
+        debug!("Answer of {} is truncated, query it over tcp", addr);
+        answer = exchange_tcp(addr, &random_query).await?;
+    }
+    answer[..2].copy_from_slice(&query[..2]);
+    Ok(answer)
+}
+
+/// Length prefixed query and answer over new connection, RFC 1035 4.2.2
+async fn exchange_tcp(addr: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut frame = (query.len() as u16).to_be_bytes().to_vec();
+    frame.extend_from_slice(query);
+    stream.write_all(&frame).await?;
+
+    let len = stream.read_u16().await? as usize;
+    let mut answer = vec![0; len];
+    stream.read_exact(&mut answer).await?;
+    if len < 2 || answer[..2] != query[..2] {
+        return Err(anyhow!("Tcp answer of {} doesn't match query id", addr));
+    }
+    Ok(answer)
+}
+
+/// Tls connection with queries waiting for answers in order of sending
+struct Connection {
+    /// DoH endpoint and method, DoT connection if it is not set
+    http: Option<(Endpoint, DohMethod)>,
+    writer: tokio::sync::Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    /// Ids of sent queries with their answer channels
+    pending: Mutex<VecDeque<(u16, Answer)>>,
+    closed: AtomicBool,
+    reader: Mutex<Option<AbortHandle>>,
+}
+
+impl Connection {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Stop reading answers, waiting queries are failed
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(reader) = self.reader.lock().unwrap().take() {
+            reader.abort();
+        }
+        self.pending.lock().unwrap().clear();
+    }
+
+    async fn send(&self, query: &[u8]) -> Result<oneshot::Receiver<Result<Vec<u8>>>> {
+        // writer lock keeps order of pending queries same as order on the wire
+        let mut writer = self.writer.lock().await;
+        if self.is_closed() {
+            return Err(anyhow!("Connection is closed"));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let mut query = query.to_vec();
+        let frame = {
+            let mut pending = self.pending.lock().unwrap();
+            match &self.http {
+                Some((endpoint, method)) => {
+                    // id 0 keeps GET requests cacheable, RFC 8484 4.1
+                    query[..2].copy_from_slice(&[0, 0]);
+                    pending.push_back((0, sender));
+                    http_request(endpoint, *method, &query)
+                }
+                None => {
+                    // answers of pipelined queries can be out of order, they are matched by id
+                    let id = loop {
+                        let id: u16 = rand::random();
+                        if !pending.iter().any(|(pending_id, _)| *pending_id == id) {
+                            break id;
+                        }
+                    };
+                    query[..2].copy_from_slice(&id.to_be_bytes());
+                    pending.push_back((id, sender));
+                    let mut frame = (query.len() as u16).to_be_bytes().to_vec();
+                    frame.extend_from_slice(&query);
+                    frame
+                }
+            }
+        };
+        writer.write_all(&frame).await?;
+        writer.flush().await?;
+        Ok(receiver)
+    }
+
+    async fn read(self: Arc<Self>, reader: ReadHalf<TlsStream<TcpStream>>) {
+        let mut reader = BufReader::new(reader);
+        let result = match self.http {
+            Some(_) => self.read_http(&mut reader).await,
+            None => self.read_tls(&mut reader).await,
+        };
+        if let Err(e) = result {
+            debug!("Upstream connection is closed, err: {:?}", e);
+        }
+        self.close();
+    }
+
+    /// Length prefixed messages, RFC 7858
+    async fn read_tls(&self, reader: &mut BufReader<ReadHalf<TlsStream<TcpStream>>>) -> Result<()> {
+        loop {
+            let len = reader.read_u16().await? as usize;
+            let mut message = vec![0; len];
+            reader.read_exact(&mut message).await?;
+            if len < 2 {
+                return Err(anyhow!("Answer of {} bytes has no header", len));
+            }
+
+            let id = u16::from_be_bytes([message[0], message[1]]);
+            let answer = {
+                let mut pending = self.pending.lock().unwrap();
+                pending
+                    .iter()
+                    .position(|(pending_id, _)| *pending_id == id)
+                    .and_then(|i| pending.remove(i))
+            };
+            match answer {
+                Some((_, answer)) => {
+                    let _ = answer.send(Ok(message));
+                }
+                None => debug!("Drop answer with unknown id {}", id),
+            }
+        }
+    }
+
+    /// Http/1.1 responses in order of requests, connection ends on 'Connection: close'
+    async fn read_http(
+        &self,
+        reader: &mut BufReader<ReadHalf<TlsStream<TcpStream>>>,
+    ) -> Result<()> {
+        loop {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+                if reader.read_until(b'\n', &mut head).await? == 0 {
+                    return Err(anyhow!("Connection is closed"));
+                }
+                if head.len() > HTTP_HEAD_LIMIT {
+                    return Err(anyhow!("Response header exceeds {} bytes", HTTP_HEAD_LIMIT));
+                }
+            }
+
+            let mut headers = [httparse::EMPTY_HEADER; 64];
+            let mut response = httparse::Response::new(&mut headers);
+            response.parse(&head)?;
+            let code = response.code.unwrap_or_default();
+            let header = |name: &str| {
+                response
+                    .headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case(name))
+                    .map(|header| String::from_utf8_lossy(header.value).to_ascii_lowercase())
+            };
+            let close = header("Connection").is_some_and(|value| value.contains("close"));
+            let chunked =
+                header("Transfer-Encoding").is_some_and(|value| value.contains("chunked"));
+            let length = header("Content-Length")
+                .map(|value| value.trim().parse::<usize>())
+                .transpose()?;
+
+            let body = match (chunked, length) {
+                (true, _) => read_chunked(reader).await?,
+                (false, Some(length)) if length > MESSAGE_LIMIT => {
+                    return Err(anyhow!("Answer of {} bytes is too big", length))
+                }
+                (false, Some(length)) => {
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).await?;
+                    body
+                }
+                // body without length ends with connection
+                (false, None) => {
+                    let mut body = Vec::new();
+                    let mut limited = reader.take(MESSAGE_LIMIT as u64);
+                    limited.read_to_end(&mut body).await?;
+                    body
+                }
+            };
+
+            let answer = self.pending.lock().unwrap().pop_front();
+            if let Some((_, answer)) = answer {
+                let _ = answer.send(match code {
+                    200 => Ok(body),
+                    code => Err(anyhow!("Upstream answered with status {}", code)),
+                });
+            }
+            if close || length.is_none() && !chunked {
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn read_chunked(reader: &mut BufReader<ReadHalf<TlsStream<TcpStream>>>) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| anyhow!("Invalid chunk size '{}'", size))?;
+        if size == 0 {
+            // trailers end with empty line
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+                    return Ok(body);
+                }
+            }
+        }
+        if body.len() + size > MESSAGE_LIMIT {
+            return Err(anyhow!("Answer exceeds {} bytes", MESSAGE_LIMIT));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        line.clear();
+        reader.read_line(&mut line).await?;
+    }
+}
+
+fn http_request(endpoint: &Endpoint, method: DohMethod, query: &[u8]) -> Vec<u8> {
+    match method {
+        DohMethod::Get => {
+            let separator = if endpoint.path.contains('?') {
+                '&'
+            } else {
+                '?'
+            };
+            format!(
+                "GET {}{}dns={} HTTP/1.1\r\nHost: {}\r\nAccept: {}\r\n\r\n",
+                endpoint.path,
+                separator,
+                URL_SAFE_NO_PAD.encode(query),
+                endpoint.authority,
+                DNS_MESSAGE
+            )
+            .into_bytes()
+        }
+        DohMethod::Post => {
+            let mut request = format!(
+                "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nAccept: {}\r\nContent-Length: {}\r\n\r\n",
+                endpoint.path,
+                endpoint.authority,
+                DNS_MESSAGE,
+                DNS_MESSAGE,
+                query.len()
+            )
+            .into_bytes();
+            request.extend_from_slice(query);
+            request
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header with id of query, flags and zero counts, followed by payload
+    fn answer(query: &[u8], flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut answer = query[..2].to_vec();
+        answer.extend_from_slice(&[0x80 | flags, 0]);
+        answer.extend_from_slice(&[0; 8]);
+        answer.extend_from_slice(payload);
+        answer
+    }
+
+    fn query() -> Vec<u8> {
+        query_of(0x1234, b"")
+    }
+
+    /// Query with id, payload marks query in tests with several queries
+    fn query_of(id: u16, payload: &[u8]) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend_from_slice(&[0x01, 0]);
+        query.extend_from_slice(&[0; 8]);
+        query.extend_from_slice(payload);
+        query
+    }
+
+    /// Answer of mock upstream, it echoes payload of query
+    fn echo(query: &[u8]) -> Vec<u8> {
+        answer(query, 0, &query[12..])
+    }
+
+    /// Tls listener on localhost with self-signed certificate and root store trusting it
+    async fn tls_listener() -> (
+        tokio::net::TcpListener,
+        tokio_rustls::TlsAcceptor,
+        rustls::RootCertStore,
+    ) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key =
+            rustls::pki_types::PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into());
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        (
+            listener,
+            tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+            roots,
+        )
+    }
+
+    /// Upstream which trusts only given roots
+    fn upstream(url: &str, method: DohMethod, roots: rustls::RootCertStore) -> Upstream {
+        let tls = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Upstream {
+            url: url.to_string(),
+            transport: parse_transport(url).unwrap(),
+            method,
+            tls: Arc::new(tls),
+            connection: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Http request head and body, body length is taken from Content-Length
+    async fn read_request<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> (String, Vec<u8>) {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            head.push_str(&line);
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map(|length| length.parse().unwrap())
+            .unwrap_or(0);
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await.unwrap();
+        (head, body)
+    }
+
+    #[tokio::test]
+    async fn udp_answers_from_other_address_or_with_other_id_are_dropped() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MESSAGE_LIMIT];
+            let (len, client) = server.recv_from(&mut buf).await.unwrap();
+            let query = &buf[..len];
+
+            let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let spoofed = answer(query, 0, b"spoofed");
+            spoofer.send_to(&spoofed, client).await.unwrap();
+            let mut other_id = answer(query, 0, b"other id");
+            other_id[..2]
+                .copy_from_slice(&(!u16::from_be_bytes([query[0], query[1]])).to_be_bytes());
+            server.send_to(&other_id, client).await.unwrap();
+            server
+                .send_to(&answer(query, 0, b"valid"), client)
+                .await
+                .unwrap();
+        });
+
+        let query = query();
+        let result = timeout(QUERY_TIMEOUT, exchange_udp(addr, &query))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, answer(&query, 0, b"valid"));
+    }
+
+    #[tokio::test]
+    async fn truncated_udp_answer_is_queried_again_over_tcp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MESSAGE_LIMIT];
+            let (len, client) = server.recv_from(&mut buf).await.unwrap();
+            server
+                .send_to(&answer(&buf[..len], TRUNCATED_FLAG, b""), client)
+                .await
+                .unwrap();
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap() as usize;
+            let mut query = vec![0; len];
+            stream.read_exact(&mut query).await.unwrap();
+            let answer = answer(&query, 0, &[7; 1024]);
+            stream.write_u16(answer.len() as u16).await.unwrap();
+            stream.write_all(&answer).await.unwrap();
+        });
+
+        let query = query();
+        let result = timeout(QUERY_TIMEOUT, exchange_udp(addr, &query))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, answer(&query, 0, &[7; 1024]));
+    }
+
+    #[test]
+    fn parse_plain_and_encrypted_transports() {
+        assert_eq!(
+            parse_transport("1.1.1.1").unwrap(),
+            Transport::Udp("1.1.1.1:53".parse().unwrap())
+        );
+        assert_eq!(
+            parse_transport("udp://[::1]:5353").unwrap(),
+            Transport::Udp("[::1]:5353".parse().unwrap())
+        );
+        let Transport::Tls(endpoint) = parse_transport("TLS://dns.example.com").unwrap() else {
+            panic!("expected tls transport");
+        };
+        assert_eq!(
+            (endpoint.host.as_str(), endpoint.port),
+            ("dns.example.com", DOT_PORT)
+        );
+
+        let Transport::Https(endpoint) = parse_transport("https://dns.example.com").unwrap() else {
+            panic!("expected https transport");
+        };
+        assert_eq!(endpoint.port, HTTPS_PORT);
+        assert_eq!(endpoint.path, DOH_PATH);
+
+        let Transport::Https(endpoint) =
+            parse_transport("https://[2606:4700::1111]:8443/resolve?ct=1").unwrap()
+        else {
+            panic!("expected https transport");
+        };
+        assert_eq!(endpoint.host, "2606:4700::1111");
+        assert_eq!(endpoint.port, 8443);
+        assert_eq!(endpoint.authority, "[2606:4700::1111]:8443");
+        assert_eq!(endpoint.path, "/resolve?ct=1");
+    }
+
+    #[test]
+    fn parse_invalid_transports() {
+        for url in [
+            "dns.example.com",
+            "ftp://dns.example.com",
+            "udp://dns.example.com",
+            "tls://",
+            "tls://dns.example.com:dot",
+            "https://:443",
+            "https://[::1:443",
+            "tls://[::1]853",
+        ] {
+            assert!(parse_transport(url).is_err(), "{} is invalid", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn dot_answers_are_matched_by_id_out_of_order() {
+        let (listener, acceptor, roots) = tls_listener().await;
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut queries = vec![];
+            for _ in 0..2 {
+                let len = stream.read_u16().await.unwrap() as usize;
+                let mut query = vec![0; len];
+                stream.read_exact(&mut query).await.unwrap();
+                queries.push(query);
+            }
+            assert_ne!(queries[0][..2], queries[1][..2]);
+
+            let mut unknown = echo(&queries[0]);
+            let id = u16::from_be_bytes([unknown[0], unknown[1]]) ^ 0xffff;
+            unknown[..2].copy_from_slice(&id.to_be_bytes());
+            for answer in [unknown, echo(&queries[1]), echo(&queries[0])] {
+                stream.write_u16(answer.len() as u16).await.unwrap();
+                stream.write_all(&answer).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+            // keep connection open until client reads answers
+            let _ = stream.read_u16().await;
+        });
+
+        let upstream = upstream(&format!("tls://localhost:{}", port), DohMethod::Post, roots);
+        let (first, second) = (query_of(1, b"first"), query_of(2, b"second"));
+        let (first_answer, second_answer) =
+            tokio::join!(upstream.exchange(&first), upstream.exchange(&second));
+        assert_eq!(first_answer.unwrap(), echo(&first));
+        assert_eq!(second_answer.unwrap(), echo(&second));
+    }
+
+    #[tokio::test]
+    async fn doh_post_answers_are_paired_in_order_with_length_and_chunked_bodies() {
+        let (listener, acceptor, roots) = tls_listener().await;
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut reader = BufReader::new(reader);
+
+            let mut queries = vec![];
+            for _ in 0..2 {
+                let (head, body) = read_request(&mut reader).await;
+                assert!(head.starts_with("POST /dns-query HTTP/1.1\r\n"), "{}", head);
+                assert!(head.contains(&format!("Host: localhost:{}\r\n", port)));
+                assert!(head.contains("Content-Type: application/dns-message\r\n"));
+                // id is 0 in DoH queries
+                assert_eq!(body[..2], [0, 0]);
+                queries.push(body);
+            }
+
+            let first = echo(&queries[0]);
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                DNS_MESSAGE,
+                first.len()
+            );
+            writer.write_all(head.as_bytes()).await.unwrap();
+            writer.write_all(&first).await.unwrap();
+
+            let second = echo(&queries[1]);
+            let (start, end) = second.split_at(5);
+            let mut chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            for chunk in [start, end] {
+                chunked.extend_from_slice(format!("{:x};ext=1\r\n", chunk.len()).as_bytes());
+                chunked.extend_from_slice(chunk);
+                chunked.extend_from_slice(b"\r\n");
+            }
+            chunked.extend_from_slice(b"0\r\nExpires: never\r\n\r\n");
+            writer.write_all(&chunked).await.unwrap();
+            writer.flush().await.unwrap();
+            let _ = reader.read_u8().await;
+        });
+
+        let upstream = upstream(
+            &format!("https://localhost:{}", port),
+            DohMethod::Post,
+            roots,
+        );
+        let (first, second) = (query_of(1, b"first"), query_of(2, b"second"));
+        let (first_answer, second_answer) =
+            tokio::join!(upstream.exchange(&first), upstream.exchange(&second));
+        assert_eq!(first_answer.unwrap(), echo(&first));
+        assert_eq!(second_answer.unwrap(), echo(&second));
+    }
+
+    #[tokio::test]
+    async fn doh_get_sends_query_in_url_and_fails_on_error_status() {
+        let (listener, acceptor, roots) = tls_listener().await;
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut reader = BufReader::new(reader);
+
+            let (head, _) = read_request(&mut reader).await;
+            let target = head.split_whitespace().nth(1).unwrap().to_string();
+            let encoded = target.strip_prefix("/resolve?ct=1&dns=").unwrap();
+            let query = URL_SAFE_NO_PAD.decode(encoded).unwrap();
+            assert!(head.contains("Accept: application/dns-message\r\n"));
+            let answer = echo(&query);
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                answer.len()
+            );
+            writer.write_all(head.as_bytes()).await.unwrap();
+            writer.write_all(&answer).await.unwrap();
+
+            read_request(&mut reader).await;
+            writer
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            writer.flush().await.unwrap();
+            let _ = reader.read_u8().await;
+        });
+
+        let upstream = upstream(
+            &format!("https://localhost:{}/resolve?ct=1", port),
+            DohMethod::Get,
+            roots,
+        );
+        let query = query_of(7, b"get");
+        assert_eq!(upstream.exchange(&query).await.unwrap(), echo(&query));
+        let err = upstream.exchange(&query).await.unwrap_err();
+        assert!(err.to_string().contains("status 400"), "{}", err);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::config::properties::ZoneProps;
+use crate::dns::header::QueryType;
+use crate::dns::record::DnsRecord;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// SOA timers of zone, they are used only by secondary servers
+const REFRESH: u32 = 3600;
+const RETRY: u32 = 600;
+const EXPIRE: u32 = 86400;
+
+/// Local zone which dns server is authoritative for
+pub struct Zone {
+    name: String,
+    ns: String,
+    ttl: u32,
+    /// Load time, so zone changed on config reload has new serial
+    serial: u32,
+}
+
+impl Zone {
+    pub fn new(props: &ZoneProps) -> Zone {
+        let serial = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs() as u32)
+            .unwrap_or(1);
+        Zone {
+            name: normalize(&props.name),
+            ns: normalize(&props.ns),
+            ttl: props.ttl,
+            serial,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        let name = normalize(name);
+        name == self.name || name.ends_with(&format!(".{}", self.name))
+    }
+
+    pub fn is_apex(&self, name: &str) -> bool {
+        normalize(name) == self.name
+    }
+
+    /// SOA and NS records of zone apex of asked type
+    pub fn apex_records(&self, qtype: QueryType) -> Vec<DnsRecord> {
+        [self.soa(), self.ns()]
+            .into_iter()
+            .filter(|record| record.answers(qtype))
+            .collect()
+    }
+
+    /// SOA is added to authority section of negative answers, its minimum is their ttl
+    pub fn soa(&self) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: self.name.to_string(),
+            m_name: self.ns.to_string(),
+            r_name: format!("hostmaster.{}", self.name),
+            serial: self.serial,
+            refresh: REFRESH,
+            retry: RETRY,
+            expire: EXPIRE,
+            minimum: self.ttl,
+            ttl: self.ttl,
+        }
+    }
+
+    fn ns(&self) -> DnsRecord {
+        DnsRecord::NS {
+            domain: self.name.to_string(),
+            host: self.ns.to_string(),
+            ttl: self.ttl,
+        }
+    }
+}
+
+/// Most specific zone which contains name
+pub fn find_zone<'a>(zones: &'a [Zone], name: &str) -> Option<&'a Zone> {
+    zones
+        .iter()
+        .filter(|zone| zone.contains(name))
+        .max_by_key(|zone| zone.name.len())
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::overrides::HostOverrides;
+    use crate::config::properties::parse_properties;
+    use crate::dns::header::ResultCode;
+    use crate::dns::packet::DnsPacket;
+    use crate::dns::question::DnsQuestion;
+    use crate::dns::server::dns::DnsServer;
+    use crate::k8s::cluster::K8sClusters;
+    use assert_fs::prelude::*;
+    use assert_fs::NamedTempFile;
+    use std::time::Duration;
+
+    fn zone(name: &str) -> Zone {
+        Zone::new(&serde_yaml::from_str(&format!("name: {}", name)).unwrap())
+    }
+
+    /// Dns server authoritative for 'dev.example.com' with local cache of hosts in it
+    async fn server() -> DnsServer {
+        let file = NamedTempFile::new("cache.zone").unwrap();
+        file.write_str(
+            "$TTL 300\n\
+             app.dev.example.com. A 10.0.0.1\n\
+             api.v1.dev.example.com. A 10.0.0.2\n",
+        )
+        .unwrap();
+        let mut props = parse_properties("", false).unwrap();
+        props.dns.cache = vec![format!("zone:{}", file.path().to_str().unwrap())];
+        props.dns.zones = vec![serde_yaml::from_str("name: Dev.Example.com.").unwrap()];
+        let clusters = K8sClusters::connect(&props, None, Duration::ZERO).await;
+        DnsServer::new(&props, &clusters, &HostOverrides::default())
+            .await
+            .unwrap()
+    }
+
+    async fn answer(server: &DnsServer, name: &str, qtype: QueryType) -> DnsPacket {
+        let mut request = DnsPacket::new();
+        request
+            .questions
+            .push(DnsQuestion::new(name.to_string(), qtype));
+        server.answer(request).await
+    }
+
+    #[test]
+    fn most_specific_zone_contains_name() {
+        let zones = vec![zone("example.com"), zone("dev.example.com.")];
+        let name = |name| find_zone(&zones, name).map(Zone::name);
+        assert_eq!(name("app.Dev.example.com."), Some("dev.example.com"));
+        assert_eq!(name("dev.example.com"), Some("dev.example.com"));
+        assert_eq!(name("www.example.com"), Some("example.com"));
+        assert_eq!(name("otherdev.example.org"), None);
+        assert_eq!(name("example.org"), None);
+    }
+
+    #[tokio::test]
+    async fn unknown_name_in_zone_is_nxdomain_with_soa() {
+        let server = server().await;
+        let packet = answer(&server, "missing.dev.example.com", QueryType::A).await;
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert!(packet.header.authoritative_answer);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities, vec![server.zones[0].soa()]);
+    }
+
+    #[tokio::test]
+    async fn existing_name_without_records_of_type_is_nodata_with_soa() {
+        let server = server().await;
+        for (name, qtype) in [
+            ("app.dev.example.com", QueryType::AAAA),
+            // name exists, because there is a host below it
+            ("v1.dev.example.com", QueryType::A),
+            ("dev.example.com", QueryType::A),
+        ] {
+            let packet = answer(&server, name, qtype).await;
+            assert_eq!(packet.header.rescode, ResultCode::NOERROR, "{}", name);
+            assert!(packet.header.authoritative_answer);
+            assert!(packet.answers.is_empty(), "{}", name);
+            assert_eq!(packet.authorities, vec![server.zones[0].soa()], "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn apex_records_and_hosts_are_answered_without_soa() {
+        let server = server().await;
+        let packet = answer(&server, "dev.example.com", QueryType::SOA).await;
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(packet.answers, vec![server.zones[0].soa()]);
+        assert!(packet.authorities.is_empty());
+
+        let packet = answer(&server, "app.dev.example.com", QueryType::A).await;
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.answers.len(), 1);
+        assert!(packet.authorities.is_empty());
+    }
+}
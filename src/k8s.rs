@@ -1,3 +1,4 @@
 pub mod client;
+pub mod cluster;
 
 mod macros;
\ No newline at end of file
@@ -1,16 +1,22 @@
-use crate::config::properties::K8sProps;
+use crate::config::properties::{
+    is_kube_file_config, K8sProps, KUBE_DEFAULT_CONFIG, KUBE_IN_CLUSTER_CONFIG,
+};
 use crate::ingress_spec;
-use anyhow::{anyhow, Result};
+use crate::metrics::{elapsed, METRICS};
+use anyhow::{anyhow, Error, Result};
 use k8s_openapi::api::core::v1::{Pod, Secret};
 use k8s_openapi::api::networking::v1::Ingress;
-use kube::api::{ListParams, ObjectList};
+use kube::api::{ListParams, ObjectList, Portforwarder};
 use kube::config::{KubeConfigOptions, Kubeconfig};
 use kube::{Api, Config, ResourceExt};
 use log::debug;
+use std::time::Instant;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 #[derive(Clone)]
 pub struct K8sClient {
+    /// Cluster name for logs and metrics
+    pub source: String,
     pub pod_namespace: String,
     pub pod_label: String,
     pub pod_http_port: u16,
@@ -19,23 +25,69 @@ pub struct K8sClient {
     client: Option<kube::Client>,
 }
 
+/// Port-forward session to ingress pod, its stream is returned separately
+pub struct PortForward {
+    /// Pod name with port, ex. 'ingress-7d9f:80'
+    pub pod: String,
+    forwarder: Portforwarder,
+}
+
+impl PortForward {
+    /// Wait until session is closed, its stream must be dropped before
+    pub async fn close(self) {
+        if let Err(e) = self.forwarder.join().await {
+            debug!("Port-forward to {} closed with err: {:?}", self.pod, e);
+        }
+    }
+}
+
+/// Build client config from service account, default kubeconfig or kubeconfig files
+async fn kube_config(props: &K8sProps) -> Result<Config> {
+    if props.config.eq_ignore_ascii_case(KUBE_IN_CLUSTER_CONFIG) {
+        return Ok(Config::incluster()?);
+    }
+
+    let options = KubeConfigOptions {
+        context: props.context.clone(),
+        cluster: props.cluster.clone(),
+        user: props.user.clone(),
+    };
+    let default_options =
+        options.context.is_none() && options.cluster.is_none() && options.user.is_none();
+    if props.config.eq_ignore_ascii_case(KUBE_DEFAULT_CONFIG) && default_options {
+        // falls back to service account, if kubeconfig is not found
+        return Ok(Config::infer().await?);
+    }
+
+    let kubeconfig = read_kubeconfig(&props.config)?;
+    Ok(Config::from_custom_kubeconfig(kubeconfig, &options).await?)
+}
+
+/// Read kubeconfig files separated same as in KUBECONFIG and merge them,
+/// 'default' reads KUBECONFIG or ~/.kube/config
+pub fn read_kubeconfig(config: &str) -> Result<Kubeconfig> {
+    if !is_kube_file_config(config) {
+        return Ok(Kubeconfig::read()?);
+    }
+
+    let mut merged = Kubeconfig::default();
+    for path in std::env::split_paths(config).filter(|path| !path.as_os_str().is_empty()) {
+        let kubeconfig = Kubeconfig::read_from(&path)
+            .map_err(|e| anyhow!("Unable to read kubeconfig {:?}, {}", path, e))?;
+        merged = merged.merge(kubeconfig)?;
+    }
+    Ok(merged)
+}
+
 const TLS_KEY_SECRET: &str = "tls.key";
 const TLS_CERT_SECRET: &str = "tls.crt";
 
 impl K8sClient {
     pub async fn new(props: &K8sProps) -> Result<K8sClient> {
-        let client = if props.config.eq_ignore_ascii_case("default") {
-            kube::Client::try_default().await?
-        } else {
-            let yaml = tokio::fs::read_to_string(&props.config).await?;
-            let kube_config = Kubeconfig::from_yaml(&yaml)?;
-            let kube_options = KubeConfigOptions::default();
-            let config = Config::from_custom_kubeconfig(kube_config, &kube_options).await?;
-
-            kube::Client::try_from(config)?
-        };
+        let client = kube::Client::try_from(kube_config(props).await?)?;
 
         return Ok(K8sClient {
+            source: props.source(),
             pod_namespace: props.pod.namespace.to_string(),
             pod_label: props.pod.label.to_string(),
             pod_http_port: props.pod.port.http,
@@ -56,7 +108,8 @@ impl K8sClient {
     pub async fn pod_list(&self) -> Result<ObjectList<Pod>> {
         let params = ListParams::default().labels(&self.pod_label);
         let pod_api = self.pod_api()?;
-        return Ok(pod_api.list(&params).await?);
+        let result = pod_api.list(&params).await.map_err(Error::from);
+        METRICS.k8s_api(&self.source, "pods", result)
     }
     async fn ingress_api(&self) -> Result<Api<Ingress>> {
         let client = self
@@ -69,7 +122,8 @@ impl K8sClient {
     pub async fn ingress_list(&self) -> Result<ObjectList<Ingress>> {
         let params = ListParams::default();
         let ingress_api = self.ingress_api().await?;
-        return Ok(ingress_api.list(&params).await?);
+        let result = ingress_api.list(&params).await.map_err(Error::from);
+        METRICS.k8s_api(&self.source, "ingresses", result)
     }
 
     pub async fn ingress_urls(&self) -> Result<Vec<String>> {
@@ -92,7 +146,8 @@ impl K8sClient {
     pub async fn secrets_list(&self) -> Result<ObjectList<Secret>> {
         let params = ListParams::default();
         let secrets_api = self.secrets_api().await?;
-        return Ok(secrets_api.list(&params).await?);
+        let result = secrets_api.list(&params).await.map_err(Error::from);
+        METRICS.k8s_api(&self.source, "secrets", result)
     }
 
     /// Return private key and cert
@@ -149,14 +204,37 @@ impl K8sClient {
         }
     }
 
+    /// Open port-forward to ingress pod, return session and its stream
     pub async fn get_port_forwarder(
         &self,
         secure: bool,
-    ) -> Result<impl AsyncRead + AsyncWrite + Unpin> {
+    ) -> Result<(PortForward, impl AsyncRead + AsyncWrite + Unpin)> {
+        let start = Instant::now();
+        let result = self.open_port_forwarder(secure).await;
+        match &result {
+            Ok(_) => METRICS
+                .port_forward_duration
+                .with_label_values(&[&self.source])
+                .observe(elapsed(start)),
+            Err(_) => METRICS
+                .port_forward_failures
+                .with_label_values(&[&self.source])
+                .inc(),
+        }
+        result
+    }
+
+    async fn open_port_forwarder(
+        &self,
+        secure: bool,
+    ) -> Result<(PortForward, impl AsyncRead + AsyncWrite + Unpin)> {
         let mut pod_list = self.pod_list().await?;
         let pod_api = self.pod_api()?;
 
-        let pod = pod_list.items.pop().ok_or(anyhow!("Unable to find free pod port"))?;
+        let pod = pod_list
+            .items
+            .pop()
+            .ok_or(anyhow!("Unable to find free pod port"))?;
         let pod_name = pod.name_any();
         debug!("Connect to {}", pod_name);
 
@@ -166,11 +244,17 @@ impl K8sClient {
             self.pod_http_port
         };
 
-        let mut forwarder = pod_api.portforward(pod_name.as_str(), &[pod_port]).await?;
+        let forwarder = pod_api.portforward(pod_name.as_str(), &[pod_port]).await;
+        let mut forwarder =
+            METRICS.k8s_api(&self.source, "portforward", forwarder.map_err(Error::from))?;
         let upstream_conn = forwarder
             .take_stream(pod_port)
             .ok_or(anyhow!("Cannot get stream from port forward"))?;
 
-        Ok(upstream_conn)
+        let session = PortForward {
+            pod: format!("{}:{}", pod_name, pod_port),
+            forwarder,
+        };
+        Ok((session, upstream_conn))
     }
 }
@@ -0,0 +1,470 @@
+use crate::config::properties::{K8sProps, Properties};
+use crate::k8s::client::K8sClient;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often ingress hosts of ready cluster are listed again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// Hosts of unreachable cluster are served until their last successful listing is older
+const STALE_TIMEOUT: Duration = Duration::from_secs(120);
+/// How often connecting state is checked while waiting for first attempts
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClusterState {
+    /// First attempt is not finished yet
+    Connecting,
+    Ready,
+    /// Unreachable, last hosts are served until they are stale, then cluster has no hosts
+    /// and connection is retried with backoff
+    Degraded,
+}
+
+/// Snapshot of cluster state
+#[derive(Clone, Debug, Serialize)]
+pub struct ClusterHealth {
+    pub source: String,
+    pub state: ClusterState,
+    pub hosts: usize,
+    pub attempts: u32,
+    pub error: Option<String>,
+}
+
+struct ClusterData {
+    state: ClusterState,
+    client: Option<Arc<K8sClient>>,
+    hosts: Vec<String>,
+    /// Time of last successful listing of hosts
+    refreshed: Option<Instant>,
+    attempts: u32,
+    error: Option<String>,
+}
+
+/// One kubernetes cluster, joined into routing and dns only when it is ready
+pub struct Cluster {
+    source: String,
+    /// Ttl of dns answers for ingress hosts
+    ttl: u32,
+    data: RwLock<ClusterData>,
+}
+
+impl Cluster {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Ingress hosts, empty until cluster is ready or after they are stale
+    pub fn hosts(&self) -> Vec<String> {
+        self.data.read().unwrap().hosts.clone()
+    }
+
+    pub fn health(&self) -> ClusterHealth {
+        let data = self.data.read().unwrap();
+        ClusterHealth {
+            source: self.source.to_string(),
+            state: data.state,
+            hosts: data.hosts.len(),
+            attempts: data.attempts,
+            error: data.error.clone(),
+        }
+    }
+
+    fn client(&self) -> Option<Arc<K8sClient>> {
+        self.data.read().unwrap().client.clone()
+    }
+
+    fn contains(&self, host: &str) -> bool {
+        self.data.read().unwrap().hosts.iter().any(|h| h == host)
+    }
+
+    /// Store result of connection(new client) or refresh(no client), return true if cluster is ready.
+    /// Unreachable cluster keeps its client and hosts until they are stale, then it is dropped
+    /// from routing and dns until it is connected again
+    fn update(
+        &self,
+        result: anyhow::Result<(Option<K8sClient>, Vec<String>)>,
+        backoff: Duration,
+    ) -> bool {
+        let mut data = self.data.write().unwrap();
+        match result {
+            Ok((Some(client), hosts)) => {
+                data.attempts += 1;
+                if data.state == ClusterState::Degraded {
+                    info!("Cluster {} is reachable again", self.source);
+                }
+                info!(
+                    "Cluster {} is ready with {} ingress hosts",
+                    self.source,
+                    hosts.len()
+                );
+                hosts.iter().for_each(|host| debug!("Ingress: {}", host));
+
+                data.state = ClusterState::Ready;
+                data.client = Some(Arc::new(client));
+                data.hosts = hosts;
+                data.refreshed = Some(Instant::now());
+                data.error = None;
+                true
+            }
+            Ok((None, hosts)) => {
+                if data.state == ClusterState::Degraded {
+                    info!("Cluster {} is reachable again", self.source);
+                }
+                if hosts != data.hosts {
+                    info!(
+                        "Cluster {} ingress hosts changed, {} hosts",
+                        self.source,
+                        hosts.len()
+                    );
+                    hosts
+                        .iter()
+                        .filter(|host| !data.hosts.contains(host))
+                        .for_each(|host| debug!("Added ingress: {}", host));
+                    data.hosts
+                        .iter()
+                        .filter(|host| !hosts.contains(host))
+                        .for_each(|host| debug!("Removed ingress: {}", host));
+                    data.hosts = hosts;
+                }
+                data.state = ClusterState::Ready;
+                data.refreshed = Some(Instant::now());
+                data.error = None;
+                true
+            }
+            Err(e) => {
+                if data.client.is_none() {
+                    data.attempts += 1;
+                }
+                data.state = ClusterState::Degraded;
+                data.error = Some(e.to_string());
+
+                let fresh = data
+                    .refreshed
+                    .is_some_and(|refreshed| refreshed.elapsed() < STALE_TIMEOUT);
+                if fresh {
+                    warn!(
+                        "Cluster {} is unreachable, keep last {} hosts, retry in {:?}, err: {}",
+                        self.source,
+                        data.hosts.len(),
+                        backoff,
+                        e
+                    );
+                } else {
+                    warn!(
+                        "Cluster {} is unreachable, retry in {:?}, err: {}",
+                        self.source, backoff, e
+                    );
+                    data.client = None;
+                    data.hosts = vec![];
+                    data.refreshed = None;
+                }
+                false
+            }
+        }
+    }
+
+    /// Connect with backoff until cluster is reachable, then list its ingresses every
+    /// refresh interval. Cluster which becomes unreachable is degraded and connected again
+    async fn supervise(self: Arc<Self>, props: K8sProps) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let result = match self.client() {
+                // ready cluster keeps its client, only ingress hosts are listed again
+                Some(client) => client.ingress_urls().await.map(|hosts| (None, hosts)),
+                None => match K8sClient::new(&props).await {
+                    Ok(client) => client
+                        .ingress_urls()
+                        .await
+                        .map(|hosts| (Some(client), hosts)),
+                    Err(e) => Err(e),
+                },
+            };
+
+            if self.update(result, backoff) {
+                backoff = MIN_BACKOFF;
+                sleep(REFRESH_INTERVAL).await;
+            } else {
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Clusters from 'k8s' properties, each of them is connected independently,
+/// so unreachable cluster doesn't block others and local caches
+#[derive(Clone)]
+pub struct K8sClusters {
+    inner: Arc<ClustersInner>,
+}
+
+struct ClustersInner {
+    clusters: Vec<Arc<Cluster>>,
+    supervisors: Vec<Arc<Supervisor>>,
+}
+
+/// Task which connects cluster, it is shared by clusters of reloaded properties
+/// while cluster props are unchanged and aborted when no one uses it
+struct Supervisor {
+    props: K8sProps,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl K8sClusters {
+    /// Start connecting to every cluster and wait up to `wait` for first attempts of new ones.
+    /// Clusters of current with unchanged props are reused with their state and connection
+    pub async fn connect(
+        props: &Properties,
+        current: Option<&K8sClusters>,
+        wait: Duration,
+    ) -> K8sClusters {
+        let mut clusters = Vec::new();
+        let mut supervisors = Vec::new();
+        let mut new_clusters = Vec::new();
+        let mut reusable: Vec<(Arc<Cluster>, Arc<Supervisor>)> = current
+            .map(|current| {
+                current
+                    .inner
+                    .clusters
+                    .iter()
+                    .cloned()
+                    .zip(current.inner.supervisors.iter().cloned())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for k8s_props in props.k8s.iter().flatten() {
+            if let Some(i) = reusable
+                .iter()
+                .position(|(_, supervisor)| supervisor.props == *k8s_props)
+            {
+                let (cluster, supervisor) = reusable.remove(i);
+                debug!("Reuse cluster {}", cluster.source);
+                clusters.push(cluster);
+                supervisors.push(supervisor);
+                continue;
+            }
+
+            let cluster = Arc::new(Cluster {
+                source: k8s_props.source(),
+                ttl: k8s_props.ttl,
+                data: RwLock::new(ClusterData {
+                    state: ClusterState::Connecting,
+                    client: None,
+                    hosts: vec![],
+                    refreshed: None,
+                    attempts: 0,
+                    error: None,
+                }),
+            });
+            supervisors.push(Arc::new(Supervisor {
+                props: k8s_props.clone(),
+                task: tokio::spawn(cluster.clone().supervise(k8s_props.clone())),
+            }));
+            new_clusters.push(cluster.clone());
+            clusters.push(cluster);
+        }
+
+        wait_first_attempts(&new_clusters, wait).await;
+        K8sClusters {
+            inner: Arc::new(ClustersInner {
+                clusters,
+                supervisors,
+            }),
+        }
+    }
+
+    pub fn clusters(&self) -> &[Arc<Cluster>] {
+        &self.inner.clusters
+    }
+
+    /// Client of ready cluster which serve host
+    pub fn client_for(&self, host: &str) -> Option<Arc<K8sClient>> {
+        self.inner
+            .clusters
+            .iter()
+            .find(|cluster| cluster.contains(host))
+            .and_then(|cluster| cluster.client())
+    }
+
+    /// Ttl of ready cluster which serve host
+    pub fn ttl(&self, host: &str) -> Option<u32> {
+        self.inner
+            .clusters
+            .iter()
+            .find(|cluster| cluster.contains(host))
+            .map(|cluster| cluster.ttl)
+    }
+
+    /// Client of first ready cluster
+    pub fn first_client(&self) -> Option<Arc<K8sClient>> {
+        self.inner
+            .clusters
+            .iter()
+            .find_map(|cluster| cluster.client())
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.inner
+            .clusters
+            .iter()
+            .any(|cluster| cluster.contains(host))
+    }
+
+    /// Ingress hosts of ready clusters
+    pub fn hosts(&self) -> Vec<String> {
+        self.inner
+            .clusters
+            .iter()
+            .flat_map(|cluster| cluster.hosts())
+            .collect()
+    }
+
+    pub fn health(&self) -> Vec<ClusterHealth> {
+        self.inner
+            .clusters
+            .iter()
+            .map(|cluster| cluster.health())
+            .collect()
+    }
+}
+
+/// Wait until first attempts of clusters are finished, but not longer than `wait`
+async fn wait_first_attempts(clusters: &[Arc<Cluster>], wait: Duration) {
+    let deadline = Instant::now() + wait;
+    while clusters
+        .iter()
+        .any(|cluster| cluster.health().state == ClusterState::Connecting)
+    {
+        if Instant::now() >= deadline {
+            warn!("Some clusters are still connecting, continue without them");
+            return;
+        }
+        sleep(WAIT_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    fn ready_cluster(hosts: &[&str]) -> Cluster {
+        Cluster {
+            source: "test".to_string(),
+            ttl: 30,
+            data: RwLock::new(ClusterData {
+                state: ClusterState::Ready,
+                client: None,
+                hosts: hosts.iter().map(|host| host.to_string()).collect(),
+                refreshed: Some(Instant::now()),
+                attempts: 1,
+                error: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn failed_refresh_keeps_hosts_until_they_are_stale() {
+        let cluster = ready_cluster(&["app.example.com"]);
+
+        assert!(!cluster.update(Err(anyhow!("timeout")), MIN_BACKOFF));
+        let health = cluster.health();
+        assert_eq!(health.state, ClusterState::Degraded);
+        assert_eq!(health.error.as_deref(), Some("timeout"));
+        assert!(cluster.contains("app.example.com"));
+
+        cluster.data.write().unwrap().refreshed = Instant::now().checked_sub(STALE_TIMEOUT);
+        assert!(!cluster.update(Err(anyhow!("timeout")), MIN_BACKOFF));
+        assert_eq!(cluster.health().state, ClusterState::Degraded);
+        assert!(cluster.hosts().is_empty());
+    }
+
+    #[test]
+    fn successful_refresh_makes_degraded_cluster_ready() {
+        let cluster = ready_cluster(&["app.example.com"]);
+        cluster.update(Err(anyhow!("timeout")), MIN_BACKOFF);
+
+        let hosts = vec!["app.example.com".to_string(), "api.example.com".to_string()];
+        assert!(cluster.update(Ok((None, hosts.clone())), MIN_BACKOFF));
+        let health = cluster.health();
+        assert_eq!(health.state, ClusterState::Ready);
+        assert_eq!(health.error, None);
+        assert_eq!(cluster.hosts(), hosts);
+    }
+
+    fn k8s_props(namespace: &str) -> K8sProps {
+        let yaml = format!(
+            "pod: {{}}\nconfig: /nonexistent/kubeconfig\ningress-namespace: {}",
+            namespace
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    fn props(k8s: Vec<K8sProps>) -> Properties {
+        let mut props = crate::config::properties::parse_properties("", false).unwrap();
+        props.k8s = Some(k8s);
+        props
+    }
+
+    #[tokio::test]
+    async fn unchanged_clusters_are_reused_on_reconnect() {
+        let current = K8sClusters::connect(
+            &props(vec![k8s_props("ingress"), k8s_props("apps")]),
+            None,
+            Duration::ZERO,
+        )
+        .await;
+        *current.clusters()[0].data.write().unwrap() = ready_cluster(&["app.example.com"])
+            .data
+            .into_inner()
+            .unwrap();
+
+        let started = Instant::now();
+        let next = K8sClusters::connect(
+            &props(vec![k8s_props("ingress"), k8s_props("other")]),
+            Some(&current),
+            Duration::from_secs(5),
+        )
+        .await;
+        let reused = &next.clusters()[0];
+        assert!(Arc::ptr_eq(reused, &current.clusters()[0]));
+        assert!(Arc::ptr_eq(
+            &next.inner.supervisors[0],
+            &current.inner.supervisors[0]
+        ));
+        assert!(next.contains("app.example.com"));
+        assert!(!Arc::ptr_eq(&next.clusters()[1], &current.clusters()[1]));
+        assert_eq!(next.inner.supervisors[1].props, k8s_props("other"));
+
+        // reused cluster keeps running after current is dropped
+        drop(current);
+        assert!(!next.inner.supervisors[0].task.is_finished());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn reconnect_with_same_props_does_not_wait() {
+        let props = props(vec![k8s_props("ingress")]);
+        let current = K8sClusters::connect(&props, None, Duration::ZERO).await;
+        current.clusters()[0].data.write().unwrap().state = ClusterState::Connecting;
+
+        let started = Instant::now();
+        let next = K8sClusters::connect(&props, Some(&current), Duration::from_secs(5)).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(Arc::ptr_eq(&next.clusters()[0], &current.clusters()[0]));
+    }
+}
@@ -0,0 +1,274 @@
+use crate::config::properties::Properties;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Sockets passed by systemd socket activation or bound before privileges are dropped.
+/// Listeners take clones of them instead of binding, so they are kept between reloads
+static SOCKETS: Mutex<Vec<Socket>> = Mutex::new(Vec::new());
+
+enum Socket {
+    Udp(std::net::UdpSocket),
+    Tcp(std::net::TcpListener),
+}
+
+impl Socket {
+    fn bind(udp: bool, addr: SocketAddr) -> Result<Socket> {
+        if udp {
+            let socket = std::net::UdpSocket::bind(addr)
+                .map_err(|e| anyhow!("Unable to bind udp {}, err: {}", addr, e))?;
+            Ok(Socket::Udp(socket))
+        } else {
+            let listener = std::net::TcpListener::bind(addr)
+                .map_err(|e| anyhow!("Unable to bind tcp {}, err: {}", addr, e))?;
+            Ok(Socket::Tcp(listener))
+        }
+    }
+
+    fn is_udp(&self) -> bool {
+        matches!(self, Socket::Udp(_))
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Socket::Udp(socket) => socket.local_addr(),
+            Socket::Tcp(listener) => listener.local_addr(),
+        }
+    }
+
+    fn try_clone(&self) -> std::io::Result<Socket> {
+        Ok(match self {
+            Socket::Udp(socket) => Socket::Udp(socket.try_clone()?),
+            Socket::Tcp(listener) => Socket::Tcp(listener.try_clone()?),
+        })
+    }
+}
+
+/// Clone of socket with the same protocol and address
+fn find_clone(sockets: &[Socket], udp: bool, addr: SocketAddr) -> Result<Option<Socket>> {
+    Ok(sockets
+        .iter()
+        .find(|socket| socket.is_udp() == udp && socket.local_addr().ok() == Some(addr))
+        .map(|socket| socket.try_clone())
+        .transpose()?)
+}
+
+/// Listeners of one app. They are bound before current app is stopped on reload,
+/// so config with address which can't be bound keeps current servers.
+/// Addresses of current app are taken as clones of its sockets instead of binding them again
+#[derive(Default)]
+pub struct Listeners {
+    sockets: Vec<Socket>,
+}
+
+impl Listeners {
+    /// Bind all configured addresses, fail if any of them can't be bound
+    pub fn bind(props: &Properties, current: Option<&Listeners>) -> Result<Listeners> {
+        let mut listeners = Listeners::default();
+        let dns = &props.dns.server;
+        for host in dns.hosts() {
+            let addr = socket_addr(&host, dns.port)?;
+            listeners.bind_socket(true, addr, current)?;
+            // tcp is optional, clients use it only when udp answer is truncated
+            if let Err(e) = listeners.bind_socket(false, addr, current) {
+                warn!("Unable to listen dns over tcp on {}, err: {:?}", addr, e);
+            }
+        }
+        for addr in tcp_addrs(props)? {
+            listeners.bind_socket(false, addr, current)?;
+        }
+        Ok(listeners)
+    }
+
+    fn bind_socket(
+        &mut self,
+        udp: bool,
+        addr: SocketAddr,
+        current: Option<&Listeners>,
+    ) -> Result<()> {
+        if find_clone(&self.sockets, udp, addr)?.is_some() {
+            return Ok(());
+        }
+        let mut socket = find_clone(&SOCKETS.lock().unwrap(), udp, addr)?;
+        if let (None, Some(current)) = (&socket, current) {
+            socket = find_clone(&current.sockets, udp, addr)?;
+        }
+        let socket = match socket {
+            Some(socket) => socket,
+            None => Socket::bind(udp, addr)?,
+        };
+        self.sockets.push(socket);
+        Ok(())
+    }
+
+    /// Udp socket bound for address
+    pub fn udp(&self, host: &str, port: u16) -> Result<UdpSocket> {
+        let addr = socket_addr(host, port)?;
+        match find_clone(&self.sockets, true, addr)? {
+            Some(Socket::Udp(socket)) => {
+                socket.set_nonblocking(true)?;
+                Ok(UdpSocket::from_std(socket)?)
+            }
+            _ => Err(anyhow!("Udp {} is not bound", addr)),
+        }
+    }
+
+    /// Tcp listener bound for address
+    pub fn tcp(&self, host: &str, port: u16) -> Result<TcpListener> {
+        let addr = socket_addr(host, port)?;
+        match find_clone(&self.sockets, false, addr)? {
+            Some(Socket::Tcp(listener)) => {
+                listener.set_nonblocking(true)?;
+                Ok(TcpListener::from_std(listener)?)
+            }
+            _ => Err(anyhow!("Tcp {} is not bound", addr)),
+        }
+    }
+}
+
+fn socket_addr(host: &str, port: u16) -> Result<SocketAddr> {
+    let ip = IpAddr::from_str(host).map_err(|_| anyhow!("'{}' is not ip address", host))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Addresses of proxy, metrics and admin api
+fn tcp_addrs(props: &Properties) -> Result<Vec<SocketAddr>> {
+    let mut tcp = vec![];
+    if let Some(proxy) = &props.proxy {
+        for host in proxy.hosts() {
+            tcp.push(socket_addr(&host, proxy.port.http)?);
+            tcp.push(socket_addr(&host, proxy.port.https)?);
+        }
+    }
+    if let Some(metrics) = &props.metrics {
+        tcp.push(socket_addr(&metrics.host, metrics.port)?);
+    }
+    if let Some(admin) = &props.admin {
+        tcp.push(socket_addr(&admin.host, admin.port)?);
+    }
+    Ok(tcp)
+}
+
+/// Bind all configured listeners, which are not activated yet, so they are kept
+/// when privileges are dropped. Listeners added by later reload are bound as unprivileged user
+pub fn bind_configured(props: &Properties) -> Result<()> {
+    let mut udp = vec![];
+    let mut tcp = vec![];
+    let dns = &props.dns.server;
+    for host in dns.hosts() {
+        udp.push(socket_addr(&host, dns.port)?);
+        tcp.push(socket_addr(&host, dns.port)?);
+    }
+    tcp.extend(tcp_addrs(props)?);
+
+    let mut sockets = SOCKETS.lock().unwrap();
+    for (is_udp, addrs) in [(true, udp), (false, tcp)] {
+        for addr in addrs {
+            if find_clone(&sockets, is_udp, addr)?.is_none() {
+                let socket = Socket::bind(is_udp, addr)?;
+                sockets.push(socket);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Take sockets passed by systemd(LISTEN_FDS), return their count.
+/// Variables are removed, so child processes don't take them
+#[cfg(unix)]
+pub fn activate() -> Result<usize> {
+    use std::os::fd::{FromRawFd, RawFd};
+
+    // first passed descriptor, see sd_listen_fds(3)
+    const LISTEN_FDS_START: RawFd = 3;
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(0);
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        warn!(
+            "LISTEN_PID {} is not pid of kidns, ignore passed sockets",
+            pid
+        );
+        return Ok(0);
+    }
+    let count: RawFd = fds
+        .parse()
+        .map_err(|_| anyhow!("LISTEN_FDS '{}' is not number", fds))?;
+
+    let mut sockets = SOCKETS.lock().unwrap();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let socket = match socket_type(fd)? {
+            libc::SOCK_DGRAM => Socket::Udp(unsafe { std::net::UdpSocket::from_raw_fd(fd) }),
+            libc::SOCK_STREAM => Socket::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) }),
+            other => {
+                return Err(anyhow!(
+                    "Passed socket {} has unsupported type {}",
+                    fd,
+                    other
+                ))
+            }
+        };
+        set_cloexec(fd)?;
+        info!(
+            "Activated {} socket {}",
+            match socket {
+                Socket::Udp(_) => "udp",
+                Socket::Tcp(_) => "tcp",
+            },
+            socket.local_addr()?
+        );
+        sockets.push(socket);
+    }
+    Ok(count as usize)
+}
+
+#[cfg(not(unix))]
+pub fn activate() -> Result<usize> {
+    Ok(0)
+}
+
+#[cfg(unix)]
+fn socket_type(fd: libc::c_int) -> Result<libc::c_int> {
+    let mut socket_type: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut socket_type as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(anyhow!(
+            "Passed descriptor {} is not socket, err: {}",
+            fd,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(socket_type)
+}
+
+#[cfg(unix)]
+fn set_cloexec(fd: libc::c_int) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(anyhow!(
+            "Unable to set close-on-exec of socket {}, err: {}",
+            fd,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
@@ -1,47 +1,163 @@
+use crate::admin::overrides::HostOverrides;
+use crate::app::App;
+use crate::cli::{CaCommand, Cli, Command};
+use crate::config::logs::{init_logs, LogFormat};
+use crate::config::properties::{env_overridden_keys, parse_properties, Properties};
+use crate::config::validate::validate_properties;
+use crate::config::watch::ConfigWatcher;
+use crate::proxy::server::cert::preload_root_ca;
+use crate::proxy::server::connections::Connections;
 use anyhow::anyhow;
-use crate::config::logs::init_logs;
-use crate::config::properties::parse_properties;
-use crate::dns::server::dns::DnsServer;
-use crate::proxy::server::proxy::Proxy;
-use log::error;
+use clap::Parser;
+use log::{error, info, warn};
 use tokio::signal;
 
+mod admin;
+mod app;
+mod ca;
+mod cli;
+mod commands;
 mod config;
 mod dns;
 mod k8s;
+mod listeners;
+mod metrics;
+mod privileges;
 mod proxy;
 mod util;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    let props = parse_properties()?;
-    init_logs(&props.log_level);
-
-    if props.dns.server.host.ne("") {
-        // run dns server
-        let dns = DnsServer::new(&props).await?;
-        tokio::spawn(async {
-            if let Err(e) = dns.serve().await {
-                error!("Unable to serve dns server, error: {:?}", e)
+    let cli = Cli::parse();
+
+    if let Some(Command::Ca { command }) = &cli.command {
+        init_logs("info", LogFormat::Text);
+        return match command {
+            CaCommand::Init(args) => {
+                ca::generate::init(args, cli.config_path(), cli.config_required()).await
             }
-        });
+            CaCommand::Export(args) => ca::generate::export(args).await,
+            CaCommand::Install(args) => ca::trust::install(args),
+        };
     }
 
-    if props.proxy.is_some() {
-        // run proxy server
-        let proxy = Proxy::new(&props).await?;
-        tokio::spawn(async {
-            if let Err(e) = proxy.serve().await {
-                error!("Unable to serve proxy server, error: {:?}", e)
-            }
-        });
+    let props = load_properties(&cli)?;
+    init_logs(&props.log_level, props.log_format);
+
+    match &cli.command {
+        Some(Command::CheckConfig) => return commands::check_config(&props).await,
+        Some(Command::ListHosts) => return commands::list_hosts(&props).await,
+        Some(Command::PrintConfig) => return commands::print_config(&props),
+        Some(Command::Resolve { name, qtype }) => {
+            return commands::resolve(&props, name, qtype).await
+        }
+        Some(Command::Ca { .. }) | Some(Command::Run) | None => {}
     }
 
-    // wait for OS SIGTERM signal
-    return match signal::ctrl_c().await {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            Err(anyhow!("Unable to handle shutdown signal, err: {:?}", e))
+    // sockets passed by systemd are used instead of binding listeners with the same address
+    listeners::activate()?;
+    if let Some(privileges) = &props.privileges {
+        listeners::bind_configured(&props)?;
+        // root CA key can be readable only by root
+        preload_root_ca(&props)?;
+        privileges::drop_privileges(privileges)?;
+    }
+
+    // runtime host overrides and proxy connections are kept between reloads
+    let overrides = HostOverrides::default();
+    let connections = Connections::default();
+    let mut app = App::new(props, &overrides, &connections, None).await?;
+    app.start();
+
+    let mut watcher = ConfigWatcher::new(app.watched_files(cli.config_path()))?;
+    let mut hangup = hangup_signal()?;
+    let mut terminate = terminate_signal()?;
+
+    loop {
+        tokio::select! {
+            res = signal::ctrl_c() => {
+                res.map_err(|e| anyhow!("Unable to handle shutdown signal, err: {:?}", e))?;
+                info!("Received SIGINT, shut down");
+                break;
+            }
+            _ = terminate.recv() => {
+                info!("Received SIGTERM, shut down");
+                break;
+            }
+            _ = hangup.recv() => info!("Received SIGHUP, reload config"),
+            _ = watcher.changed() => info!("Config files changed, reload config"),
+        }
+
+        // build new servers and bind their listeners before stopping current ones,
+        // so invalid config or address which can't be bound keep current state
+        let new_app = match load_properties(&cli) {
+            Ok(props) => App::new(props, &overrides, &connections, Some(&app)).await,
+            Err(e) => Err(e),
+        };
+        match new_app {
+            Ok(new_app) => {
+                app.log_diff(&new_app);
+                app.stop().await;
+                app.revert_resolved(Some(&new_app)).await;
+                app = new_app;
+                app.start();
+
+                match ConfigWatcher::new(app.watched_files(cli.config_path())) {
+                    Ok(new_watcher) => watcher = new_watcher,
+                    Err(e) => error!("Unable to watch config files, err: {:?}", e),
+                }
+                info!("Config reloaded");
+            }
+            Err(e) => error!("Unable to reload config, keep current one, err: {:?}", e),
         }
-    };
+    }
+
+    // second signal skips draining
+    tokio::select! {
+        _ = app.shutdown() => info!("Shutdown completed"),
+        _ = signal::ctrl_c() => warn!("Received SIGINT, exit without waiting for connections"),
+        _ = terminate.recv() => warn!("Received SIGTERM, exit without waiting for connections"),
+    }
+    Ok(())
+}
+
+fn load_properties(cli: &Cli) -> anyhow::Result<Properties> {
+    let mut props = parse_properties(cli.config_path(), cli.config_required())?;
+    cli.overrides.apply(&mut props);
+    let mut overridden = env_overridden_keys(std::env::vars());
+    overridden.extend(cli.overrides.keys());
+    validate_properties(&props, cli.config_path(), &overridden)?;
+    Ok(props)
+}
+
+#[cfg(unix)]
+fn hangup_signal() -> anyhow::Result<signal::unix::Signal> {
+    Ok(signal::unix::signal(signal::unix::SignalKind::hangup())?)
+}
+
+#[cfg(unix)]
+fn terminate_signal() -> anyhow::Result<signal::unix::Signal> {
+    Ok(signal::unix::signal(signal::unix::SignalKind::terminate())?)
+}
+
+/// Stub of SIGHUP for platforms without it, never receive signal
+#[cfg(not(unix))]
+fn hangup_signal() -> anyhow::Result<NoSignal> {
+    Ok(NoSignal)
+}
+
+/// Stub of SIGTERM for platforms without it, ctrl-c is still handled
+#[cfg(not(unix))]
+fn terminate_signal() -> anyhow::Result<NoSignal> {
+    Ok(NoSignal)
+}
+
+#[cfg(not(unix))]
+struct NoSignal;
+
+#[cfg(not(unix))]
+impl NoSignal {
+    async fn recv(&mut self) -> Option<()> {
+        std::future::pending().await
+    }
 }
@@ -0,0 +1,159 @@
+use crate::listeners::Listeners;
+use crate::util::{log_error_result, read_http_request, write_http_response};
+use anyhow::Result;
+use log::info;
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::{Arc, LazyLock};
+use std::time::Instant;
+use tokio::net::TcpStream;
+
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Process wide metrics, they are kept between config reloads
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Labels: type, rcode, source(cache, zone, policy, upstream, none)
+    pub dns_queries: IntCounterVec,
+    /// Labels: rule(list source or rewrite pattern), action(allow, block, rewrite)
+    pub dns_policy_hits: IntCounterVec,
+    pub dns_upstream_duration: HistogramVec,
+    /// Labels: host, cluster, mode(http, tls-k8s, tls-local)
+    pub proxy_connections: IntCounterVec,
+    /// Labels: host, direction(sent, received), sent is client to upstream
+    pub proxy_bytes: IntCounterVec,
+    /// Labels: cluster
+    pub port_forward_duration: HistogramVec,
+    /// Labels: cluster
+    pub port_forward_failures: IntCounterVec,
+    /// Labels: source(local, k8s)
+    pub cert_duration: HistogramVec,
+    /// Labels: cluster, resource, result(ok, error)
+    pub k8s_api_requests: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("kidns".to_string()), None)
+            .expect("Metrics registry prefix is valid");
+        // from 1ms to ~16s
+        let buckets = exponential_buckets(0.001, 2.0, 15).expect("Histogram buckets are valid");
+
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let counter = IntCounterVec::new(Opts::new(name, help), labels)
+                .expect("Metric options are valid");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("Metric is registered once");
+            counter
+        };
+        let histogram = |name: &str, help: &str, labels: &[&str]| {
+            let opts = HistogramOpts::new(name, help).buckets(buckets.clone());
+            let histogram = HistogramVec::new(opts, labels).expect("Metric options are valid");
+            registry
+                .register(Box::new(histogram.clone()))
+                .expect("Metric is registered once");
+            histogram
+        };
+
+        Metrics {
+            dns_queries: counter(
+                "dns_queries_total",
+                "DNS queries by type, response code and answer source",
+                &["type", "rcode", "source"],
+            ),
+            dns_policy_hits: counter(
+                "dns_policy_hits_total",
+                "DNS queries matched by policy rule",
+                &["rule", "action"],
+            ),
+            dns_upstream_duration: histogram(
+                "dns_upstream_duration_seconds",
+                "Latency of upstream DNS server",
+                &[],
+            ),
+            proxy_connections: counter(
+                "proxy_connections_total",
+                "Proxied connections by host, cluster and mode",
+                &["host", "cluster", "mode"],
+            ),
+            proxy_bytes: counter(
+                "proxy_bytes_total",
+                "Bytes transferred through proxy",
+                &["host", "direction"],
+            ),
+            port_forward_duration: histogram(
+                "port_forward_duration_seconds",
+                "Time to set up kubernetes port-forward",
+                &["cluster"],
+            ),
+            port_forward_failures: counter(
+                "port_forward_failures_total",
+                "Failed kubernetes port-forward setups",
+                &["cluster"],
+            ),
+            cert_duration: histogram(
+                "cert_generation_duration_seconds",
+                "Time to generate or load certificate for host",
+                &["source"],
+            ),
+            k8s_api_requests: counter(
+                "k8s_api_requests_total",
+                "Kubernetes API calls by cluster, resource and result",
+                &["cluster", "resource", "result"],
+            ),
+            registry,
+        }
+    }
+
+    /// Count kubernetes API call and pass its result through
+    pub fn k8s_api<T>(&self, cluster: &str, resource: &str, result: Result<T>) -> Result<T> {
+        let status = if result.is_ok() { "ok" } else { "error" };
+        self.k8s_api_requests
+            .with_label_values(&[cluster, resource, status])
+            .inc();
+        result
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// Seconds elapsed from start, used for histograms
+pub fn elapsed(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64()
+}
+
+/// Serve metrics in prometheus text format on '/metrics'
+pub async fn serve(sockets: Arc<Listeners>, host: String, port: u16) -> Result<()> {
+    let listener = sockets.tcp(&host, port)?;
+    info!("Metrics server listen on {}:{}", host, port);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            log_error_result(respond(stream).await);
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> Result<()> {
+    let Some(request) = read_http_request(&mut stream).await? else {
+        return Ok(());
+    };
+
+    match request.path.as_str() {
+        "/metrics" => {
+            let body = METRICS.encode()?;
+            write_http_response(&mut stream, "200 OK", METRICS_CONTENT_TYPE, &body).await
+        }
+        _ => write_http_response(&mut stream, "404 Not Found", "text/plain", b"Not found\n").await,
+    }
+}
@@ -0,0 +1,87 @@
+use crate::config::properties::PrivilegesProps;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+/// Switch process to unprivileged user and group, it is done once listeners are bound
+#[cfg(unix)]
+pub fn drop_privileges(props: &PrivilegesProps) -> Result<()> {
+    let (uid, user_gid) = find_user(&props.user)?;
+    let gid = match &props.group {
+        None => user_gid,
+        Some(group) => find_group(group)?,
+    };
+
+    if unsafe { libc::geteuid() } != 0 {
+        warn!(
+            "Kidns is not run as root, privileges are not dropped to {}",
+            props.user
+        );
+        return Ok(());
+    }
+
+    // order matters, after setuid process can't change groups
+    check(unsafe { libc::setgroups(1, &gid) }, "setgroups")?;
+    check(unsafe { libc::setgid(gid) }, "setgid")?;
+    check(unsafe { libc::setuid(uid) }, "setuid")?;
+    if unsafe { libc::setuid(0) } == 0 {
+        return Err(anyhow!("Root privileges are regained after setuid"));
+    }
+
+    info!(
+        "Dropped privileges to user {}({}), group {}",
+        props.user, uid, gid
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_: &PrivilegesProps) -> Result<()> {
+    Err(anyhow!("Dropping privileges is supported only on unix"))
+}
+
+/// Return uid and primary gid of user name or numeric id
+#[cfg(unix)]
+pub fn find_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let name = std::ffi::CString::new(user)?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !passwd.is_null() {
+        return Ok(unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) });
+    }
+    match user.parse::<libc::uid_t>() {
+        Ok(uid) => {
+            let passwd = unsafe { libc::getpwuid(uid) };
+            if passwd.is_null() {
+                // user without passwd entry keeps group of same id
+                Ok((uid, uid))
+            } else {
+                Ok((uid, unsafe { (*passwd).pw_gid }))
+            }
+        }
+        Err(_) => Err(anyhow!("User '{}' is not found", user)),
+    }
+}
+
+/// Return gid of group name or numeric id
+#[cfg(unix)]
+pub fn find_group(group: &str) -> Result<libc::gid_t> {
+    let name = std::ffi::CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { (*entry).gr_gid });
+    }
+    group
+        .parse::<libc::gid_t>()
+        .map_err(|_| anyhow!("Group '{}' is not found", group))
+}
+
+#[cfg(unix)]
+fn check(result: libc::c_int, call: &str) -> Result<()> {
+    if result != 0 {
+        return Err(anyhow!(
+            "Unable to drop privileges, {} failed, err: {}",
+            call,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
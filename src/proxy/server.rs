@@ -1,4 +1,6 @@
 pub mod proxy;
 pub mod handler;
-pub(super) mod tls;
-mod cert;
\ No newline at end of file
+pub(crate) mod tls;
+pub(crate) mod cert;
+pub mod connections;
+mod store;
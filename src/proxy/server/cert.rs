@@ -1,34 +1,31 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::Read;
+use std::net::IpAddr;
 use std::ops::Add;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
 
 use anyhow::{anyhow, Error};
-use rcgen::{CertificateParams, DnType, KeyPair, SanType};
+use rcgen::{Certificate, CertificateParams, CidrSubnet, DnType, GeneralSubtree, KeyPair, SanType};
 use rsa::pkcs8::EncodePrivateKey;
 use rsa::RsaPrivateKey;
 use rustls::pki_types::PrivateKeyDer;
 use time::{Duration, OffsetDateTime};
 
+use crate::config::properties::{KeyPairAlgorithm, Properties, ProxyTlsProps};
 use crate::proxy::server::proxy::Proxy;
 use crate::proxy::server::tls::CertificateData;
 
-/// Supported Keypair Algorithms
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum KeyPairAlgorithm {
-    #[allow(unused)]
-    Ed25519,
-    #[allow(unused)]
-    EcdsaP256,
-    #[allow(unused)]
-    EcdsaP384,
-    #[allow(unused)]
-    RSA,
-}
+/// Root CA files read before privileges are dropped, keyed by path. They are used only when
+/// file can not be read again, so root owned key stays available after dropping privileges
+static PRELOADED: Mutex<BTreeMap<PathBuf, String>> = Mutex::new(BTreeMap::new());
 
 impl KeyPairAlgorithm {
     /// Return an `rcgen::KeyPair` for the given varient
-    fn to_key_pair(self) -> anyhow::Result<KeyPair> {
+    pub(crate) fn to_key_pair(self) -> anyhow::Result<KeyPair> {
         match self {
             KeyPairAlgorithm::Ed25519 => {
                 use ring::signature::Ed25519KeyPair;
@@ -67,7 +64,7 @@ impl KeyPairAlgorithm {
 
                 Ok(KeyPair::from_der_and_sign_algo(&pkcs8_bytes, alg)?)
             }
-            KeyPairAlgorithm::RSA => {
+            KeyPairAlgorithm::Rsa => {
                 let mut rng = rand::rngs::OsRng;
                 let bits = 3072;
                 let private_key = RsaPrivateKey::new(&mut rng, bits)?;
@@ -79,6 +76,78 @@ impl KeyPairAlgorithm {
     }
 }
 
+/// Options of locally minted leaf certificates
+#[derive(Clone, Debug)]
+pub(crate) struct LeafCertOptions {
+    pub(crate) key_algorithm: KeyPairAlgorithm,
+    pub(crate) validity: Duration,
+    pub(crate) subject_alt_names: Vec<String>,
+    pub(crate) wildcard: bool,
+    pub(crate) permitted_domains: Vec<String>,
+}
+
+impl From<&ProxyTlsProps> for LeafCertOptions {
+    fn from(props: &ProxyTlsProps) -> Self {
+        LeafCertOptions {
+            key_algorithm: props.key_algorithm,
+            validity: Duration::days(props.validity_days as i64),
+            subject_alt_names: props.subject_alt_names.clone(),
+            wildcard: props.wildcard,
+            permitted_domains: props.permitted_domains.clone(),
+        }
+    }
+}
+
+impl Default for LeafCertOptions {
+    fn default() -> Self {
+        LeafCertOptions {
+            key_algorithm: KeyPairAlgorithm::EcdsaP256,
+            validity: Duration::days(365),
+            subject_alt_names: vec![],
+            wildcard: false,
+            permitted_domains: vec![],
+        }
+    }
+}
+
+impl LeafCertOptions {
+    /// Hex encoded SHA-256 of CA fingerprint and options, stored certificates are reused
+    /// only if they are minted by the same CA with the same options
+    pub(crate) fn fingerprint(&self, ca_fingerprint: &str) -> String {
+        let options = format!(
+            "{}|{:?}|{}|{}|{}|{}",
+            ca_fingerprint,
+            self.key_algorithm,
+            self.validity.whole_seconds(),
+            self.subject_alt_names.join(","),
+            self.wildcard,
+            self.permitted_domains.join(",")
+        );
+        sha256_hex(options.as_bytes())
+    }
+
+    /// Return subject alternative names for domain, without duplicates
+    fn san_names(&self, domain: &str) -> Vec<String> {
+        let mut names = vec![domain.to_string()];
+
+        if self.wildcard {
+            // skip wildcard for top level domains, ex. '*.com'
+            if let Some((_, parent)) = domain.split_once('.') {
+                if parent.contains('.') {
+                    names.push(format!("*.{}", parent));
+                }
+            }
+        }
+
+        for name in &self.subject_alt_names {
+            if !names.contains(name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+}
+
 impl Proxy {
     /// Generate CA signed client cert, return (key, cert) in pem format
     pub(crate) fn generate_signed_cert(&self, domain: &str) -> anyhow::Result<(String, String)> {
@@ -86,22 +155,32 @@ impl Proxy {
             .root_cert
             .as_ref()
             .ok_or(anyhow!("Unable to generate CA certificate, is empty"))?;
+        let options = &self.leaf_cert_options;
 
         let mut client_cert_params = CertificateParams::default();
-        // client_cert_params.alg = &PKCS_ECDSA_P384_SHA384;
-        // client_cert_params.key_pair = Some(KeyPairAlgorithm::EcdsaP384.to_key_pair()?);
-        // client_cert_params.alg = &PKCS_RSA_SHA256;
-        // client_cert_params.key_pair = Some(KeyPairAlgorithm::RSA.to_key_pair()?);
         client_cert_params
             .distinguished_name
             .push(DnType::CommonName, domain);
 
-        client_cert_params.subject_alt_names = vec![SanType::DnsName(domain.try_into()?)];
+        let mut subject_alt_names = Vec::new();
+        for name in options.san_names(domain) {
+            subject_alt_names.push(match IpAddr::from_str(&name) {
+                Ok(ip) => {
+                    check_ip_constraints(&ca.cert, ip)?;
+                    SanType::IpAddress(ip)
+                }
+                Err(_) => {
+                    check_name_constraints(&ca.cert, &options.permitted_domains, &name)?;
+                    SanType::DnsName(name.try_into()?)
+                }
+            });
+        }
+        client_cert_params.subject_alt_names = subject_alt_names;
 
         client_cert_params.not_before = OffsetDateTime::now_utc();
-        client_cert_params.not_after = OffsetDateTime::now_utc().add(Duration::days(365));
+        client_cert_params.not_after = OffsetDateTime::now_utc().add(options.validity);
 
-        let csrp_key = KeyPairAlgorithm::RSA.to_key_pair()?;
+        let csrp_key = options.key_algorithm.to_key_pair()?;
         let csrp = client_cert_params.signed_by(&csrp_key, &ca.cert, &ca.key)?;
         let client_crt = csrp.pem();
         let client_key = csrp_key.serialize_pem();
@@ -110,27 +189,296 @@ impl Proxy {
     }
 }
 
+/// Refuse names outside of CA certificate name constraints or configured permitted domains
+fn check_name_constraints(
+    ca: &Certificate,
+    permitted_domains: &[String],
+    name: &str,
+) -> anyhow::Result<()> {
+    if !permitted_domains.is_empty()
+        && !permitted_domains
+            .iter()
+            .any(|domain| name_within(name, domain))
+    {
+        return Err(anyhow!(
+            "Refuse to sign certificate for {}, it is out of permitted domains {:?}",
+            name,
+            permitted_domains
+        ));
+    }
+
+    let Some(constraints) = &ca.params().name_constraints else {
+        return Ok(());
+    };
+
+    let dns_subtrees = |subtrees: &Vec<GeneralSubtree>| -> Vec<String> {
+        subtrees
+            .iter()
+            .filter_map(|subtree| match subtree {
+                GeneralSubtree::DnsName(domain) => Some(domain.to_string()),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let excluded = dns_subtrees(&constraints.excluded_subtrees);
+    if let Some(domain) = excluded.iter().find(|domain| name_within(name, domain)) {
+        return Err(anyhow!(
+            "Refuse to sign certificate for {}, root CA excludes {}",
+            name,
+            domain
+        ));
+    }
+
+    // empty permitted dns subtrees do not restrict dns names
+    let permitted = dns_subtrees(&constraints.permitted_subtrees);
+    if !permitted.is_empty() && !permitted.iter().any(|domain| name_within(name, domain)) {
+        return Err(anyhow!(
+            "Refuse to sign certificate for {}, root CA is constrained to {:?}",
+            name,
+            permitted
+        ));
+    }
+    Ok(())
+}
+
+/// Refuse ip addresses out of permitted ip subtrees of name constrained CA,
+/// constrained CA without ip subtrees would let clients accept any ip address
+fn check_ip_constraints(ca: &Certificate, ip: IpAddr) -> anyhow::Result<()> {
+    let Some(constraints) = &ca.params().name_constraints else {
+        return Ok(());
+    };
+
+    let contains = |subtrees: &Vec<GeneralSubtree>| {
+        subtrees.iter().any(|subtree| match subtree {
+            GeneralSubtree::IpAddress(subnet) => subnet_contains(subnet, ip),
+            _ => false,
+        })
+    };
+    if contains(&constraints.excluded_subtrees) || !contains(&constraints.permitted_subtrees) {
+        return Err(anyhow!(
+            "Refuse to sign certificate for {}, ip address is not permitted by root CA",
+            ip
+        ));
+    }
+    Ok(())
+}
+
+fn subnet_contains(subnet: &CidrSubnet, ip: IpAddr) -> bool {
+    let masked_eq = |addr: &[u8], mask: &[u8], ip: &[u8]| {
+        addr.iter()
+            .zip(mask)
+            .zip(ip)
+            .all(|((addr, mask), ip)| addr & mask == ip & mask)
+    };
+    match (subnet, ip) {
+        (CidrSubnet::V4(addr, mask), IpAddr::V4(ip)) => masked_eq(addr, mask, &ip.octets()),
+        (CidrSubnet::V6(addr, mask), IpAddr::V6(ip)) => masked_eq(addr, mask, &ip.octets()),
+        _ => false,
+    }
+}
+
+/// Check if dns name is inside of name constraint subtree(RFC 5280), constraint 'example.com'
+/// cover domain itself and subdomains, '.example.com' cover only subdomains
+pub(crate) fn name_within(name: &str, constraint: &str) -> bool {
+    // wildcard name cover only subdomains, so check it as any subdomain
+    let name = match name.strip_prefix("*.") {
+        None => name.to_ascii_lowercase(),
+        Some(parent) => format!("_.{}", parent.to_ascii_lowercase()),
+    };
+    let constraint = constraint.to_ascii_lowercase();
+
+    if constraint.is_empty() {
+        return true;
+    }
+    if let Some(parent) = constraint.strip_prefix('.') {
+        return name.ends_with(&format!(".{}", parent));
+    }
+    name == constraint || name.ends_with(&format!(".{}", constraint))
+}
+
 pub(crate) async fn get_root_ca_params(
     key_path: &String,
     cert_path: &String,
 ) -> anyhow::Result<CertificateData> {
     let parent = env::current_dir()?;
 
-    let cert_path = parent.join(cert_path).to_str().unwrap().to_string();
-    let key_path = parent.join(key_path).to_str().unwrap().to_string();
-
-    let mut cert_file = String::new();
-    File::open(cert_path)?.read_to_string(&mut cert_file)?;
+    let cert_path = parent.join(cert_path);
+    let key_path = parent.join(key_path);
 
-    let mut key_file = String::new();
-    File::open(key_path)?.read_to_string(&mut key_file)?;
+    let cert_file = read_root_ca_file(&cert_path)?;
+    let key_file = read_root_ca_file(&key_path)?;
 
     let key_pair = KeyPair::from_pem(key_file.as_str())?;
+    let fingerprint = ca_fingerprint(&cert_file)?;
     Ok(CertificateData {
         cert: CertificateParams::from_ca_cert_pem(cert_file.as_str())?
             .self_signed(&key_pair)
             .map_err(Error::msg)?,
 
         key: key_pair,
+        fingerprint,
     })
 }
+
+/// Read root CA key and certificate of proxy, it is done before privileges are dropped
+pub(crate) fn preload_root_ca(props: &Properties) -> anyhow::Result<()> {
+    let Some(tls) = props
+        .proxy
+        .as_ref()
+        .and_then(|proxy| proxy.root_ca.as_ref())
+    else {
+        return Ok(());
+    };
+    let parent = env::current_dir()?;
+    for path in [&tls.key, &tls.cert] {
+        let path = parent.join(path);
+        let content = read_file(&path).map_err(|e| root_ca_open_error(&path, e))?;
+        PRELOADED.lock().unwrap().insert(path, content);
+    }
+    Ok(())
+}
+
+/// Read root CA file, preloaded content is used when file can not be read anymore,
+/// e.g. after privileges were dropped
+fn read_root_ca_file(path: &Path) -> anyhow::Result<String> {
+    match read_file(path) {
+        Ok(content) => Ok(content),
+        Err(e) => match PRELOADED.lock().unwrap().get(path) {
+            Some(content) => Ok(content.to_string()),
+            None => Err(root_ca_open_error(path, e)),
+        },
+    }
+}
+
+fn read_file(path: &Path) -> std::io::Result<String> {
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+fn root_ca_open_error(path: &Path, e: std::io::Error) -> Error {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        anyhow!(
+            "Root CA file {} not found, generate it with 'kidns ca init'",
+            path.display()
+        )
+    } else {
+        anyhow!("Unable to open root CA file {}, err: {}", path.display(), e)
+    }
+}
+
+/// Return hex encoded SHA-256 of CA certificate in der format
+fn ca_fingerprint(cert_pem: &str) -> anyhow::Result<String> {
+    let cert = pem::parse(cert_pem)?;
+    Ok(sha256_hex(cert.contents()))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+
+    digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subnet(ip: &str, prefix: u8) -> CidrSubnet {
+        CidrSubnet::from_addr_prefix(ip.parse().unwrap(), prefix)
+    }
+
+    #[test]
+    fn name_within_covers_domain_and_subdomains() {
+        assert!(name_within("example.com", "example.com"));
+        assert!(name_within("app.example.com", "example.com"));
+        assert!(name_within("a.b.Example.COM", "example.com"));
+        assert!(name_within("app.example.com", "EXAMPLE.com"));
+        assert!(name_within("anything.org", ""));
+    }
+
+    #[test]
+    fn name_within_refuses_other_domains() {
+        assert!(!name_within("example.com", "app.example.com"));
+        assert!(!name_within("badexample.com", "example.com"));
+        assert!(!name_within("example.com.evil.org", "example.com"));
+        assert!(!name_within("example.org", "example.com"));
+    }
+
+    #[test]
+    fn name_within_dot_constraint_covers_only_subdomains() {
+        assert!(name_within("app.example.com", ".example.com"));
+        assert!(!name_within("example.com", ".example.com"));
+        assert!(!name_within("badexample.com", ".example.com"));
+    }
+
+    #[test]
+    fn name_within_checks_wildcard_as_any_subdomain() {
+        assert!(name_within("*.example.com", "example.com"));
+        assert!(name_within("*.example.com", ".example.com"));
+        assert!(name_within("*.dev.example.com", "example.com"));
+        assert!(!name_within("*.example.com", "app.example.com"));
+        assert!(!name_within("*.com", "example.com"));
+    }
+
+    #[test]
+    fn subnet_contains_addresses_within_prefix() {
+        let net = subnet("192.168.1.0", 24);
+        assert!(subnet_contains(&net, "192.168.1.0".parse().unwrap()));
+        assert!(subnet_contains(&net, "192.168.1.255".parse().unwrap()));
+        assert!(!subnet_contains(&net, "192.168.2.1".parse().unwrap()));
+
+        let host = subnet("10.0.0.1", 32);
+        assert!(subnet_contains(&host, "10.0.0.1".parse().unwrap()));
+        assert!(!subnet_contains(&host, "10.0.0.2".parse().unwrap()));
+
+        let any = subnet("0.0.0.0", 0);
+        assert!(subnet_contains(&any, "8.8.8.8".parse().unwrap()));
+
+        let net = subnet("fd00::", 8);
+        assert!(subnet_contains(&net, "fd12:3456::1".parse().unwrap()));
+        assert!(!subnet_contains(&net, "fe80::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn subnet_contains_refuses_other_address_family() {
+        assert!(!subnet_contains(
+            &subnet("0.0.0.0", 0),
+            "::1".parse().unwrap()
+        ));
+        assert!(!subnet_contains(
+            &subnet("::", 0),
+            "127.0.0.1".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn root_ca_file_is_read_again_and_preloaded_content_is_fallback() {
+        use assert_fs::prelude::*;
+
+        let dir = assert_fs::TempDir::new().unwrap();
+        let file = dir.child("ca.crt");
+        file.write_str("first").unwrap();
+        let path = file.path().to_path_buf();
+        PRELOADED
+            .lock()
+            .unwrap()
+            .insert(path.clone(), read_file(&path).unwrap());
+        PRELOADED
+            .lock()
+            .unwrap()
+            .insert(path.clone(), "preloaded".to_string());
+
+        file.write_str("second").unwrap();
+        assert_eq!(read_root_ca_file(&path).unwrap(), "second");
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_root_ca_file(&path).unwrap(), "preloaded");
+        PRELOADED.lock().unwrap().remove(&path);
+        assert!(read_root_ca_file(&path).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use crate::config::logs::PROXY_ACCESS;
+use crate::metrics::elapsed;
+use crate::util::wait_until;
+use anyhow::Error;
+use log::info;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+
+/// How long interrupted tunnels are waited to close their port-forward sessions
+const CLOSE_WAIT: Duration = Duration::from_secs(1);
+/// How long aborted connections are waited to write their access logs
+const ABORT_WAIT: Duration = Duration::from_secs(1);
+
+/// Active proxy connection, host is empty until request is parsed
+#[derive(Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client: String,
+    pub host: String,
+    pub mode: String,
+    pub cluster: String,
+    pub upstream: String,
+    pub age_seconds: u64,
+}
+
+struct Connection {
+    client: SocketAddr,
+    host: String,
+    sni: String,
+    mode: String,
+    cluster: String,
+    upstream: String,
+    sent: u64,
+    received: u64,
+    error: String,
+    started: Instant,
+    task: Option<AbortHandle>,
+}
+
+/// Registry of active proxy connections
+#[derive(Clone)]
+pub struct Connections {
+    active: Arc<Mutex<HashMap<u64, Connection>>>,
+    next_id: Arc<AtomicU64>,
+    /// Set when connections must be closed, tunnels stop and close their sessions
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections {
+            active: Arc::default(),
+            next_id: Arc::default(),
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl Connections {
+    /// Register connection, it is removed when guard is dropped
+    pub fn open(&self, client: SocketAddr) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.active.lock().unwrap().insert(
+            id,
+            Connection {
+                client,
+                host: String::new(),
+                sni: String::new(),
+                mode: String::new(),
+                cluster: String::new(),
+                upstream: String::new(),
+                sent: 0,
+                received: 0,
+                error: String::new(),
+                started: Instant::now(),
+                task: None,
+            },
+        );
+        ConnectionGuard {
+            id,
+            connections: self.clone(),
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .active
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, connection)| ConnectionInfo {
+                id: *id,
+                client: connection.client.to_string(),
+                host: connection.host.to_string(),
+                mode: connection.mode.to_string(),
+                cluster: connection.cluster.to_string(),
+                upstream: connection.upstream.to_string(),
+                age_seconds: connection.started.elapsed().as_secs(),
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Keep task of connection, so it can be aborted on shutdown
+    pub fn track(&self, id: u64, task: AbortHandle) {
+        if let Some(connection) = self.active.lock().unwrap().get_mut(&id) {
+            connection.task = Some(task);
+        }
+    }
+
+    /// Wait until active connections are closed, interrupt the rest after timeout,
+    /// so tunnels close their port-forward sessions, and abort tasks which are still running.
+    /// Return count of interrupted connections
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        if wait_until(|| self.len() == 0, timeout).await {
+            return 0;
+        }
+
+        let interrupted = {
+            let mut active = self.active.lock().unwrap();
+            for connection in active.values_mut() {
+                connection.error = "closed on shutdown".to_string();
+            }
+            active.len()
+        };
+        self.shutdown.send_replace(true);
+        if wait_until(|| self.len() == 0, CLOSE_WAIT).await {
+            return interrupted;
+        }
+
+        for connection in self.active.lock().unwrap().values() {
+            if let Some(task) = &connection.task {
+                task.abort();
+            }
+        }
+        wait_until(|| self.len() == 0, ABORT_WAIT).await;
+        interrupted
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+}
+
+pub struct ConnectionGuard {
+    id: u64,
+    connections: Connections,
+}
+
+impl ConnectionGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Set destination once it is known
+    pub fn route(&self, host: &str, mode: &str, cluster: &str) {
+        self.update(|connection| {
+            connection.host = host.to_string();
+            connection.mode = mode.to_string();
+            connection.cluster = cluster.to_string();
+        });
+    }
+
+    /// Server name requested by tls client
+    pub fn sni(&self, sni: &str) {
+        self.update(|connection| connection.sni = sni.to_string());
+    }
+
+    /// Local address or pod with port which connection is forwarded to
+    pub fn upstream(&self, upstream: &str) {
+        self.update(|connection| connection.upstream = upstream.to_string());
+    }
+
+    pub fn transferred(&self, sent: u64, received: u64) {
+        self.update(|connection| {
+            connection.sent = sent;
+            connection.received = received;
+        });
+    }
+
+    /// Resolve once connections are closed on shutdown
+    pub async fn shutdown(&self) {
+        let mut shutdown = self.connections.shutdown.subscribe();
+        let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+    }
+
+    pub fn failed(&self, error: &Error) {
+        self.update(|connection| connection.error = error.to_string());
+    }
+
+    fn update(&self, update: impl FnOnce(&mut Connection)) {
+        if let Some(connection) = self.connections.active.lock().unwrap().get_mut(&self.id) {
+            update(connection);
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    /// Remove connection from registry and write its access log
+    fn drop(&mut self) {
+        let Some(connection) = self.connections.active.lock().unwrap().remove(&self.id) else {
+            return;
+        };
+        info!(
+            target: PROXY_ACCESS,
+            client:% = connection.client,
+            host:% = connection.host,
+            sni:% = connection.sni,
+            mode:% = connection.mode,
+            route:% = connection.cluster,
+            upstream:% = connection.upstream,
+            sent = connection.sent,
+            received = connection.received,
+            duration_ms = elapsed(connection.started) * 1000.0,
+            error:% = connection.error;
+            "proxy connection"
+        );
+    }
+}
@@ -1,85 +1,120 @@
-use crate::k8s::client::K8sClient;
+use crate::k8s::client::{K8sClient, PortForward};
+use crate::listeners::Listeners;
+use crate::metrics::METRICS;
 use crate::proxy::http::get_host;
+use crate::proxy::server::connections::{ConnectionGuard, Connections};
 use crate::proxy::server::proxy::Proxy;
 use crate::proxy::server::tls::get_self_tls_client_config;
 use crate::util::{is_tls, log_error_result};
 use anyhow::{anyhow, format_err, Error, Result};
 use rustls::ServerConfig;
+use std::collections::HashMap;
 use std::io;
 use std::io::ErrorKind;
 use std::io::ErrorKind::UnexpectedEof;
-use std::net::{Ipv4Addr, SocketAddr};
-use std::str::FromStr;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::try_join;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
 use tokio_rustls::server::TlsStream;
 use tokio_rustls::{rustls, LazyConfigAcceptor, TlsConnector};
 
+/// Aliases followed to find route of host
+const MAX_ALIAS_DEPTH: usize = 8;
+
 impl Proxy {
-    pub async fn serve(self) -> Result<()> {
+    pub async fn serve(self, sockets: Arc<Listeners>) -> Result<()> {
         let proxy = Arc::new(self);
 
-        let http_proxy = proxy.clone();
-        let http = tokio::spawn(async {
-            let http_port = http_proxy.http_port;
-            log_error_result(http_proxy.serve_port(http_port).await.map_err(|e| {
-                anyhow!(
-                    "Unable to run proxy on http port {}, with error {:?}",
-                    http_port,
-                    e
-                )
-            }))
-        });
-
-        let https_proxy = proxy.clone();
-        let https = tokio::spawn(async {
-            let https_port = https_proxy.https_port;
-            log_error_result(https_proxy.serve_port(https_port).await.map_err(|e| {
-                anyhow!(
-                    "Unable to run proxy on https port {}, with error {:?}",
-                    https_port,
-                    e
-                )
-            }))
-        });
-
-        try_join!(http, https)?;
+        // listeners are owned by join set of current task, so aborting it close all of them
+        let mut listeners = JoinSet::new();
+        for host in &proxy.hosts {
+            for (scheme, port) in [("http", proxy.http_port), ("https", proxy.https_port)] {
+                let (proxy, host, sockets) = (proxy.clone(), host.to_string(), sockets.clone());
+                listeners.spawn(async move {
+                    log_error_result(proxy.serve_port(&sockets, &host, port).await.map_err(|e| {
+                        anyhow!(
+                            "Unable to run proxy on {} {}:{}, with error {:?}",
+                            scheme,
+                            host,
+                            port,
+                            e
+                        )
+                    }))
+                });
+            }
+        }
+        while listeners.join_next().await.is_some() {}
 
         Ok(())
     }
 
-    async fn serve_port(self: Arc<Proxy>, port: u16) -> Result<()> {
-        let addr = SocketAddr::from((Ipv4Addr::from_str(&self.host)?, port));
+    /// Hosts with their addresses from local caches
+    pub fn local_routes(&self) -> HashMap<String, SocketAddr> {
+        self.local_clients.clone()
+    }
+
+    /// Aliases of local caches with their target hosts
+    pub fn aliases(&self) -> HashMap<String, String> {
+        self.aliases.clone()
+    }
+
+    /// Active connections registry, shared with admin api
+    pub fn connections(&self) -> Connections {
+        self.connections.clone()
+    }
+
+    /// Return hosts which proxy can route
+    pub fn hosts(&self) -> Vec<String> {
+        let mut hosts = self.clusters.hosts();
+        hosts.extend(self.local_clients.keys().cloned());
+        hosts.extend(self.aliases.keys().cloned());
+        hosts
+    }
 
-        let listener = TcpListener::bind(addr).await?;
+    async fn serve_port(
+        self: Arc<Proxy>,
+        sockets: &Listeners,
+        host: &str,
+        port: u16,
+    ) -> Result<()> {
+        let listener = sockets.tcp(host, port)?;
 
         loop {
-            let (client_conn, _) = listener.accept().await?;
+            let (client_conn, client_addr) = listener.accept().await?;
 
             let proxy = self.clone();
-            tokio::spawn(async move {
-                log_error_result(proxy.forward_connection(client_conn).await);
+            let connection = proxy.connections.open(client_addr);
+            let id = connection.id();
+            let task = tokio::spawn(async move {
+                let result = proxy.forward_connection(client_conn, &connection).await;
+                if let Err(e) = &result {
+                    connection.failed(e);
+                }
+                log_error_result(result);
             });
+            self.connections.track(id, task.abort_handle());
         }
     }
     pub(crate) fn get_k8s_client(&self, url: Option<&String>) -> Result<Arc<K8sClient>> {
         Ok(match url {
             None => self
-                .k8s_clients
-                .first()
-                .ok_or(anyhow!("Unable to found any k8s client"))?
-                .clone(),
-            Some(url) => self
-                .ingress_clients
-                .get(url)
-                .ok_or(anyhow!("Unable to found any k8s client for url {}", url))?
-                .clone(),
+                .clusters
+                .first_client()
+                .ok_or(anyhow!("Unable to found any ready k8s client"))?,
+            Some(url) => self.clusters.client_for(url).ok_or(anyhow!(
+                "Unable to found any ready k8s client for url {}",
+                url
+            ))?,
         })
     }
 
-    async fn forward_connection(self: Arc<Self>, client_conn: TcpStream) -> Result<()> {
+    async fn forward_connection(
+        self: Arc<Self>,
+        client_conn: TcpStream,
+        connection: &ConnectionGuard,
+    ) -> Result<()> {
         let is_tls = is_tls(&client_conn).await?;
 
         if is_tls {
@@ -92,24 +127,28 @@ impl Proxy {
                 .server_name()
                 .ok_or(anyhow!("TLS connection didn't provide server name"))?
                 .to_owned();
+            connection.sni(&server_name);
 
             if self.root_cert.is_none() {
-                let server_config = self.get_k8s_server_config(&server_name).await?;
+                // alias is served with ingress certificate of its target
+                let server_config = self
+                    .get_k8s_server_config(&self.route_host(&server_name))
+                    .await?;
 
                 let mut client_stream = start.into_stream(server_config).await?;
 
-                self.proxy_tls_connection(&mut client_stream, &server_name)
+                self.proxy_tls_connection(&mut client_stream, &server_name, "tls-k8s", connection)
                     .await?;
             } else {
                 let user_defined_config = self.get_local_server_config(&server_name).await?;
 
                 let client_stream = &mut start.into_stream(user_defined_config).await?;
-                self.proxy_tls_connection(client_stream, &server_name)
+                self.proxy_tls_connection(client_stream, &server_name, "tls-local", connection)
                     .await?;
             }
             Ok(())
         } else {
-            self.proxy_connection(client_conn).await
+            self.proxy_connection(client_conn, connection).await
         }
     }
 
@@ -117,57 +156,77 @@ impl Proxy {
         &self,
         client_stream: &mut TlsStream<TcpStream>,
         host: &String,
+        mode: &str,
+        connection: &ConnectionGuard,
     ) -> Result<()> {
-        let tunnel: Result<(u64, u64), io::Error> = if self.ingress_clients.contains_key(host) {
-            let domain = rustls::pki_types::ServerName::try_from(host.as_str())
-                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?
-                .to_owned();
-            let connector = TlsConnector::from(Arc::new(get_self_tls_client_config()?));
-
-            let k8s_forwarder = self.get_k8s_port_forwarder(Some(host), true).await?;
-            let mut k8s_socket = connector.connect(domain, k8s_forwarder).await?;
+        self.record_connection(host, mode, connection);
+        let target = self.route_host(host);
+        let tunnel: Result<(u64, u64), io::Error> = match self.local_addr(&target) {
+            Some(addr) => {
+                let mut local_socket = self.get_local_port_forwarder(&addr, connection).await?;
+                tokio::io::copy_bidirectional(client_stream, &mut local_socket).await
+            }
+            None if self.clusters.contains(&target) => {
+                let domain = rustls::pki_types::ServerName::try_from(target.as_str())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?
+                    .to_owned();
+                let connector = TlsConnector::from(Arc::new(get_self_tls_client_config()?));
+
+                let (session, k8s_forwarder) = self
+                    .get_k8s_port_forwarder(Some(&target), true, connection)
+                    .await?;
+                let mut k8s_socket = connector.connect(domain, k8s_forwarder).await?;
 
-            tokio::io::copy_bidirectional(client_stream, &mut k8s_socket).await
-        } else {
-            match self.local_clients.get(host) {
-                Some(addr) => {
-                    let mut local_socket = self.get_local_port_forwarder(addr).await?;
-                    tokio::io::copy_bidirectional(client_stream, &mut local_socket).await
-                }
-                None => Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    format_err!("Unable to proxy connection to {}", host),
-                )),
+                let tunnel = tunnel(client_stream, &mut k8s_socket, connection).await;
+                drop(k8s_socket);
+                session.close().await;
+                tunnel
             }
+            None => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format_err!("Unable to proxy connection to {}", host),
+            )),
         };
 
-        if let Err(e) = tunnel {
-            if e.kind() != UnexpectedEof {
-                return Err(Error::from(e));
-            }
+        match tunnel {
+            Ok(transferred) => record_bytes(host, transferred, connection),
+            Err(e) if e.kind() != UnexpectedEof => return Err(Error::from(e)),
+            Err(_) => {}
         }
 
         Ok(())
     }
 
-    async fn proxy_connection(&self, mut client_conn: TcpStream) -> Result<()> {
+    async fn proxy_connection(
+        &self,
+        mut client_conn: TcpStream,
+        connection: &ConnectionGuard,
+    ) -> Result<()> {
         let url = get_host(&mut client_conn).await?;
+        let target = self.route_host(&url);
 
         // remap
-        let host = match url.as_str() {
+        let host = match target.as_str() {
             "" => None,
-            _ => Some(&url),
+            _ => Some(&target),
         };
 
-        if self.ingress_clients.contains_key(&url) {
-            let mut upstream_conn = self.get_k8s_port_forwarder(host, false).await?;
-            tokio::io::copy_bidirectional(&mut client_conn, &mut upstream_conn).await?;
-        }
+        self.record_connection(&url, "http", connection);
 
-        match self.local_clients.get(&url) {
+        match self.local_addr(&target) {
             Some(addr) => {
-                let mut upstream_conn = self.get_local_port_forwarder(addr).await?;
-                tokio::io::copy_bidirectional(&mut client_conn, &mut upstream_conn).await?;
+                let mut upstream_conn = self.get_local_port_forwarder(&addr, connection).await?;
+                let transferred =
+                    tokio::io::copy_bidirectional(&mut client_conn, &mut upstream_conn).await?;
+                record_bytes(&url, transferred, connection);
+            }
+            None if self.clusters.contains(&target) => {
+                let (session, mut upstream_conn) =
+                    self.get_k8s_port_forwarder(host, false, connection).await?;
+                let tunnel = tunnel(&mut client_conn, &mut upstream_conn, connection).await;
+                drop(upstream_conn);
+                session.close().await;
+                record_bytes(&url, tunnel?, connection);
             }
             None => {
                 // close connection
@@ -177,20 +236,77 @@ impl Proxy {
 
         Ok(())
     }
+    /// Address of runtime override or local cache, they take precedence over ingress hosts
+    fn local_addr(&self, host: &str) -> Option<SocketAddr> {
+        self.overrides
+            .get(host)
+            .or_else(|| self.local_clients.get(host).copied())
+    }
+
+    /// Host whose route serves host, aliases are followed until host with own route,
+    /// ex. 'api.dev.local' -> 'api.k8s.example.com'
+    fn route_host(&self, host: &str) -> String {
+        let mut route = host.to_string();
+        for _ in 0..MAX_ALIAS_DEPTH {
+            if self.local_addr(&route).is_some() || self.clusters.contains(&route) {
+                break;
+            }
+            match self.aliases.get(&route) {
+                Some(target) => route = target.to_string(),
+                None => break,
+            }
+        }
+        route
+    }
+
+    /// Route source of host: 'override', 'local' or cluster name, alias has source of its target
+    pub(crate) fn route_source(&self, host: &str) -> Option<String> {
+        let host = &self.route_host(host);
+        if self.overrides.get(host).is_some() {
+            Some("override".to_string())
+        } else if self.local_clients.contains_key(host) {
+            Some("local".to_string())
+        } else {
+            self.clusters
+                .client_for(host)
+                .map(|client| client.source.to_string())
+        }
+    }
+
+    /// Count connection, unknown hosts are grouped to keep metric labels bounded
+    fn record_connection(&self, host: &str, mode: &str, connection: &ConnectionGuard) {
+        let cluster = self.route_source(host);
+        connection.route(host, mode, cluster.as_deref().unwrap_or("none"));
+
+        let (host, cluster) = match cluster {
+            Some(cluster) => (host, cluster),
+            None => ("unknown", "none".to_string()),
+        };
+        METRICS
+            .proxy_connections
+            .with_label_values(&[host, &cluster, mode])
+            .inc();
+    }
+
     async fn get_k8s_port_forwarder(
         &self,
         url: Option<&String>,
         secure: bool,
-    ) -> Result<impl AsyncRead + AsyncWrite + Unpin> {
+        connection: &ConnectionGuard,
+    ) -> Result<(PortForward, impl AsyncRead + AsyncWrite + Unpin)> {
         let k8s_client = self.get_k8s_client(url)?;
 
-        k8s_client.get_port_forwarder(secure).await
+        let (session, stream) = k8s_client.get_port_forwarder(secure).await?;
+        connection.upstream(&session.pod);
+        Ok((session, stream))
     }
 
     async fn get_local_port_forwarder(
         &self,
         addr: &SocketAddr,
+        connection: &ConnectionGuard,
     ) -> Result<impl AsyncRead + AsyncWrite + Unpin> {
+        connection.upstream(&addr.to_string());
         return Ok(TcpStream::connect(addr).await?);
     }
 
@@ -228,3 +344,33 @@ impl Proxy {
         }
     }
 }
+
+/// Count bytes of finished tunnel, sent is from client to upstream
+fn record_bytes(host: &str, (sent, received): (u64, u64), connection: &ConnectionGuard) {
+    connection.transferred(sent, received);
+    METRICS
+        .proxy_bytes
+        .with_label_values(&[host, "sent"])
+        .inc_by(sent);
+    METRICS
+        .proxy_bytes
+        .with_label_values(&[host, "received"])
+        .inc_by(received);
+}
+
+/// Copy between port-forward stream and client until both are closed or proxy is shut down,
+/// so caller can close port-forward session before its task is aborted
+async fn tunnel<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    connection: &ConnectionGuard,
+) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    U: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    tokio::select! {
+        transferred = tokio::io::copy_bidirectional(client, upstream) => transferred,
+        _ = connection.shutdown() => Err(io::Error::new(ErrorKind::Interrupted, "closed on shutdown")),
+    }
+}
@@ -3,47 +3,42 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
+use log::warn;
 use rustls::ServerConfig;
 use tokio::sync::RwLock;
 
+use crate::admin::overrides::HostOverrides;
 use crate::config::properties::Properties;
-use crate::k8s::client::K8sClient;
-use crate::proxy::server::cert::get_root_ca_params;
-use crate::proxy::server::tls::CertificateData;
-use crate::util::load_local_cache;
+use crate::dns::local_cache::{load_local_aliases, load_local_cache};
+use crate::k8s::cluster::K8sClusters;
+use crate::proxy::server::cert::{get_root_ca_params, LeafCertOptions};
+use crate::proxy::server::connections::Connections;
+use crate::proxy::server::store::CertStore;
+use crate::proxy::server::tls::{local_server_config, CertificateData};
 
 pub struct Proxy {
-    pub(super) host: String,
+    pub(super) hosts: Vec<String>,
     pub(super) http_port: u16,
     pub(super) https_port: u16,
-    pub(super) k8s_clients: Vec<Arc<K8sClient>>,
-    pub(super) ingress_clients: HashMap<String, Arc<K8sClient>>,
+    pub(super) clusters: K8sClusters,
+    pub(super) overrides: HostOverrides,
+    pub(super) connections: Connections,
     pub(super) local_clients: HashMap<String, SocketAddr>,
+    /// Aliases of local caches with their target hosts, alias is routed as its target
+    pub(super) aliases: HashMap<String, String>,
     pub(super) destinations_certs: RwLock<HashMap<String, Arc<ServerConfig>>>,
     pub(super) root_cert: Option<CertificateData>,
+    pub(super) leaf_cert_options: LeafCertOptions,
+    pub(super) cert_store: Option<CertStore>,
 }
 
 impl Proxy {
-    pub async fn new(props: &Properties) -> Result<Proxy> {
-        let mut ingress_clients = HashMap::<String, Arc<K8sClient>>::new();
-        let k8s_clients = match &props.k8s {
-            Some(k8s_props) => {
-                let mut k8s_clients = Vec::<Arc<K8sClient>>::with_capacity(k8s_props.len());
-                for k8s_prop in k8s_props {
-                    let k8s_client = Arc::new(K8sClient::new(k8s_prop).await?);
-
-                    for url in k8s_client.ingress_urls().await? {
-                        ingress_clients.insert(url, k8s_client.clone());
-                    }
-
-                    k8s_clients.push(k8s_client.clone());
-                }
-                k8s_clients
-            }
-            None => {
-                vec![]
-            }
-        };
+    pub async fn new(
+        props: &Properties,
+        clusters: &K8sClusters,
+        overrides: &HostOverrides,
+        connections: &Connections,
+    ) -> Result<Proxy> {
         let proxy_props = match &props.proxy {
             None => Err(anyhow!("Proxy properties is missing")),
             Some(proxy_props) => Ok(proxy_props),
@@ -58,25 +53,61 @@ impl Proxy {
             .collect();
 
         let mut local_clients: HashMap<String, SocketAddr> = HashMap::new();
+        let mut aliases: HashMap<String, String> = HashMap::new();
         for filename in local_clients_paths {
             let file_cache = load_local_cache(&filename).await?;
             local_clients = local_clients.into_iter().chain(file_cache).collect();
+            aliases.extend(load_local_aliases(&filename).await?);
         }
 
         let ca_certificate = match &proxy_props.root_ca {
             None => None,
             Some(tls_props) => Some(get_root_ca_params(&tls_props.key, &tls_props.cert).await?),
         };
+        let leaf_cert_options = match &proxy_props.root_ca {
+            None => LeafCertOptions::default(),
+            Some(tls_props) => {
+                if tls_props.validity_days > 398 {
+                    warn!(
+                        "Leaf certificates validity of {} days is rejected by Apple platforms, max is 398",
+                        tls_props.validity_days
+                    );
+                }
+                LeafCertOptions::from(tls_props)
+            }
+        };
+
+        let mut destinations_certs = HashMap::new();
+        let cert_store = match (&proxy_props.root_ca, &ca_certificate) {
+            (Some(tls_props), Some(ca)) if !tls_props.store.is_empty() => {
+                let store =
+                    CertStore::new(&tls_props.store, &ca.fingerprint, &leaf_cert_options).await?;
+                for (host, (key, cert)) in store.load_all().await? {
+                    match local_server_config(&key, &cert) {
+                        Ok(config) => {
+                            destinations_certs.insert(host, Arc::new(config));
+                        }
+                        Err(e) => warn!("Unable to load stored cert of {}, err: {:?}", host, e),
+                    }
+                }
+                Some(store)
+            }
+            _ => None,
+        };
 
         return Ok(Proxy {
-            host: proxy_props.host.to_string(),
+            hosts: proxy_props.hosts(),
             http_port: proxy_props.port.http,
             https_port: proxy_props.port.https,
-            k8s_clients,
-            ingress_clients,
+            clusters: clusters.clone(),
+            overrides: overrides.clone(),
+            connections: connections.clone(),
             local_clients,
-            destinations_certs: RwLock::new(HashMap::new()),
+            aliases,
+            destinations_certs: RwLock::new(destinations_certs),
             root_cert: ca_certificate,
+            leaf_cert_options,
+            cert_store,
         });
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use time::{Duration, OffsetDateTime};
+use x509_parser::pem::parse_x509_pem;
+
+use crate::proxy::server::cert::LeafCertOptions;
+use crate::util::write_private_file;
+
+const PEM_EXTENSION: &str = "pem";
+
+/// Directory of minted leaf certificates, every file contain key and cert in pem format
+/// and is named `<host>.<fingerprint>.pem`. Fingerprint covers CA certificate and leaf options,
+/// so certificates signed by old CA or minted with old options are not reused
+pub(crate) struct CertStore {
+    dir: PathBuf,
+    fingerprint: String,
+    renew_before: Duration,
+}
+
+impl CertStore {
+    pub(crate) async fn new(
+        dir: &str,
+        ca_fingerprint: &str,
+        options: &LeafCertOptions,
+    ) -> Result<CertStore> {
+        let dir = PathBuf::from(dir);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| anyhow!("Unable to create cert store {:?}, err: {}", dir, e))?;
+
+        Ok(CertStore {
+            dir,
+            fingerprint: options.fingerprint(ca_fingerprint),
+            // renew when less than third of validity left, but not earlier than 30 days
+            renew_before: (options.validity / 3i32).min(Duration::days(30)),
+        })
+    }
+
+    /// Load all fresh certificates signed by current CA with current options,
+    /// return host -> (key, cert) in pem format. Certificates with other fingerprint are removed
+    pub(crate) async fn load_all(&self) -> Result<HashMap<String, (String, String)>> {
+        let mut certs = HashMap::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some(stem) = file_name.strip_suffix(&format!(".{}", PEM_EXTENSION)) else {
+                continue;
+            };
+            let Some((host, fingerprint)) = stem.rsplit_once('.') else {
+                continue;
+            };
+
+            if fingerprint != self.fingerprint {
+                debug!(
+                    "Remove cert of {} signed by other CA or with other options",
+                    host
+                );
+                if let Err(e) = tokio::fs::remove_file(entry.path()).await {
+                    warn!("Unable to remove stale cert {:?}, err: {}", entry.path(), e);
+                }
+                continue;
+            }
+
+            if let Some(key_cert) = self.load(host).await {
+                certs.insert(host.to_string(), key_cert);
+            }
+        }
+
+        info!("Loaded {} certificates from {:?}", certs.len(), self.dir);
+        Ok(certs)
+    }
+
+    /// Return (key, cert) in pem format if stored certificate is not near expiry
+    pub(crate) async fn load(&self, host: &str) -> Option<(String, String)> {
+        let path = self.path(host)?;
+        let content = tokio::fs::read_to_string(&path).await.ok()?;
+
+        let (key, cert) = split_key_cert(&content)?;
+        if !self.is_fresh(&cert) {
+            debug!("Stored cert of {} is near expiry", host);
+            return None;
+        }
+
+        Some((key, cert))
+    }
+
+    pub(crate) async fn save(&self, host: &str, key: &str, cert: &str) -> Result<()> {
+        let path = self
+            .path(host)
+            .ok_or(anyhow!("Unable to store cert, invalid host name {}", host))?;
+
+        write_private_file(&path, format!("{}{}", key, cert).as_bytes()).await
+    }
+
+    fn path(&self, host: &str) -> Option<PathBuf> {
+        // host is taken from client SNI, do not allow to escape store directory
+        let is_valid = !host.is_empty()
+            && !host.starts_with('.')
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+        if !is_valid {
+            return None;
+        }
+
+        Some(self.dir.join(format!(
+            "{}.{}.{}",
+            host.to_ascii_lowercase(),
+            self.fingerprint,
+            PEM_EXTENSION
+        )))
+    }
+
+    fn is_fresh(&self, cert: &str) -> bool {
+        let Ok((_, pem)) = parse_x509_pem(cert.as_bytes()) else {
+            return false;
+        };
+        let Ok(x509) = pem.parse_x509() else {
+            return false;
+        };
+
+        let not_after = x509.validity().not_after.to_datetime();
+        not_after - OffsetDateTime::now_utc() > self.renew_before
+    }
+}
+
+/// Split stored file to private key and certificate pem sections
+fn split_key_cert(content: &str) -> Option<(String, String)> {
+    let start = content.find("-----BEGIN CERTIFICATE-----")?;
+    let (key, cert) = content.split_at(start);
+    if !key.contains("PRIVATE KEY-----") {
+        return None;
+    }
+    Some((key.to_string(), cert.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, KeyPair};
+
+    /// Key and self-signed cert of host in pem format, which expires in given time
+    fn key_cert(host: &str, expires_in: Duration) -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![host.to_string()]).unwrap();
+        params.not_before = OffsetDateTime::now_utc() - Duration::days(1);
+        params.not_after = OffsetDateTime::now_utc() + expires_in;
+        let cert = params.self_signed(&key).unwrap();
+        (key.serialize_pem(), cert.pem())
+    }
+
+    fn options(validity_days: i64) -> LeafCertOptions {
+        LeafCertOptions {
+            validity: Duration::days(validity_days),
+            ..LeafCertOptions::default()
+        }
+    }
+
+    async fn store(dir: &assert_fs::TempDir, ca: &str, options: &LeafCertOptions) -> CertStore {
+        CertStore::new(dir.path().to_str().unwrap(), ca, options)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn saved_cert_is_reused() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let store = store(&dir, "ca", &options(365)).await;
+        let (key, cert) = key_cert("app.example.com", Duration::days(300));
+        store.save("App.Example.com", &key, &cert).await.unwrap();
+
+        assert_eq!(
+            store.load("app.example.com").await,
+            Some((key.clone(), cert.clone()))
+        );
+        let reopened = CertStore::new(dir.path().to_str().unwrap(), "ca", &options(365))
+            .await
+            .unwrap();
+        let certs = reopened.load_all().await.unwrap();
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs["app.example.com"], (key, cert));
+        assert_eq!(store.load("other.example.com").await, None);
+    }
+
+    #[tokio::test]
+    async fn certs_with_other_fingerprint_are_removed() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let (key, cert) = key_cert("app.example.com", Duration::days(300));
+        store(&dir, "old-ca", &options(365))
+            .await
+            .save("app.example.com", &key, &cert)
+            .await
+            .unwrap();
+        let unrelated = dir.path().join("notes.txt");
+        std::fs::write(&unrelated, "keep").unwrap();
+
+        let new_ca = store(&dir, "new-ca", &options(365)).await;
+        assert_eq!(new_ca.load("app.example.com").await, None);
+        assert!(new_ca.load_all().await.unwrap().is_empty());
+
+        let files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files, vec![unrelated]);
+
+        // changed options also change fingerprint
+        let other_options = store(&dir, "new-ca", &options(90)).await;
+        assert_ne!(other_options.fingerprint, new_ca.fingerprint);
+    }
+
+    #[tokio::test]
+    async fn cert_is_renewed_before_third_of_validity_or_30_days_left() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        // third of 365 days is more than 30 days
+        let long = store(&dir, "ca", &options(365)).await;
+        assert_eq!(long.renew_before, Duration::days(30));
+        // third of 30 days
+        let short = store(&dir, "ca", &options(30)).await;
+        assert_eq!(short.renew_before, Duration::days(10));
+
+        let (_, expires_in_20_days) = key_cert("app.example.com", Duration::days(20));
+        assert!(!long.is_fresh(&expires_in_20_days));
+        assert!(short.is_fresh(&expires_in_20_days));
+
+        let (_, expires_in_40_days) = key_cert("app.example.com", Duration::days(40));
+        assert!(long.is_fresh(&expires_in_40_days));
+
+        let (_, expires_in_5_days) = key_cert("app.example.com", Duration::days(5));
+        assert!(!short.is_fresh(&expires_in_5_days));
+
+        let (key, cert) = key_cert("app.example.com", Duration::days(20));
+        long.save("app.example.com", &key, &cert).await.unwrap();
+        assert_eq!(long.load("app.example.com").await, None);
+    }
+
+    #[tokio::test]
+    async fn host_names_escaping_store_are_refused() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let store = store(&dir, "ca", &options(365)).await;
+        let (key, cert) = key_cert("app.example.com", Duration::days(300));
+        for host in ["", ".hidden", "../etc/passwd", "a/b"] {
+            assert!(store.save(host, &key, &cert).await.is_err(), "{}", host);
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use crate::metrics::{elapsed, METRICS};
 use crate::proxy::server::proxy::Proxy;
 use anyhow::{anyhow, Result};
+use log::warn;
 use rcgen::{Certificate, KeyPair};
 use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
 use rustls::pki_types::ServerName;
@@ -8,12 +10,16 @@ use rustls::server::WebPkiClientVerifier;
 use rustls::{
     ClientConfig, DigitallySignedStruct, Error, RootCertStore, ServerConfig, SignatureScheme,
 };
-use rustls_pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
-use std::io::{BufReader, Cursor};
+use rustls_pemfile::{certs, pkcs8_private_keys, read_one, Item};
+use std::io::{BufRead, BufReader, Cursor};
 use std::sync::Arc;
+use std::time::Instant;
 use webpki::types::{CertificateDer, PrivateKeyDer, UnixTime};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
 
-fn get_root_cert_store() -> RootCertStore {
+pub(crate) fn get_root_cert_store() -> RootCertStore {
     let mut root_cert_store = RootCertStore::empty();
     root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
     root_cert_store
@@ -34,19 +40,22 @@ impl Proxy {
         &self,
         server_name: &String,
     ) -> Result<ServerConfig> {
+        let start = Instant::now();
         let k8s_client = self.get_k8s_client(Some(server_name))?;
         let (key, cert) = k8s_client.tls_cert(server_name).await?;
 
-        let cert = certs(&mut BufReader::new(Cursor::new(cert)))
-            .filter_map(|x| x.ok())
-            .collect();
-        let key = rsa_private_keys(&mut BufReader::new(Cursor::new(key)))
-            .next()
-            .ok_or(anyhow!("Empty ingress private key"))??;
+        let cert = read_cert_chain(&mut BufReader::new(Cursor::new(cert)))?;
+        let cert = order_cert_chain(cert, server_name)?;
+        let key = read_private_key(&mut BufReader::new(Cursor::new(key)))?
+            .ok_or(anyhow!("Empty ingress private key for {}", server_name))?;
 
         let config = ServerConfig::builder()
             .with_no_client_auth()
-            .with_single_cert(cert, key.into())?;
+            .with_single_cert(cert, key)?;
+        METRICS
+            .cert_duration
+            .with_label_values(&["k8s"])
+            .observe(elapsed(start));
         Ok(config)
     }
 
@@ -54,27 +63,162 @@ impl Proxy {
         &self,
         server_name: &String,
     ) -> Result<ServerConfig> {
-        let (key, cert) = self.generate_signed_cert(server_name.as_str())?;
+        let start = Instant::now();
+        let (key, cert) = match &self.cert_store {
+            None => self.generate_signed_cert(server_name.as_str())?,
+            Some(store) => match store.load(server_name).await {
+                Some(key_cert) => key_cert,
+                None => {
+                    let (key, cert) = self.generate_signed_cert(server_name.as_str())?;
+                    if let Err(e) = store.save(server_name, &key, &cert).await {
+                        warn!("Unable to store cert of {}, err: {:?}", server_name, e);
+                    }
+                    (key, cert)
+                }
+            },
+        };
 
-        let key: PrivateKeyDer = pkcs8_private_keys(&mut BufReader::new(key.as_bytes()))
-            .next()
-            .ok_or(anyhow!("Unable to find generated cert key"))??
-            .into();
+        let config = local_server_config(&key, &cert);
+        METRICS
+            .cert_duration
+            .with_label_values(&["local"])
+            .observe(elapsed(start));
+        config
+    }
+}
 
-        let cert = vec![certs(&mut BufReader::new(cert.as_bytes()))
-            .next()
-            .ok_or(anyhow!("Unable to find generated cert pem"))??];
+/// Create server config from locally minted key and cert in pem format
+pub(crate) fn local_server_config(key: &str, cert: &str) -> Result<ServerConfig> {
+    let key: PrivateKeyDer = pkcs8_private_keys(&mut BufReader::new(key.as_bytes()))
+        .next()
+        .ok_or(anyhow!("Unable to find generated cert key"))??
+        .into();
 
-        let config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(cert, key)?;
-        Ok(config)
+    let cert = vec![certs(&mut BufReader::new(cert.as_bytes()))
+        .next()
+        .ok_or(anyhow!("Unable to find generated cert pem"))??];
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert, key)?;
+    Ok(config)
+}
+
+/// Read all certificates from pem, skipping other pem sections
+fn read_cert_chain(reader: &mut dyn BufRead) -> Result<Vec<CertificateDer<'static>>> {
+    let mut chain = Vec::new();
+    while let Some(item) = read_one(reader)? {
+        if let Item::X509Certificate(cert) = item {
+            chain.push(cert);
+        }
+    }
+
+    if chain.is_empty() {
+        return Err(anyhow!("Empty ingress certificate"));
+    }
+    Ok(chain)
+}
+
+/// Read first private key from pem, supported PKCS#1(RSA), PKCS#8 and SEC1(EC) formats
+fn read_private_key(reader: &mut dyn BufRead) -> Result<Option<PrivateKeyDer<'static>>> {
+    while let Some(item) = read_one(reader)? {
+        match item {
+            Item::Pkcs1Key(key) => return Ok(Some(key.into())),
+            Item::Pkcs8Key(key) => return Ok(Some(key.into())),
+            Item::Sec1Key(key) => return Ok(Some(key.into())),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Put certificate that cover server name first and follow it by issuers chain,
+/// certificates out of chain are dropped, clients may reject chain with unrelated certificates
+fn order_cert_chain(
+    chain: Vec<CertificateDer<'static>>,
+    server_name: &str,
+) -> Result<Vec<CertificateDer<'static>>> {
+    let mut parsed = Vec::with_capacity(chain.len());
+    for cert in &chain {
+        let (_, x509) = parse_x509_certificate(cert.as_ref())
+            .map_err(|e| anyhow!("Unable to parse ingress certificate: {}", e))?;
+        parsed.push(x509);
+    }
+
+    let leaf = parsed
+        .iter()
+        .position(|cert| {
+            !cert.is_ca()
+                && cert_names(cert)
+                    .iter()
+                    .any(|name| dns_name_matches(name, server_name))
+        })
+        .ok_or_else(|| {
+            let names: Vec<String> = parsed.iter().flat_map(cert_names).collect();
+            anyhow!(
+                "Ingress certificate doesn't match server name {}, certificate names: {:?}",
+                server_name,
+                names
+            )
+        })?;
+
+    let mut order = vec![leaf];
+    let mut current = leaf;
+    // follow issuers, chain length is bounded by number of certificates
+    while let Some(issuer) = parsed.iter().enumerate().position(|(i, cert)| {
+        !order.contains(&i) && cert.subject().as_raw() == parsed[current].issuer().as_raw()
+    }) {
+        order.push(issuer);
+        current = issuer;
+    }
+    if order.len() < chain.len() {
+        warn!(
+            "Ingress certificate of {} has {} certificates out of its chain, they are dropped",
+            server_name,
+            chain.len() - order.len()
+        );
+    }
+
+    Ok(order.into_iter().map(|i| chain[i].clone()).collect())
+}
+
+/// Return DNS names from certificate SAN, or common name if SAN is missing
+fn cert_names(cert: &X509Certificate) -> Vec<String> {
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        return san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect();
+    }
+
+    cert.subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string())
+        .collect()
+}
+
+/// Match server name with certificate dns name, wildcard cover only one label
+fn dns_name_matches(pattern: &str, server_name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        None => pattern.eq_ignore_ascii_case(server_name),
+        Some(suffix) => match server_name.split_once('.') {
+            None => false,
+            Some((_, parent)) => parent.eq_ignore_ascii_case(suffix),
+        },
     }
 }
 
 pub(crate) struct CertificateData {
     pub(crate) cert: Certificate,
     pub(crate) key: KeyPair,
+    /// hex encoded SHA-256 of original CA certificate
+    pub(crate) fingerprint: String,
 }
 
 #[derive(Debug)]
@@ -125,3 +269,38 @@ impl ServerCertVerifier for SelfSignedVerifier {
         self.verifier.supported_verify_schemes()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa};
+
+    fn params(common_name: &str, names: &[&str]) -> CertificateParams {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let mut params = CertificateParams::new(names).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params
+    }
+
+    #[test]
+    fn chain_starts_with_leaf_and_drops_unrelated_certificates() {
+        let mut ca_params = params("ca", &[]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let leaf_key = KeyPair::generate().unwrap();
+        let leaf = params("app", &["app.example.com"])
+            .signed_by(&leaf_key, &ca, &ca_key)
+            .unwrap();
+        let other = params("other", &["other.example.com"])
+            .self_signed(&KeyPair::generate().unwrap())
+            .unwrap();
+
+        let chain = vec![ca.der().clone(), other.der().clone(), leaf.der().clone()];
+        let ordered = order_cert_chain(chain, "app.example.com").unwrap();
+        assert_eq!(ordered, vec![leaf.der().clone(), ca.der().clone()]);
+    }
+}
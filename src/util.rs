@@ -1,11 +1,13 @@
+use crate::proxy::server::tls::get_root_cert_store;
 use anyhow::anyhow;
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::io::ErrorKind;
 use std::path::Path;
-use std::str::FromStr;
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio_rustls::{rustls, TlsConnector};
 
 pub fn log_error_result(res: anyhow::Result<()>) {
     match res {
@@ -16,6 +18,21 @@ pub fn log_error_result(res: anyhow::Result<()>) {
     }
 }
 
+/// How often wait_until checks condition
+const WAIT_POLL: Duration = Duration::from_millis(100);
+
+/// Wait until condition is true or timeout is elapsed, return last condition value
+pub async fn wait_until(condition: impl Fn() -> bool, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(WAIT_POLL).await;
+    }
+    true
+}
+
 pub async fn is_tls(stream: &TcpStream) -> anyhow::Result<bool> {
     let mut handshake_buffer = [0u8; 3];
     stream.peek(handshake_buffer.as_mut_slice()).await?;
@@ -30,50 +47,313 @@ pub async fn is_tls(stream: &TcpStream) -> anyhow::Result<bool> {
     Ok(is_tls)
 }
 
-pub async fn load_local_cache(path: &String) -> anyhow::Result<HashMap<String, SocketAddr>> {
-    let mut lines = read_lines(path).await?;
-
-    let lines: HashMap<String, SocketAddr> = lines
-        .iter_mut()
-        .map(|line| line.split_once("="))
-        .flatten()
-        .map(|(url, ip)| {
-            let addr = match SocketAddr::from_str(ip) {
-                Ok(addr) => Ok(addr),
-                Err(_) => {
-                    // parse without port
-                    match IpAddr::from_str(ip) {
-                        Ok(ip_addr) => Ok(SocketAddr::new(ip_addr, 0)),
-                        Err(_) => Err("Unknown ip"),
-                    }
-                }
-            };
-            (url, addr)
-        })
-        .filter(|(_, ip)| ip.is_ok())
-        .map(|(url, ip)| (url, ip.unwrap()))
-        .map(|(url, ip)| {
-            return (url.to_string(), ip);
-        })
-        .collect();
-
-    return Ok(lines);
-}
-
-async fn read_lines<P>(filename: P) -> anyhow::Result<Vec<String>>
+/// Write file readable only by owner, used for private keys. Mode applies only to created file,
+/// so content is written to new temporary file in the same directory and renamed over path
+pub async fn write_private_file<P>(path: P, content: &[u8]) -> anyhow::Result<()>
 where
     P: AsRef<Path>,
 {
-    let file = match File::open(filename).await {
-        Ok(f) => Ok(f),
-        Err(e) => Err(anyhow!("Can't open file, err: {:#?}", e)),
-    }?;
-    let mut line_buf = BufReader::new(file).lines();
-    let mut lines: Vec<String> = Vec::default();
+    let path = path.as_ref();
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow!("{:?} is not file path", path))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{:08x}.tmp", rand::random::<u32>()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let result = async {
+        let mut file = options.open(&tmp_path).await?;
+        file.write_all(content).await?;
+        file.flush().await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+    result.map_err(|e| anyhow!("Unable to write {:?}, err: {}", path, e))
+}
+
+/// Request of local http endpoints(metrics, admin)
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub host: Option<String>,
+    pub origin: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// Limit of request with body, local endpoints receive only small json documents
+const HTTP_REQUEST_LIMIT: usize = 65536;
+
+/// Read http request with body, return None if connection is closed before request
+pub async fn read_http_request(stream: &mut TcpStream) -> anyhow::Result<Option<HttpRequest>> {
+    let mut data = Vec::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk[..read]);
+        if data.len() > HTTP_REQUEST_LIMIT {
+            return Err(anyhow!(
+                "Http request is bigger than {}",
+                HTTP_REQUEST_LIMIT
+            ));
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut req = httparse::Request::new(&mut headers);
+        let header_len = match req.parse(&data)? {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => continue,
+        };
+        let header = |name: &str| {
+            req.headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case(name))
+                .map(|header| String::from_utf8_lossy(header.value).trim().to_string())
+        };
+        let content_len = header("Content-Length")
+            .map(|len| len.parse::<usize>())
+            .transpose()?
+            .unwrap_or(0);
+        if data.len() < header_len + content_len {
+            continue;
+        }
+
+        return Ok(Some(HttpRequest {
+            method: req.method.unwrap_or_default().to_string(),
+            path: req.path.unwrap_or_default().to_string(),
+            host: header("Host"),
+            origin: header("Origin"),
+            body: data[header_len..header_len + content_len].to_vec(),
+        }));
+    }
+}
+
+/// Write response and close connection
+pub async fn write_http_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Limit of downloaded documents, ex. blocklists
+const HTTP_DOWNLOAD_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Download document by http or https url. Request is HTTP/1.0,
+/// so body is not chunked and ends with connection
+pub async fn http_get(url: &str) -> anyhow::Result<String> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| anyhow!("'{}' is not url", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let default_port = match scheme.to_ascii_lowercase().as_str() {
+        "http" => 80,
+        "https" => 443,
+        _ => return Err(anyhow!("Unsupported scheme of url {}", url)),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse::<u16>()?),
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: kidns\r\nAccept: */*\r\n\r\n",
+        path, authority
+    );
+    let stream = TcpStream::connect((host, port)).await?;
+    if default_port == 443 {
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(get_root_cert_store())
+            .with_no_client_auth();
+        let server_name = rustls::pki_types::ServerName::try_from(host.to_string())?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await?;
+        read_http_response(stream, &request, url).await
+    } else {
+        read_http_response(stream, &request, url).await
+    }
+}
 
-    while let Some(line) = line_buf.next_line().await? {
-        lines.push(line);
+/// Send request and read body of response, body must be complete
+/// if its length is declared by Content-Length
+async fn read_http_response(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    request: &str,
+    url: &str,
+) -> anyhow::Result<String> {
+    stream.write_all(request.as_bytes()).await?;
+    let mut data = Vec::new();
+    match stream
+        .take(HTTP_DOWNLOAD_LIMIT)
+        .read_to_end(&mut data)
+        .await
+    {
+        // many servers close tls connection without close_notify,
+        // truncated body is detected by its declared length
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof && !data.is_empty() => {}
+        result => {
+            result?;
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let header_len = match response.parse(&data)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => return Err(anyhow!("Incomplete response of {}", url)),
+    };
+    if response.code != Some(200) {
+        return Err(anyhow!(
+            "Unexpected status {} of {}",
+            response.code.unwrap_or_default(),
+            url
+        ));
+    }
+    let mut body = &data[header_len..];
+    let content_length = response
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("content-length"));
+    if let Some(header) = content_length {
+        let length = std::str::from_utf8(header.value)?.trim().parse::<usize>()?;
+        if body.len() < length {
+            return Err(anyhow!(
+                "Truncated response of {}, received {} of {} bytes",
+                url,
+                body.len(),
+                length
+            ));
+        }
+        body = &body[..length];
+    }
+    Ok(String::from_utf8_lossy(body).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Response of server which closes connection after writing it
+    async fn response_of(response: &'static [u8]) -> anyhow::Result<String> {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut request = [0; 16];
+            server.read_exact(&mut request).await.unwrap();
+            server.write_all(response).await.unwrap();
+        });
+        let result = read_http_response(client, "GET / HTTP/1.0\r\n", "http://list").await;
+        server.await.unwrap();
+        result
+    }
+
+    #[tokio::test]
+    async fn body_with_declared_length_is_read() {
+        let body = response_of(b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+        assert_eq!(body.unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn body_without_length_ends_with_connection() {
+        let body = response_of(b"HTTP/1.0 200 OK\r\n\r\nhello world").await;
+        assert_eq!(body.unwrap(), "hello world");
+    }
+
+    #[tokio::test]
+    async fn truncated_body_is_refused() {
+        let err = response_of(b"HTTP/1.0 200 OK\r\ncontent-length: 11\r\n\r\nhello")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Truncated response"), "{}", err);
     }
 
-    return Ok(lines);
+    #[tokio::test]
+    async fn unexpected_status_is_error() {
+        let err = response_of(b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("status 404"), "{}", err);
+    }
+
+    /// Tls stream of server which closes connection without close_notify
+    struct NoCloseNotify(&'static [u8]);
+
+    impl AsyncRead for NoCloseNotify {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.0.is_empty() {
+                return std::task::Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+            let len = self.0.len().min(buf.remaining());
+            buf.put_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for NoCloseNotify {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_close_notify_is_accepted_only_with_complete_body() {
+        let complete = NoCloseNotify(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+        let body = read_http_response(complete, "GET / HTTP/1.0\r\n", "https://list").await;
+        assert_eq!(body.unwrap(), "hello");
+
+        let truncated = NoCloseNotify(b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello");
+        let err = read_http_response(truncated, "GET / HTTP/1.0\r\n", "https://list")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Truncated response"), "{}", err);
+    }
 }